@@ -0,0 +1,543 @@
+//! Typed progress of image builds
+//!
+//! Set a channel with `DockerExecutor::with_build_events` to receive the events of every image
+//! the executor builds, i.e. to show build progress in a UI. Events are also logged with
+//! `tracing`, whether a channel is set or not.
+#[cfg(feature = "buildkit")]
+use std::collections::HashSet;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::BuildFailure;
+
+/// How many lines of output are kept for errors of failed builds
+pub const LOG_TAIL_LINES: usize = 50;
+
+/// Something that happened while building an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildEvent {
+    /// A build of the image started
+    Started { image: String },
+    /// An existing image for the same inputs is used instead of building it
+    Reused { image: String },
+    /// The image was loaded from an archive in the image cache directory
+    Loaded { image: String, path: PathBuf },
+    /// A step started. Steps are numbered for the classic builder, i.e. `3`, and identified by
+    /// their vertex digest with BuildKit.
+    StepStarted { step: String, name: String },
+    /// A step finished, and how long it took if known
+    StepFinished {
+        step: String,
+        duration: Option<Duration>,
+    },
+    /// A step was taken from the build cache instead of running it
+    CacheHit { step: String, name: String },
+    /// A line of output, of the step that printed it if known
+    Log { step: Option<String>, line: String },
+    /// The status of a BuildKit vertex changed
+    Vertex(VertexStatus),
+    /// The build failed
+    Error { message: String },
+    /// The image was built
+    Finished { image: String, duration: Duration },
+}
+
+/// The status of a step in a BuildKit build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VertexStatus {
+    pub digest: String,
+    pub name: String,
+    pub cached: bool,
+    pub started: Option<SystemTime>,
+    pub completed: Option<SystemTime>,
+    /// Why the step failed, if it did
+    pub error: Option<String>,
+}
+
+impl VertexStatus {
+    /// How long the step ran, if it completed
+    pub fn duration(&self) -> Option<Duration> {
+        self.completed?.duration_since(self.started?).ok()
+    }
+}
+
+/// Where a step is in the Dockerfile that was built, as the builder reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StepPosition {
+    /// The instruction in the whole Dockerfile, counting from 1, i.e. `Step 3/5` of the classic
+    /// builder
+    Instruction(usize),
+    /// The instruction in a stage, counting from 1 for its `FROM`, i.e. `[builder 2/4]` of
+    /// BuildKit. The stage is its name, `stage-<index>` if it has none, and left out if the
+    /// Dockerfile has one stage.
+    #[cfg_attr(not(feature = "buildkit"), allow(dead_code))]
+    Stage {
+        stage: Option<String>,
+        instruction: usize,
+    },
+}
+
+/// Sends events to the channel of the executor, if there is one
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BuildEvents {
+    sender: Option<UnboundedSender<BuildEvent>>,
+}
+
+impl BuildEvents {
+    pub fn new(sender: Option<UnboundedSender<BuildEvent>>) -> Self {
+        Self { sender }
+    }
+
+    /// Sends an event, a receiver that is gone does not stop the build
+    pub fn emit(&self, event: BuildEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+/// Turns the output of a build into events
+#[derive(Debug, Default)]
+pub(crate) struct BuildProgress {
+    /// The step of the classic builder that is running, and when it started
+    current_step: Option<(String, String, Instant)>,
+    /// Vertexes that were reported as started or finished
+    #[cfg(feature = "buildkit")]
+    started: HashSet<String>,
+    #[cfg(feature = "buildkit")]
+    finished: HashSet<String>,
+    /// The first vertex that failed, and its name
+    #[cfg(feature = "buildkit")]
+    failed: Option<(String, String)>,
+    /// The last lines of output, and the step that printed them
+    log_tail: VecDeque<(Option<String>, String)>,
+}
+
+impl BuildProgress {
+    /// Events for output of the classic builder, like `Step 2/5 : RUN make` or
+    /// ` ---> Using cache`
+    pub fn classic(&mut self, output: &str) -> Vec<BuildEvent> {
+        let mut events = Vec::new();
+
+        for line in output.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            if let Some((step, name)) = parse_step(trimmed) {
+                events.extend(self.finish_step());
+                events.push(BuildEvent::StepStarted {
+                    step: step.clone(),
+                    name: name.clone(),
+                });
+                self.current_step = Some((step, name, Instant::now()));
+            } else if trimmed == "---> Using cache" {
+                if let Some((step, name, _)) = &self.current_step {
+                    events.push(BuildEvent::CacheHit {
+                        step: step.clone(),
+                        name: name.clone(),
+                    });
+                }
+            } else if !trimmed.starts_with("--->") {
+                let step = self.current_step.as_ref().map(|(step, _, _)| step.clone());
+                events.push(self.log(step, line));
+            }
+        }
+
+        events
+    }
+
+    /// Finishes the running step of the classic builder, if any
+    pub fn finish_step(&mut self) -> Option<BuildEvent> {
+        let (step, _, started) = self.current_step.take()?;
+
+        Some(BuildEvent::StepFinished {
+            step,
+            duration: Some(started.elapsed()),
+        })
+    }
+
+    /// Why the build failed: the step that was running or failed, and its last lines of output.
+    /// If the step printed nothing, the last lines of the whole build are used.
+    pub fn failure(&self, message: impl Into<String>) -> BuildFailure {
+        let failed = self
+            .current_step
+            .as_ref()
+            .map(|(step, name, _)| (step.as_str(), name.as_str()));
+        let position = failed
+            .and_then(|(step, _)| step.parse().ok())
+            .map(StepPosition::Instruction);
+        #[cfg(feature = "buildkit")]
+        let (failed, position) = match &self.failed {
+            Some((step, name)) => (
+                Some((step.as_str(), strip_vertex_prefix(name))),
+                vertex_position(name),
+            ),
+            None => (failed, position),
+        };
+
+        let step_output = self
+            .log_tail
+            .iter()
+            .filter(|(step, _)| failed.is_some_and(|(failed, _)| step.as_deref() == Some(failed)))
+            .map(|(_, line)| line.clone())
+            .collect::<Vec<_>>();
+        let log_tail = if step_output.is_empty() {
+            self.log_tail.iter().map(|(_, line)| line.clone()).collect()
+        } else {
+            step_output
+        };
+
+        BuildFailure {
+            message: message.into(),
+            step: failed.map(|(_, name)| name.to_string()),
+            line: None,
+            log_tail,
+            position,
+        }
+    }
+
+    /// A line of output, which is kept for the error if the build fails
+    fn log(&mut self, step: Option<String>, line: &str) -> BuildEvent {
+        if self.log_tail.len() == LOG_TAIL_LINES {
+            self.log_tail.pop_front();
+        }
+        self.log_tail.push_back((step.clone(), line.to_string()));
+
+        BuildEvent::Log {
+            step,
+            line: line.to_string(),
+        }
+    }
+
+    /// Events for a status update of BuildKit
+    #[cfg(feature = "buildkit")]
+    pub fn buildkit(
+        &mut self,
+        status: &bollard::moby::buildkit::v1::StatusResponse,
+    ) -> Vec<BuildEvent> {
+        let mut events = Vec::new();
+
+        for vertex in &status.vertexes {
+            let vertex = VertexStatus {
+                digest: vertex.digest.clone(),
+                name: vertex.name.clone(),
+                cached: vertex.cached,
+                started: vertex
+                    .started
+                    .as_ref()
+                    .and_then(|time| system_time(time.seconds, time.nanos)),
+                completed: vertex
+                    .completed
+                    .as_ref()
+                    .and_then(|time| system_time(time.seconds, time.nanos)),
+                error: (!vertex.error.is_empty()).then(|| vertex.error.clone()),
+            };
+
+            if vertex.error.is_some() && self.failed.is_none() {
+                self.failed = Some((vertex.digest.clone(), vertex.name.clone()));
+            }
+
+            if vertex.cached && self.finished.insert(vertex.digest.clone()) {
+                events.push(BuildEvent::CacheHit {
+                    step: vertex.digest.clone(),
+                    name: vertex.name.clone(),
+                });
+            } else {
+                if vertex.started.is_some() && self.started.insert(vertex.digest.clone()) {
+                    events.push(BuildEvent::StepStarted {
+                        step: vertex.digest.clone(),
+                        name: vertex.name.clone(),
+                    });
+                }
+                if vertex.completed.is_some() && self.finished.insert(vertex.digest.clone()) {
+                    events.push(BuildEvent::StepFinished {
+                        step: vertex.digest.clone(),
+                        duration: vertex.duration(),
+                    });
+                }
+            }
+
+            events.push(BuildEvent::Vertex(vertex));
+        }
+
+        for log in &status.logs {
+            for line in String::from_utf8_lossy(&log.msg).lines() {
+                events.push(self.log(Some(log.vertex.clone()), line));
+            }
+        }
+
+        events
+    }
+}
+
+/// Parses `Step 2/5 : RUN make` into the step number and the instruction
+fn parse_step(line: &str) -> Option<(String, String)> {
+    let (step, name) = line.strip_prefix("Step ")?.split_once(" : ")?;
+    let (step, _total) = step.split_once('/')?;
+
+    step.parse::<usize>().ok()?;
+    Some((step.to_string(), name.to_string()))
+}
+
+/// Strips the stage and step from names of vertexes like `[builder 2/5] RUN make`
+#[cfg(feature = "buildkit")]
+fn strip_vertex_prefix(name: &str) -> &str {
+    name.strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .map_or(name, |(_, instruction)| instruction)
+}
+
+/// The stage and instruction of vertexes like `[builder 2/5] RUN make`. BuildKit pads the
+/// instruction to the width of the total, and may put the platform before the stage.
+#[cfg(feature = "buildkit")]
+fn vertex_position(name: &str) -> Option<StepPosition> {
+    let (prefix, _) = name.strip_prefix('[')?.split_once("] ")?;
+    let mut words = prefix.split_whitespace().rev();
+    let (instruction, _total) = words.next()?.split_once('/')?;
+
+    Some(StepPosition::Stage {
+        stage: words.next().map(str::to_string),
+        instruction: instruction.parse().ok()?,
+    })
+}
+
+#[cfg(feature = "buildkit")]
+fn system_time(seconds: i64, nanos: i32) -> Option<SystemTime> {
+    let since_epoch = Duration::new(u64::try_from(seconds).ok()?, u32::try_from(nanos).ok()?);
+
+    SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classic_progress() {
+        let mut progress = BuildProgress::default();
+
+        let mut events = progress.classic("Step 1/3 : FROM alpine\n");
+        events.extend(progress.classic(" ---> Using cache\n ---> 4a8b2f1c\n"));
+        events.extend(progress.classic("Step 2/3 : RUN make\n"));
+        events.extend(progress.classic(" ---> Running in 1f2e3d\ncc -o app main.c\n\n"));
+        events.extend(progress.finish_step());
+
+        let events = events
+            .into_iter()
+            .map(|event| match event {
+                BuildEvent::StepFinished { step, duration } => {
+                    assert!(duration.is_some());
+                    format!("finished {step}")
+                }
+                BuildEvent::StepStarted { step, name } => format!("started {step} {name}"),
+                BuildEvent::CacheHit { step, name } => format!("cached {step} {name}"),
+                BuildEvent::Log { step, line } => format!("log {step:?} {line}"),
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                "started 1 FROM alpine",
+                "cached 1 FROM alpine",
+                "finished 1",
+                "started 2 RUN make",
+                "log Some(\"2\") cc -o app main.c",
+                "finished 2",
+            ]
+        );
+        assert!(progress.finish_step().is_none());
+    }
+
+    #[test]
+    fn test_classic_failure() {
+        let mut progress = BuildProgress::default();
+        progress.classic("Step 1/2 : FROM alpine\n ---> 4a8b2f1c\n");
+        progress.classic("Step 2/2 : RUN make\n ---> Running in 1f2e3d\n");
+        for index in 0..LOG_TAIL_LINES + 10 {
+            progress.classic(&format!("line {index}\n"));
+        }
+
+        let failure = progress.failure("The command '/bin/sh -c make' returned a non-zero code: 2");
+        assert_eq!(failure.step.as_deref(), Some("RUN make"));
+        assert_eq!(failure.position, Some(StepPosition::Instruction(2)));
+        assert_eq!(failure.log_tail.len(), LOG_TAIL_LINES);
+        assert_eq!(failure.log_tail.first().unwrap(), "line 10");
+        assert_eq!(
+            failure.log_tail.last().unwrap(),
+            &format!("line {}", LOG_TAIL_LINES + 9)
+        );
+        assert!(failure.to_string().starts_with(
+            "The command '/bin/sh -c make' returned a non-zero code: 2 in `RUN make`\n  line 10\n"
+        ));
+    }
+
+    #[test]
+    fn test_failure_without_steps() {
+        let mut progress = BuildProgress::default();
+        progress.classic("Sending build context\n");
+
+        let failure = progress.failure("no such image");
+        assert_eq!(failure.step, None);
+        assert_eq!(failure.log_tail, ["Sending build context"]);
+    }
+
+    #[test]
+    fn test_parse_step() {
+        assert_eq!(
+            parse_step("Step 12/20 : COPY . /app"),
+            Some(("12".to_string(), "COPY . /app".to_string()))
+        );
+        assert_eq!(parse_step("Step right up : now"), None);
+        assert_eq!(parse_step("Successfully built 4a8b2f1c"), None);
+    }
+
+    #[cfg(feature = "buildkit")]
+    #[test]
+    fn test_buildkit_progress() {
+        use bollard::moby::buildkit::v1::{StatusResponse, Vertex, VertexLog};
+
+        let vertex = |digest: &str, cached, started: Option<i64>, completed: Option<i64>| {
+            let mut vertex = Vertex {
+                digest: digest.to_string(),
+                name: format!("[{digest}] RUN make"),
+                cached,
+                ..Default::default()
+            };
+            if let Some(seconds) = started {
+                vertex.started.get_or_insert_default().seconds = seconds;
+            }
+            if let Some(seconds) = completed {
+                vertex.completed.get_or_insert_default().seconds = seconds;
+            }
+            vertex
+        };
+        let mut progress = BuildProgress::default();
+
+        let events = progress.buildkit(&StatusResponse {
+            vertexes: vec![
+                vertex("a", true, None, None),
+                vertex("b", false, Some(10), None),
+            ],
+            logs: vec![VertexLog {
+                vertex: "b".to_string(),
+                msg: b"compiling\nlinking\n".to_vec(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let kinds = events
+            .iter()
+            .map(|event| match event {
+                BuildEvent::CacheHit { step, .. } => format!("cached {step}"),
+                BuildEvent::StepStarted { step, .. } => format!("started {step}"),
+                BuildEvent::Vertex(vertex) => format!("vertex {}", vertex.digest),
+                BuildEvent::Log { line, .. } => format!("log {line}"),
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "cached a",
+                "vertex a",
+                "started b",
+                "vertex b",
+                "log compiling",
+                "log linking"
+            ]
+        );
+
+        // Updates of a vertex only start and finish it once
+        let events = progress.buildkit(&StatusResponse {
+            vertexes: vec![vertex("b", false, Some(10), Some(12))],
+            ..Default::default()
+        });
+        assert_eq!(
+            events[0],
+            BuildEvent::StepFinished {
+                step: "b".to_string(),
+                duration: Some(Duration::from_secs(2)),
+            }
+        );
+        assert_eq!(events.len(), 2);
+    }
+
+    #[cfg(feature = "buildkit")]
+    #[test]
+    fn test_buildkit_failure() {
+        use bollard::moby::buildkit::v1::{StatusResponse, Vertex, VertexLog};
+
+        let log = |vertex: &str, msg: &str| VertexLog {
+            vertex: vertex.to_string(),
+            msg: msg.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let mut progress = BuildProgress::default();
+        progress.buildkit(&StatusResponse {
+            vertexes: vec![Vertex {
+                digest: "a".to_string(),
+                name: "[builder 2/3] RUN cargo build".to_string(),
+                error: "process did not complete successfully: exit code: 101".to_string(),
+                ..Default::default()
+            }],
+            logs: vec![
+                log("a", "error[E0425]: cannot find value `x`\n"),
+                log("b", "unrelated output of another step\n"),
+            ],
+            ..Default::default()
+        });
+
+        let failure = progress.failure("exit code: 101");
+        assert_eq!(failure.step.as_deref(), Some("RUN cargo build"));
+        assert_eq!(
+            failure.position,
+            Some(StepPosition::Stage {
+                stage: Some("builder".to_string()),
+                instruction: 2
+            })
+        );
+        assert_eq!(failure.log_tail, ["error[E0425]: cannot find value `x`"]);
+        assert_eq!(
+            strip_vertex_prefix("[internal] load .dockerignore"),
+            "load .dockerignore"
+        );
+        assert_eq!(strip_vertex_prefix("RUN make"), "RUN make");
+    }
+
+    #[cfg(feature = "buildkit")]
+    #[test]
+    fn test_vertex_position() {
+        assert_eq!(
+            vertex_position("[stage-1  3/12] RUN make"),
+            Some(StepPosition::Stage {
+                stage: Some("stage-1".to_string()),
+                instruction: 3
+            })
+        );
+        assert_eq!(
+            vertex_position("[linux/amd64 builder 2/4] COPY . ."),
+            Some(StepPosition::Stage {
+                stage: Some("builder".to_string()),
+                instruction: 2
+            })
+        );
+        assert_eq!(
+            vertex_position("[2/2] RUN make"),
+            Some(StepPosition::Stage {
+                stage: None,
+                instruction: 2
+            })
+        );
+        assert_eq!(vertex_position("[internal] load .dockerignore"), None);
+        assert_eq!(vertex_position("RUN make"), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Status of builds that run over a BuildKit session
+//!
+//! Docker streams the status of builds on `/build`, but builds over a session are solved by
+//! BuildKit directly. Their status is read from the control API of the BuildKit in the daemon
+//! instead, which docker serves on an upgraded `/grpc` connection.
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context as _, Result};
+use bollard::moby::buildkit::v1::{
+    BuildHistoryEventType, BuildHistoryRecord, BuildHistoryRequest, StatusRequest, StatusResponse,
+    UpdateBuildHistoryRequest, control_client::ControlClient,
+};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Request, StatusCode, header, upgrade::Upgraded};
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint};
+
+/// How long an aborted build may take to stop before it is left to BuildKit
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Calls `on_started` with the ref of the build that has all of the given labels once it
+/// started, and `on_status` with every status update of it until it ends
+pub(crate) async fn watch(
+    socket_path: &str,
+    labels: &HashMap<String, String>,
+    on_started: impl FnOnce(&str),
+    mut on_status: impl FnMut(&StatusResponse),
+) -> Result<()> {
+    let mut control = control_client(socket_path).await?;
+
+    // Builds that are running already are sent first, so the build is found if it started
+    let mut history = control
+        .listen_build_history(BuildHistoryRequest {
+            active_only: true,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    let build_ref = loop {
+        let event = history
+            .message()
+            .await?
+            .context("build history ended before the build started")?;
+
+        if let Some(record) = event.record
+            && has_labels(&record, labels)
+        {
+            break record.r#ref;
+        }
+    };
+    on_started(&build_ref);
+
+    let mut status = control
+        .status(StatusRequest { r#ref: build_ref })
+        .await?
+        .into_inner();
+    while let Some(response) = status.message().await? {
+        on_status(&response);
+    }
+
+    Ok(())
+}
+
+/// Waits until an aborted build stopped, and removes it from the build history
+///
+/// BuildKit cancels a build when the connection that solves it closes, which happens when the
+/// build is aborted. Waiting for it makes sure nothing of it still runs once the start failed.
+pub(crate) async fn remove(socket_path: &str, build_ref: &str) -> Result<()> {
+    let mut control = control_client(socket_path).await?;
+    let active = |early_exit| BuildHistoryRequest {
+        active_only: true,
+        r#ref: build_ref.to_string(),
+        early_exit,
+        ..Default::default()
+    };
+
+    // Exiting early only sends the build if it is still running
+    let running = control
+        .listen_build_history(active(true))
+        .await?
+        .into_inner()
+        .message()
+        .await?
+        .is_some();
+    if running {
+        let mut history = control
+            .listen_build_history(active(false))
+            .await?
+            .into_inner();
+        let stopped = async {
+            while let Some(event) = history.message().await? {
+                if event.r#type() == BuildHistoryEventType::Complete {
+                    break;
+                }
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(STOP_TIMEOUT, stopped)
+            .await
+            .with_context(|| format!("build did not stop within {STOP_TIMEOUT:?}"))??;
+    }
+
+    control
+        .update_build_history(UpdateBuildHistoryRequest {
+            r#ref: build_ref.to_string(),
+            delete: true,
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// The builds in the history that have all of the given labels, running or not
+#[cfg(test)]
+pub(crate) async fn records(
+    socket_path: &str,
+    labels: &HashMap<String, String>,
+) -> Result<Vec<BuildHistoryRecord>> {
+    let mut control = control_client(socket_path).await?;
+    let mut history = control
+        .listen_build_history(BuildHistoryRequest {
+            early_exit: true,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    let mut records = Vec::new();
+    while let Some(event) = history.message().await? {
+        if let Some(record) = event.record
+            && has_labels(&record, labels)
+        {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+/// Whether a build has all of the given labels
+fn has_labels(record: &BuildHistoryRecord, labels: &HashMap<String, String>) -> bool {
+    labels
+        .iter()
+        .all(|(key, value)| record.frontend_attrs.get(&format!("label:{key}")) == Some(value))
+}
+
+/// A client for the control API of the BuildKit in the daemon
+async fn control_client(socket_path: &str) -> Result<ControlClient<Channel>> {
+    let socket_path = socket_path.to_string();
+
+    // The uri is not used, every connection is upgraded from the docker socket
+    let channel = Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_| upgrade(socket_path.clone())))
+        .await?;
+
+    Ok(ControlClient::new(channel))
+}
+
+/// Opens a connection to the docker socket and upgrades it to the grpc endpoint
+async fn upgrade(socket_path: String) -> Result<Upgraded> {
+    let stream = UnixStream::connect(&socket_path).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection.with_upgrades());
+
+    let request = Request::post("/grpc")
+        .header(header::HOST, "docker")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "h2c")
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    anyhow::ensure!(
+        response.status() == StatusCode::SWITCHING_PROTOCOLS,
+        "docker did not upgrade the connection: {}",
+        response.status()
+    );
+
+    Ok(hyper::upgrade::on(response).await?)
+}
@@ -0,0 +1,355 @@
+//! Finding and removing the images, containers and networks created by the executor
+//!
+//! Everything the executor creates is labeled, so that containers and networks left behind by
+//! crashed processes and stale images can be cleaned up later.
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bollard::query_parameters::{
+    ListContainersOptions, ListImagesOptions, ListNetworksOptions, RemoveContainerOptions,
+    RemoveImageOptions,
+};
+use uuid::Uuid;
+
+use crate::{DockerExecutorError, client::Client};
+
+/// Set to `true` on every image and container created by the executor
+pub const MANAGED_LABEL: &str = "ai.bosun.swiftide-docker-executor.managed";
+/// Version of this crate that created the image or container
+pub const VERSION_LABEL: &str = "ai.bosun.swiftide-docker-executor.version";
+/// Uuid of the executor that created the image or container
+pub const EXECUTOR_ID_LABEL: &str = "ai.bosun.swiftide-docker-executor.executor-id";
+/// Creation time in seconds since the unix epoch
+pub const CREATED_AT_LABEL: &str = "ai.bosun.swiftide-docker-executor.created-at";
+/// Pid of the process that created the image or container
+pub const PID_LABEL: &str = "ai.bosun.swiftide-docker-executor.pid";
+/// Host name of the machine that created the image or container
+pub const HOST_LABEL: &str = "ai.bosun.swiftide-docker-executor.host";
+/// Set to `true` on containers that are retained on drop, and on the networks created for them.
+/// They are never removed as orphans, only when they are older than the maximum age of a cleanup.
+pub const RETAINED_LABEL: &str = "ai.bosun.swiftide-docker-executor.retained";
+
+/// Labels for the images and containers created by an executor
+pub(crate) fn labels(executor_id: &Uuid) -> HashMap<String, String> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    HashMap::from([
+        (MANAGED_LABEL.to_string(), "true".to_string()),
+        (
+            VERSION_LABEL.to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        (EXECUTOR_ID_LABEL.to_string(), executor_id.to_string()),
+        (CREATED_AT_LABEL.to_string(), created_at.to_string()),
+        (PID_LABEL.to_string(), std::process::id().to_string()),
+        (HOST_LABEL.to_string(), hostname()),
+    ])
+}
+
+/// Removes containers, networks and images created by the executor
+///
+/// By default only containers and networks whose creating process on this host has exited are
+/// removed. With a maximum age, any container, network or image older than that is removed as
+/// well.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use swiftide_docker_executor::cleanup::Cleanup;
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let report = Cleanup::default()
+///     .with_max_age(Duration::from_secs(24 * 60 * 60))
+///     .run()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cleanup {
+    max_age: Option<Duration>,
+    orphans: bool,
+    images: bool,
+}
+
+impl Default for Cleanup {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            orphans: true,
+            images: true,
+        }
+    }
+}
+
+/// What a cleanup removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// Ids of the removed containers
+    pub containers: Vec<String>,
+    /// Tags of the removed images
+    pub images: Vec<String>,
+    /// Names of the removed networks
+    pub networks: Vec<String>,
+}
+
+impl Cleanup {
+    /// Also remove containers and images older than this
+    pub fn with_max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_age = Some(max_age);
+
+        self
+    }
+
+    /// Remove containers created by processes on this host that have exited. Default is true.
+    pub fn remove_orphans(&mut self, remove: bool) -> &mut Self {
+        self.orphans = remove;
+
+        self
+    }
+
+    /// Remove images older than the maximum age. Default is true.
+    ///
+    /// Images still used by a container are kept.
+    pub fn remove_images(&mut self, remove: bool) -> &mut Self {
+        self.images = remove;
+
+        self
+    }
+
+    /// Removes the matching containers, then the matching networks, and then the matching images
+    pub async fn run(&self) -> Result<CleanupReport, DockerExecutorError> {
+        let docker = Client::lazy_client().await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let host = hostname();
+
+        let mut report = CleanupReport::default();
+
+        let containers = docker
+            .list_containers(Some(ListContainersOptions {
+                all: true,
+                filters: Some(managed_filter()),
+                ..Default::default()
+            }))
+            .await?;
+
+        for container in containers {
+            let Some(id) = container.id else {
+                continue;
+            };
+            let labels = container.labels.unwrap_or_default();
+
+            let is_orphan = self.orphans && is_orphan(&labels, &host);
+            let is_stale = self.is_older(container.created, now);
+            if !is_orphan && !is_stale {
+                continue;
+            }
+
+            tracing::info!(container = id, is_orphan, is_stale, "Removing container");
+            match docker
+                .remove_container(
+                    &id,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        v: true,
+                        ..Default::default()
+                    }),
+                )
+                .await
+            {
+                Ok(()) => report.containers.push(id),
+                // Auto removed containers can disappear while we are at it
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404 | 409,
+                    ..
+                }) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // Networks are removed after their containers, those still in use are kept
+        let networks = docker
+            .list_networks(Some(ListNetworksOptions {
+                filters: Some(managed_filter()),
+            }))
+            .await?;
+
+        for network in networks {
+            let Some(name) = network.name else {
+                continue;
+            };
+            let labels = network.labels.unwrap_or_default();
+
+            let is_orphan = self.orphans && is_orphan(&labels, &host);
+            let created = labels
+                .get(CREATED_AT_LABEL)
+                .and_then(|created| created.parse().ok());
+            let is_stale = self.is_older(created, now);
+            if !is_orphan && !is_stale {
+                continue;
+            }
+
+            tracing::info!(network = name, is_orphan, is_stale, "Removing network");
+            match docker.remove_network(&name).await {
+                Ok(()) => report.networks.push(name),
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404 | 409,
+                    ..
+                }) => {
+                    tracing::debug!(network = name, "Keeping network");
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if !self.images {
+            return Ok(report);
+        }
+
+        let images = docker
+            .list_images(Some(ListImagesOptions {
+                filters: Some(managed_filter()),
+                ..Default::default()
+            }))
+            .await?;
+
+        for image in images {
+            if !self.is_older(Some(image.created), now) {
+                continue;
+            }
+
+            for tag in image.repo_tags {
+                tracing::info!(image = tag, "Removing image");
+                match docker
+                    .remove_image(&tag, None::<RemoveImageOptions>, None)
+                    .await
+                {
+                    Ok(_) => report.images.push(tag),
+                    // In use by a container, or already gone
+                    Err(bollard::errors::Error::DockerResponseServerError {
+                        status_code: 404 | 409,
+                        ..
+                    }) => {
+                        tracing::debug!(image = tag, "Keeping image");
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn is_older(&self, created: Option<i64>, now: u64) -> bool {
+        let (Some(max_age), Some(created)) = (self.max_age, created) else {
+            return false;
+        };
+
+        now.saturating_sub(created.max(0) as u64) > max_age.as_secs()
+    }
+}
+
+fn managed_filter() -> HashMap<String, Vec<String>> {
+    HashMap::from([("label".to_string(), vec![format!("{MANAGED_LABEL}=true")])])
+}
+
+/// True if the labels point to a process on this host that is no longer running, and the
+/// container or network was not meant to be retained
+fn is_orphan(labels: &HashMap<String, String>, host: &str) -> bool {
+    if labels.get(HOST_LABEL).map(String::as_str) != Some(host)
+        || labels.get(RETAINED_LABEL).map(String::as_str) == Some("true")
+    {
+        return false;
+    }
+
+    labels
+        .get(PID_LABEL)
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| !process_is_running(pid))
+}
+
+fn process_is_running(pid: u32) -> bool {
+    if Path::new("/proc/self").exists() {
+        return Path::new("/proc").join(pid.to_string()).exists();
+    }
+
+    // Without procfs, ask kill if the process can be signalled; assume it runs if unsure
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(true)
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() {
+        let id = Uuid::new_v4();
+        let labels = labels(&id);
+
+        assert_eq!(labels[MANAGED_LABEL], "true");
+        assert_eq!(labels[EXECUTOR_ID_LABEL], id.to_string());
+        assert_eq!(labels[PID_LABEL], std::process::id().to_string());
+        assert_eq!(labels[HOST_LABEL], hostname());
+        assert!(labels[CREATED_AT_LABEL].parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_is_orphan() {
+        let host = hostname();
+        let with = |host: &str, pid: &str| {
+            HashMap::from([
+                (HOST_LABEL.to_string(), host.to_string()),
+                (PID_LABEL.to_string(), pid.to_string()),
+            ])
+        };
+
+        assert!(!is_orphan(
+            &with(&host, &std::process::id().to_string()),
+            &host
+        ));
+        assert!(is_orphan(&with(&host, &u32::MAX.to_string()), &host));
+        assert!(!is_orphan(
+            &with("other-host", &u32::MAX.to_string()),
+            &host
+        ));
+        assert!(!is_orphan(&HashMap::new(), &host));
+
+        let mut retained = with(&host, &u32::MAX.to_string());
+        retained.insert(RETAINED_LABEL.to_string(), "true".to_string());
+        assert!(!is_orphan(&retained, &host));
+    }
+
+    #[test]
+    fn test_is_older() {
+        let mut cleanup = Cleanup::default();
+        assert!(!cleanup.is_older(Some(0), 1000));
+
+        cleanup.with_max_age(Duration::from_secs(100));
+        assert!(cleanup.is_older(Some(0), 1000));
+        assert!(!cleanup.is_older(Some(950), 1000));
+        assert!(!cleanup.is_older(None, 1000));
+    }
+}
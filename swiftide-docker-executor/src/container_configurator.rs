@@ -1,13 +1,94 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use bollard::{
-    models::{ContainerCreateBody, EndpointSettings, NetworkingConfig, PortBinding},
+    models::{
+        ContainerCreateBody, ContainerInspectResponse, EndpointSettings, HostConfig,
+        NetworkingConfig, PortBinding, ResourcesUlimits,
+    },
     query_parameters::InspectContainerOptions,
 };
 
 use crate::client::Client;
 
+/// Limits on the resources of the container, so a runaway command cannot starve the host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// Memory in bytes, like `--memory`
+    pub memory: Option<i64>,
+    /// Memory plus swap in bytes, like `--memory-swap`. `-1` allows unlimited swap.
+    pub memory_swap: Option<i64>,
+    /// Number of CPUs, like `--cpus`
+    pub cpus: Option<f64>,
+    /// Relative weight of the CPU time, like `--cpu-shares`
+    pub cpu_shares: Option<i64>,
+    /// CPUs the container may run on, like `--cpuset-cpus 0-3`
+    pub cpuset: Option<String>,
+    /// Maximum number of processes, like `--pids-limit`
+    pub pids: Option<i64>,
+    /// Ulimits by name, i.e. `nofile`, with their soft and hard limit
+    pub ulimits: BTreeMap<String, (i64, i64)>,
+    /// Size of `/dev/shm` in bytes, like `--shm-size`
+    pub shm_size: Option<i64>,
+}
+
+impl ResourceLimits {
+    /// Sets the limits on the host config of the container
+    fn apply(&self, host_config: &mut HostConfig) {
+        host_config.memory = self.memory;
+        host_config.memory_swap = self.memory_swap;
+        host_config.nano_cpus = self.cpus.map(|cpus| (cpus * 1e9) as i64);
+        host_config.cpu_shares = self.cpu_shares;
+        host_config.cpuset_cpus.clone_from(&self.cpuset);
+        host_config.pids_limit = self.pids;
+        host_config.shm_size = self.shm_size;
+        if !self.ulimits.is_empty() {
+            host_config.ulimits = Some(
+                self.ulimits
+                    .iter()
+                    .map(|(name, (soft, hard))| ResourcesUlimits {
+                        name: Some(name.clone()),
+                        soft: Some(*soft),
+                        hard: Some(*hard),
+                    })
+                    .collect(),
+            );
+        }
+    }
+}
+
+/// Directory in the container holding the socket of the service, when it has no network
+const SERVICE_SOCKET_DIR: &str = "/run/swiftide-docker-service";
+
+/// File name of the socket of the service
+pub(crate) const SERVICE_SOCKET: &str = "service.sock";
+
+/// The network the container is attached to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ContainerNetwork {
+    /// The network of the executor if it runs in a container, otherwise the default bridge
+    #[default]
+    Default,
+    /// No network at all. The service listens on a unix socket in `socket_dir` on the host.
+    None { socket_dir: PathBuf },
+    /// A network by name. Internal networks do not publish the port of the service on the host.
+    Named { name: String, internal: bool },
+}
+
+/// How the container is connected
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkSettings {
+    pub network: ContainerNetwork,
+    /// Extra entries for `/etc/hosts`, as `host:ip`
+    pub extra_hosts: Vec<String>,
+    /// DNS servers to use instead of the ones from the host
+    pub dns: Vec<String>,
+}
+
 pub struct ContainerConfigurator {
+    /// The docker socket to mount in the container, if any
     socket_path: Option<String>,
 }
 
@@ -20,24 +101,55 @@ impl ContainerConfigurator {
         &self,
         image_name: &str,
         user: Option<&str>,
+        labels: &HashMap<String, String>,
+        limits: &ResourceLimits,
+        network: &NetworkSettings,
         docker: &Client,
+    ) -> ContainerCreateBody {
+        // Check if we're running inside a container and try to get the network name
+        let maybe_network = match &network.network {
+            ContainerNetwork::Default => self.maybe_docker_network(docker).await,
+            ContainerNetwork::None { .. } => Some("none".to_string()),
+            ContainerNetwork::Named { name, .. } => Some(name.clone()),
+        };
+        if let (ContainerNetwork::Default, Some(network)) = (&network.network, &maybe_network) {
+            tracing::info!(?network, "using discovered docker network");
+        }
+
+        self.container_config(image_name, user, labels, limits, network, maybe_network)
+    }
+
+    fn container_config(
+        &self,
+        image_name: &str,
+        user: Option<&str>,
+        labels: &HashMap<String, String>,
+        limits: &ResourceLimits,
+        network: &NetworkSettings,
+        maybe_network: Option<String>,
     ) -> ContainerCreateBody {
         let internal_port = "50051/tcp";
-        let port_bindings = HashMap::from([(
-            internal_port.to_string(),
-            Some(vec![PortBinding {
-                host_ip: Some("0.0.0.0".to_string()),
-                host_port: Some("".to_string()),
-            }]),
-        )]);
+
+        // Without a network or on an internal one, there is nothing to publish the port on
+        let publish_port = match &network.network {
+            ContainerNetwork::Default => true,
+            ContainerNetwork::None { .. } => false,
+            ContainerNetwork::Named { internal, .. } => !internal,
+        };
+        let port_bindings = publish_port.then(|| {
+            HashMap::from([(
+                internal_port.to_string(),
+                Some(vec![PortBinding {
+                    host_ip: Some("0.0.0.0".to_string()),
+                    host_port: Some("".to_string()),
+                }]),
+            )])
+        });
 
         let exposed_ports = vec![internal_port.to_string()];
 
-        // Check if we're running inside a container and try to get the network name
         let mut network_config = None;
-        let maybe_network = self.maybe_docker_network(docker).await;
-        if let Some(network) = maybe_network.as_deref() {
-            tracing::info!(?network, "using discovered docker network");
+        if let Some(network) = maybe_network.as_deref().filter(|n| *n != "none") {
             let mut endpoints = HashMap::<String, EndpointSettings>::new();
             endpoints.insert(network.to_string(), Default::default());
             network_config = Some(NetworkingConfig {
@@ -45,34 +157,55 @@ impl ContainerConfigurator {
             });
         }
 
+        // The docker socket gives access to the host, which a container without a network or
+        // on an internal one is meant to be kept from
+        let isolated = !publish_port;
+        if isolated && self.socket_path.is_some() {
+            tracing::warn!("Not mounting the docker socket in a container on an isolated network");
+        }
+        let mut binds = self
+            .socket_path
+            .iter()
+            .filter(|_| !isolated)
+            .map(|socket_path| format!("{}:/var/run/docker.sock", socket_path))
+            .collect::<Vec<_>>();
+        let mut env = None;
+        if let ContainerNetwork::None { socket_dir } = &network.network {
+            binds.push(format!("{}:{SERVICE_SOCKET_DIR}", socket_dir.display()));
+            env = Some(vec![format!(
+                "SWIFTIDE_DOCKER_SERVICE_SOCKET={SERVICE_SOCKET_DIR}/{SERVICE_SOCKET}"
+            )]);
+        }
+
+        let mut host_config = HostConfig {
+            // Containers that can run out of memory are kept when they stop, so an OOM kill
+            // can be told apart from other failures. They are removed on drop all the same.
+            auto_remove: Some(limits.memory.is_none()),
+            binds: (!binds.is_empty()).then_some(binds),
+            port_bindings,
+            network_mode: maybe_network,
+            extra_hosts: (!network.extra_hosts.is_empty()).then(|| network.extra_hosts.clone()),
+            dns: (!network.dns.is_empty()).then(|| network.dns.clone()),
+            ..Default::default()
+        };
+        limits.apply(&mut host_config);
+
         ContainerCreateBody {
             image: Some(image_name.to_string()),
             cmd: Some(vec!["swiftide-docker-service".to_string()]),
             tty: Some(true),
             user: user.map(|u| u.to_string()),
+            labels: Some(labels.clone()),
+            env,
             exposed_ports: Some(exposed_ports),
             networking_config: network_config,
-            host_config: Some(bollard::models::HostConfig {
-                auto_remove: Some(true),
-                binds: self
-                    .socket_path
-                    .as_ref()
-                    .map(|socket_path| vec![format!("{}:/var/run/docker.sock", socket_path)]),
-                port_bindings: Some(port_bindings),
-                network_mode: maybe_network.clone(),
-                ..Default::default()
-            }),
+            host_config: Some(host_config),
             ..Default::default()
         }
     }
 
     async fn maybe_docker_network(&self, docker: &Client) -> Option<String> {
-        // Check if we're running inside a container and try to get the network name
-        let self_id = std::env::var("HOSTNAME").unwrap_or_else(|_| "".into());
-        let me = docker
-            .inspect_container(&self_id, None::<InspectContainerOptions>)
-            .await
-            .ok()?;
+        let me = own_container(docker).await?;
         let nets = me.network_settings.and_then(|ns| ns.networks)?;
 
         // Pick the first network not bridge
@@ -82,3 +215,158 @@ impl ContainerConfigurator {
             .cloned()
     }
 }
+
+/// The container the executor runs in, if any
+pub(crate) async fn own_container(docker: &Client) -> Option<ContainerInspectResponse> {
+    let self_id = std::env::var("HOSTNAME").unwrap_or_else(|_| "".into());
+    docker
+        .inspect_container(&self_id, None::<InspectContainerOptions>)
+        .await
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_resource_limits() {
+        let limits = ResourceLimits {
+            memory: Some(512 * 1024 * 1024),
+            memory_swap: Some(-1),
+            cpus: Some(1.5),
+            cpu_shares: Some(512),
+            cpuset: Some("0-1".to_string()),
+            pids: Some(256),
+            ulimits: BTreeMap::from([("nofile".to_string(), (1024, 4096))]),
+            shm_size: Some(64 * 1024 * 1024),
+        };
+        let mut host_config = HostConfig::default();
+        limits.apply(&mut host_config);
+
+        assert_eq!(host_config.memory, Some(512 * 1024 * 1024));
+        assert_eq!(host_config.memory_swap, Some(-1));
+        assert_eq!(host_config.nano_cpus, Some(1_500_000_000));
+        assert_eq!(host_config.cpu_shares, Some(512));
+        assert_eq!(host_config.cpuset_cpus.as_deref(), Some("0-1"));
+        assert_eq!(host_config.pids_limit, Some(256));
+        assert_eq!(host_config.shm_size, Some(64 * 1024 * 1024));
+        assert_eq!(
+            host_config.ulimits,
+            Some(vec![ResourcesUlimits {
+                name: Some("nofile".to_string()),
+                soft: Some(1024),
+                hard: Some(4096),
+            }])
+        );
+
+        // Nothing is limited by default
+        let mut host_config = HostConfig::default();
+        ResourceLimits::default().apply(&mut host_config);
+        assert_eq!(host_config, HostConfig::default());
+    }
+
+    fn config(network: ContainerNetwork, maybe_network: Option<&str>) -> ContainerCreateBody {
+        let settings = NetworkSettings {
+            network,
+            extra_hosts: vec!["db:10.0.0.2".to_string()],
+            dns: vec!["1.1.1.1".to_string()],
+        };
+        ContainerConfigurator::new(Some("/var/run/docker.sock".to_string())).container_config(
+            "image",
+            None,
+            &HashMap::new(),
+            &ResourceLimits::default(),
+            &settings,
+            maybe_network.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn test_network_modes() {
+        let default = config(ContainerNetwork::Default, None);
+        let host_config = default.host_config.unwrap();
+        assert_eq!(host_config.network_mode, None);
+        assert!(host_config.port_bindings.is_some());
+        assert_eq!(
+            host_config.extra_hosts,
+            Some(vec!["db:10.0.0.2".to_string()])
+        );
+        assert_eq!(host_config.dns, Some(vec!["1.1.1.1".to_string()]));
+        assert_eq!(
+            host_config.binds,
+            Some(vec![
+                "/var/run/docker.sock:/var/run/docker.sock".to_string()
+            ])
+        );
+        assert_eq!(default.env, None);
+
+        let none = config(
+            ContainerNetwork::None {
+                socket_dir: PathBuf::from("/tmp/sockets"),
+            },
+            Some("none"),
+        );
+        let host_config = none.host_config.unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("none"));
+        assert_eq!(host_config.port_bindings, None);
+        assert_eq!(
+            host_config.binds,
+            Some(vec![
+                "/tmp/sockets:/run/swiftide-docker-service".to_string()
+            ])
+        );
+        assert_eq!(
+            none.env,
+            Some(vec![
+                "SWIFTIDE_DOCKER_SERVICE_SOCKET=/run/swiftide-docker-service/service.sock"
+                    .to_string()
+            ])
+        );
+        assert_eq!(none.networking_config, None);
+
+        let internal = config(
+            ContainerNetwork::Named {
+                name: "sandbox".to_string(),
+                internal: true,
+            },
+            Some("sandbox"),
+        );
+        let host_config = internal.host_config.unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("sandbox"));
+        assert_eq!(host_config.port_bindings, None);
+        assert_eq!(host_config.binds, None);
+        assert!(
+            internal
+                .networking_config
+                .and_then(|c| c.endpoints_config)
+                .unwrap()
+                .contains_key("sandbox")
+        );
+
+        let named = config(
+            ContainerNetwork::Named {
+                name: "services".to_string(),
+                internal: false,
+            },
+            Some("services"),
+        );
+        let host_config = named.host_config.unwrap();
+        assert!(host_config.port_bindings.is_some());
+        assert!(host_config.binds.is_some());
+
+        // The docker socket is only mounted when asked for
+        let host_config = ContainerConfigurator::new(None)
+            .container_config(
+                "image",
+                None,
+                &HashMap::new(),
+                &ResourceLimits::default(),
+                &NetworkSettings::default(),
+                None,
+            )
+            .host_config
+            .unwrap();
+        assert_eq!(host_config.binds, None);
+    }
+}
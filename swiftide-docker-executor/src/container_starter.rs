@@ -1,4 +1,6 @@
+use std::fmt;
 use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::{sync::Arc, time::Duration};
 
@@ -8,10 +10,57 @@ use bollard::{
         CreateContainerOptions, InspectContainerOptions, LogsOptions, StartContainerOptions,
     },
 };
+use hyper_util::rt::TokioIo;
 use swiftide_core::prelude::StreamExt as _;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint};
 use uuid::Uuid;
 
-use crate::{ContainerStartError, client::Client};
+use crate::{
+    ContainerStartError,
+    client::Client,
+    container_configurator::{ContainerNetwork, SERVICE_SOCKET},
+};
+
+/// Where the service in the container listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceAddress {
+    /// An ip and port that can be reached from the executor
+    Tcp { ip: IpAddr, port: String },
+    /// A unix socket on the host, for containers without a network
+    Unix(PathBuf),
+}
+
+impl ServiceAddress {
+    /// Opens a channel to the service
+    pub async fn connect(&self) -> Result<Channel, tonic::transport::Error> {
+        match self {
+            Self::Tcp { .. } => Endpoint::from_shared(self.to_string())?.connect().await,
+            Self::Unix(path) => {
+                let path = path.clone();
+
+                // The uri is not used, every connection goes to the socket
+                Endpoint::from_static("http://localhost")
+                    .connect_with_connector(tower::service_fn(move |_| {
+                        let path = path.clone();
+                        async move {
+                            Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?))
+                        }
+                    }))
+                    .await
+            }
+        }
+    }
+}
+
+impl fmt::Display for ServiceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { ip, port } => write!(f, "http://{ip}:{port}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
 
 pub struct ContainerStarter {
     docker: Arc<Client>,
@@ -27,7 +76,8 @@ impl ContainerStarter {
         image_name: &str,
         container_uuid: &Uuid,
         config: ContainerCreateBody,
-    ) -> Result<(String, IpAddr, String), ContainerStartError> {
+        network: &ContainerNetwork,
+    ) -> Result<(String, ServiceAddress), ContainerStartError> {
         // Strip tag suffix from image name if present
         let image_name = if let Some(index) = image_name.find(':') {
             &image_name[..index]
@@ -59,9 +109,9 @@ impl ContainerStarter {
             .map_err(ContainerStartError::Start)?;
 
         self.wait_for_logs(&container_id).await?;
-        let (ip, port) = self.get_ip_and_port(&container_id).await?;
+        let address = self.get_ip_and_port(&container_id, network).await?;
 
-        Ok((container_id, ip, port))
+        Ok((container_id, address))
     }
 
     async fn wait_for_logs(&self, container_id: &str) -> Result<(), ContainerStartError> {
@@ -115,25 +165,57 @@ impl ContainerStarter {
     async fn get_ip_and_port(
         &self,
         container_id: &str,
-    ) -> Result<(IpAddr, String), ContainerStartError> {
-        // If we have a container ip, return inner port and ip
-        // If we have a host gateway ip (we i.e. running inside docker compose or docker), return that and the inner port
-        // Otherwise return localhost and the mapped port
-        if let Some(ip) = self.get_container_ip(container_id).await? {
-            return Ok((ip, "50051".into()));
-        }
+        network: &ContainerNetwork,
+    ) -> Result<ServiceAddress, ContainerStartError> {
+        let inner = |ip| ServiceAddress::Tcp {
+            ip,
+            port: "50051".into(),
+        };
 
-        if let Some(ip) = self.host_gateway_ip() {
-            return Ok((ip, "50051".into()));
+        match network {
+            // Without a network, the service listens on a socket in a directory from the host
+            ContainerNetwork::None { socket_dir } => {
+                return Ok(ServiceAddress::Unix(socket_dir.join(SERVICE_SOCKET)));
+            }
+            // On a named network, the container is reached by its ip on that network. Internal
+            // networks have no published port to fall back to.
+            ContainerNetwork::Named { name, internal } => {
+                if let Some(ip) = self.get_container_ip(container_id, Some(name)).await? {
+                    return Ok(inner(ip));
+                }
+                if *internal {
+                    return Err(ContainerStartError::PortMapping(format!(
+                        "container has no ip on network {name}"
+                    )));
+                }
+            }
+            // If we have a container ip, return inner port and ip
+            // If we have a host gateway ip (we i.e. running inside docker compose or docker), return that and the inner port
+            ContainerNetwork::Default => {
+                if let Some(ip) = self.get_container_ip(container_id, None).await? {
+                    return Ok(inner(ip));
+                }
+
+                if let Some(ip) = self.host_gateway_ip() {
+                    return Ok(inner(ip));
+                }
+            }
         }
 
+        // Otherwise return localhost and the mapped port
         let container_port = self.get_container_port(container_id).await?;
-        Ok((IpAddr::from_str("127.0.0.1").unwrap(), container_port))
+        Ok(ServiceAddress::Tcp {
+            ip: IpAddr::from_str("127.0.0.1").unwrap(),
+            port: container_port,
+        })
     }
 
+    /// The ip of the container on a network, or on the first network that is not the default
+    /// bridge
     async fn get_container_ip(
         &self,
         container_id: &str,
+        network: Option<&str>,
     ) -> Result<Option<IpAddr>, ContainerStartError> {
         let container_info = self
             .docker
@@ -148,8 +230,14 @@ impl ContainerStarter {
                 tracing::debug!(networks = ?nets, "Container networks");
                 nets
             })
-            .and_then(|nets| nets.into_iter().find(|(k, _)| *k != "bridge"))
+            .and_then(|nets| {
+                nets.into_iter().find(|(k, _)| match network {
+                    Some(network) => k == network,
+                    None => *k != "bridge",
+                })
+            })
             .and_then(|(_, endpoint)| endpoint.ip_address)
+            .filter(|ip| !ip.is_empty())
             .as_deref()
             .map(IpAddr::from_str)
             .transpose()?)
@@ -1,43 +1,118 @@
 use std::{
-    os::unix::fs::MetadataExt as _,
+    ffi::OsString,
+    io::Write as _,
+    os::unix::{ffi::OsStrExt as _, fs::MetadataExt as _},
     path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll, ready},
 };
 
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use sha2::{Digest as _, Sha256};
 // use ignore::{overrides::OverrideBuilder, WalkBuilder};
-use tokio::io::AsyncReadExt as _;
+use bytes::Bytes;
+use flate2::{Compression, write::GzEncoder};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_tar::{Builder, EntryType, Header};
 use walkdir::{DirEntry, WalkDir};
 
-use crate::ContextError;
+use crate::{ContextError, dockerignore::DockerIgnore};
 
 type ContextArchive = Vec<u8>;
 
+/// Size of the chunks the archive is compressed and sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of compressed chunks that can wait to be sent
+const STREAM_CAPACITY: usize = 4;
+/// Number of archived files between progress reports
+const PROGRESS_INTERVAL: u64 = 1000;
+
 #[derive(Debug)]
 pub struct ContextBuilder {
     context_path: PathBuf,
-    ignore: Gitignore,
+    ignore: IgnoreRules,
     dockerfile: PathBuf,
+    dockerfile_name: OsString,
+    archive_metadata: ArchiveMetadata,
+    extra_paths: Vec<ExtraPath>,
+    files: Vec<ContextFile>,
+}
+
+/// A file or directory on the host that is added to the context at another path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExtraPath {
+    pub host_path: PathBuf,
+    pub destination: PathBuf,
+}
+
+/// A file that is added to the context from memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ContextFile {
+    pub destination: PathBuf,
+    pub contents: Vec<u8>,
+    pub mode: u32,
+}
+
+/// An additional named context, that stages and `COPY --from` in the Dockerfile can refer to
+/// like with `docker build --build-context <name>=<source>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildContext {
+    /// A directory on the host. It is added to the build context in [`BUILD_CONTEXTS_DIR`], and
+    /// copied from there into a stage of its own.
+    Path(PathBuf),
+    /// An existing image, i.e. `alpine:3.20`
+    Image(String),
+}
+
+/// Directory in the build context that holds the directories of named build contexts. It is
+/// removed from the working directory of the container if the Dockerfile copies it there.
+pub const BUILD_CONTEXTS_DIR: &str = ".swiftide-build-contexts";
+
+/// Which ownership and modification times end up in the archive
+///
+/// Permission bits are always kept, so executables stay executable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArchiveMetadata {
+    /// Owned by root with a modification time of 0, so archives are reproducible
+    #[default]
+    Normalized,
+    /// The owner and modification time of the files on the host
+    Preserved,
+}
+
+/// The combined `.gitignore`, `.dockerignore` and global gitignore rules of a context path
+///
+/// A path is ignored if any of them ignores it. The `.dockerignore` is matched with docker's
+/// semantics, the others with git's.
+#[derive(Debug)]
+pub(crate) struct IgnoreRules {
+    context_path: PathBuf,
+    ignore: Gitignore,
+    docker: DockerIgnore,
     global: Option<Gitignore>,
 }
 
-impl ContextBuilder {
-    pub fn from_path(
-        context_path: impl Into<PathBuf>,
-        dockerfile: impl AsRef<Path>,
-    ) -> Result<Self, ContextError> {
+impl IgnoreRules {
+    pub(crate) fn from_path(context_path: impl Into<PathBuf>) -> Result<Self, ContextError> {
         let path = context_path.into();
         let mut gitignore = GitignoreBuilder::new(&path);
 
         if let Some(err) = gitignore.add(path.join(".gitignore")) {
             tracing::warn!(?err, "Error adding .gitignore");
         }
-        if let Some(err) = gitignore.add(path.join(".dockerignore")) {
-            tracing::warn!(?err, "Error adding .dockerignore");
-        }
 
         let gitignore = gitignore.build()?;
 
+        let docker = DockerIgnore::from_path(&path).unwrap_or_else(|err| {
+            tracing::warn!(?err, "Error adding .dockerignore");
+            DockerIgnore::default()
+        });
+
         let (global_gitignore, maybe_error) = Gitignore::global();
         let maybe_global = if let Some(err) = maybe_error {
             tracing::warn!(?err, "Error adding global gitignore");
@@ -47,169 +122,736 @@ impl ContextBuilder {
         };
 
         Ok(Self {
-            dockerfile: dockerfile.as_ref().to_path_buf(),
             context_path: path,
             ignore: gitignore,
+            docker,
             global: maybe_global,
         })
     }
 
-    fn is_ignored(&self, path: impl AsRef<Path>) -> bool {
-        let Ok(relative_path) = path.as_ref().strip_prefix(&self.context_path) else {
+    pub(crate) fn is_ignored(&self, path: impl AsRef<Path>) -> bool {
+        self.is_ignored_as(path.as_ref(), false)
+    }
+
+    /// True if the directory itself is ignored, though an exception may include files in it
+    pub(crate) fn is_ignored_dir(&self, path: impl AsRef<Path>) -> bool {
+        self.is_ignored_as(path.as_ref(), true)
+    }
+
+    /// True if the directory and everything in it is ignored, so it does not have to be walked
+    ///
+    /// Git cannot re-include files in an ignored directory, but a `.dockerignore` exception can.
+    pub(crate) fn can_skip_dir(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let Some(relative_path) = self.relative_path(path) else {
+            return false;
+        };
+
+        self.is_git_ignored(relative_path, true) || self.docker.can_skip_dir(relative_path)
+    }
+
+    fn is_ignored_as(&self, path: &Path, is_dir: bool) -> bool {
+        let Some(relative_path) = self.relative_path(path) else {
+            return false;
+        };
+
+        let ignored =
+            self.is_git_ignored(relative_path, is_dir) || self.docker.is_ignored(relative_path);
+        if ignored {
+            tracing::debug!("ignoring {path}", path = path.display());
+        }
+
+        ignored
+    }
+
+    /// The path relative to the context, or `None` if it can never be ignored
+    fn relative_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        let Ok(relative_path) = path.strip_prefix(&self.context_path) else {
             tracing::debug!(
                 "not ignoring {path} as it seems to be not prefixed by {prefix}",
-                path = path.as_ref().display(),
+                path = path.display(),
                 prefix = self.context_path.to_string_lossy()
             );
-            return false;
+            return None;
         };
 
         if relative_path.starts_with(".git") {
             tracing::debug!(
                 "not ignoring {path} as it seems to be a git file",
-                path = path.as_ref().display()
+                path = path.display()
             );
-            return false;
+            return None;
         }
 
+        Some(relative_path)
+    }
+
+    fn is_git_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
         if let Some(global) = &self.global
             && global
-                .matched_path_or_any_parents(relative_path, false)
+                .matched_path_or_any_parents(relative_path, is_dir)
                 .is_ignore()
         {
-            tracing::debug!(
-                "ignoring {path} as it is ignored by global gitignore",
-                path = path.as_ref().display()
-            );
             return true;
         }
 
         self.ignore
-            .matched_path_or_any_parents(relative_path, false)
+            .matched_path_or_any_parents(relative_path, is_dir)
             .is_ignore()
     }
+}
+
+impl ContextBuilder {
+    pub fn from_path(
+        context_path: impl Into<PathBuf>,
+        dockerfile: impl AsRef<Path>,
+    ) -> Result<Self, ContextError> {
+        let path = context_path.into();
+        let dockerfile = dockerfile.as_ref().to_path_buf();
+
+        Ok(Self {
+            dockerfile_name: dockerfile
+                .file_name()
+                .ok_or_else(|| {
+                    ContextError::CustomDockerfile("Dockerfile path has no file name".to_string())
+                })?
+                .to_os_string(),
+            dockerfile,
+            ignore: IgnoreRules::from_path(&path)?,
+            context_path: path,
+            archive_metadata: ArchiveMetadata::default(),
+            extra_paths: Vec::new(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Adds a file or directory on the host to the context at the destination
+    ///
+    /// Directories are added with their own ignore files applied. Extra paths take precedence
+    /// over files in the context path.
+    pub fn with_extra_path(
+        &mut self,
+        host_path: impl Into<PathBuf>,
+        destination: impl Into<PathBuf>,
+    ) -> &mut Self {
+        self.extra_paths.push(ExtraPath {
+            host_path: host_path.into(),
+            destination: destination.into(),
+        });
+
+        self
+    }
+
+    /// Adds a file with the given contents and permissions to the context at the destination
+    ///
+    /// Files are added last, so they take precedence over anything else at the same path.
+    pub fn with_file(
+        &mut self,
+        destination: impl Into<PathBuf>,
+        contents: impl Into<Vec<u8>>,
+        mode: u32,
+    ) -> &mut Self {
+        self.files.push(ContextFile {
+            destination: destination.into(),
+            contents: contents.into(),
+            mode,
+        });
+
+        self
+    }
+
+    /// Set the name of the Dockerfile in the archive (default is the file name of the Dockerfile)
+    pub fn with_dockerfile_name(&mut self, name: impl Into<OsString>) -> &mut Self {
+        self.dockerfile_name = name.into();
+
+        self
+    }
+
+    /// Set which ownership and modification times are archived (default is normalized)
+    pub fn with_archive_metadata(&mut self, archive_metadata: ArchiveMetadata) -> &mut Self {
+        self.archive_metadata = archive_metadata;
+
+        self
+    }
+
+    /// Returns a sha256 hex digest of the Dockerfile and everything that goes into the archive
+    ///
+    /// Entries are hashed in the same order as they are archived, with their permission bits but
+    /// without their ownership and modification times, so the hash only changes if the
+    /// Dockerfile or the contents of the context do.
+    pub async fn content_hash(&self) -> Result<String, ContextError> {
+        let mut hasher = Sha256::new();
+
+        hasher.update(b"dockerfile\0");
+        hasher.update(fs_err::tokio::read(&self.dockerfile).await?);
+        hasher.update(match self.archive_metadata {
+            ArchiveMetadata::Normalized => b"normalized\0".as_slice(),
+            ArchiveMetadata::Preserved => b"preserved\0".as_slice(),
+        });
+
+        for entry in self.entries() {
+            let (relative_path, entry) = match entry? {
+                Entry::Host { path, entry } => (path, entry),
+                Entry::Memory(file) => {
+                    hash_entry(
+                        &mut hasher,
+                        b'f',
+                        &file.destination,
+                        file.mode,
+                        &file.contents,
+                    );
+                    continue;
+                }
+            };
+            let relative_path = relative_path.as_path();
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let mode = permissions(&metadata);
+            self.hash_metadata(&mut hasher, &metadata);
+
+            if path.is_dir() && !path.is_symlink() {
+                hash_entry(&mut hasher, b'd', relative_path, mode, &[]);
+            } else if path.is_symlink() {
+                let Ok(link_target) = tokio::fs::read_link(path).await else {
+                    continue;
+                };
+                hash_entry(
+                    &mut hasher,
+                    b'l',
+                    relative_path,
+                    mode,
+                    link_target.as_os_str().as_bytes(),
+                );
+            } else {
+                hash_file(&mut hasher, path, relative_path, mode).await?;
+            }
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Lists everything that would be sent as the context, in the order of the archive, without
+    /// reading any file
+    ///
+    /// The Dockerfile comes first, under the name it has in the archive.
+    pub fn dry_run(&self) -> Result<Vec<ContextEntry>, ContextError> {
+        let mut entries = vec![ContextEntry {
+            path: PathBuf::from(&self.dockerfile_name),
+            kind: ContextEntryKind::File,
+            size: fs_err::metadata(&self.dockerfile)?.len(),
+        }];
+
+        for entry in self.entries() {
+            let (path, entry) = match entry? {
+                Entry::Host { path, entry } => (path, entry),
+                Entry::Memory(file) => {
+                    entries.push(ContextEntry {
+                        path: file.destination.clone(),
+                        kind: ContextEntryKind::File,
+                        size: file.contents.len() as u64,
+                    });
+                    continue;
+                }
+            };
+            let file_type = entry.file_type();
+
+            let (kind, size) = if file_type.is_dir() {
+                (ContextEntryKind::Directory, 0)
+            } else if file_type.is_symlink() {
+                (ContextEntryKind::Symlink, 0)
+            } else {
+                (ContextEntryKind::File, entry.metadata()?.len())
+            };
+
+            entries.push(ContextEntry { path, kind, size });
+        }
+
+        Ok(entries)
+    }
+
+    /// Everything that goes into the archive after the Dockerfile: the context path, then the
+    /// extra paths and then the files from memory
+    fn entries(&self) -> impl Iterator<Item = Result<Entry<'_>, ContextError>> {
+        let context = walk(&self.context_path, &self.ignore).map(|entry| {
+            let path = entry.path().strip_prefix(&self.context_path)?.to_path_buf();
+            Ok(Entry::Host { path, entry })
+        });
+
+        let extra_paths = self
+            .extra_paths
+            .iter()
+            .flat_map(|extra| match walk_extra_path(extra) {
+                Ok(entries) => entries.into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            });
+
+        let files = self.files.iter().map(|file| {
+            check_destination(&file.destination)?;
+            Ok(Entry::Memory(file))
+        });
+
+        context.chain(extra_paths).chain(files)
+    }
+
+    /// Hashes the owner and modification time of an entry, if they end up in the archive
+    fn hash_metadata(&self, hasher: &mut Sha256, metadata: &std::fs::Metadata) {
+        if self.archive_metadata == ArchiveMetadata::Preserved {
+            hasher.update(metadata.uid().to_le_bytes());
+            hasher.update(metadata.gid().to_le_bytes());
+            hasher.update(metadata.mtime().to_le_bytes());
+        }
+    }
+
+    /// Creates a tar header with the permissions of the entry, and its ownership and
+    /// modification time if those are preserved
+    fn header(&self, metadata: &std::fs::Metadata, entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(permissions(metadata));
+
+        match self.archive_metadata {
+            ArchiveMetadata::Normalized => {
+                header.set_uid(0);
+                header.set_gid(0);
+                header.set_mtime(0);
+            }
+            ArchiveMetadata::Preserved => {
+                header.set_uid(metadata.uid().into());
+                header.set_gid(metadata.gid().into());
+                header.set_mtime(metadata.mtime().max(0) as u64);
+            }
+        }
 
-    fn iter(&self) -> impl Iterator<Item = Result<DirEntry, walkdir::Error>> {
-        WalkDir::new(&self.context_path).into_iter()
+        header
     }
 
+    /// Builds the archive of the context in memory
+    ///
+    /// Prefer [`ContextBuilder::stream_tar`] for large contexts.
     pub async fn build_tar(&self) -> Result<ContextArchive, ContextError> {
-        let buffer = Vec::new();
+        let mut stats = ContextStats::default();
+
+        self.write_tar(Vec::new(), &mut stats).await
+    }
 
-        let mut tar = Builder::new(buffer);
+    /// Streams a gzipped archive of the context while it is built
+    ///
+    /// Files are read, archived and compressed a chunk at a time, so memory use stays bounded
+    /// regardless of the size of the context.
+    pub fn stream_tar(self) -> ContextStream {
+        let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+
+        let stats = tokio::spawn(async move {
+            let (tar_writer, tar_reader) = tokio::io::duplex(CHUNK_SIZE);
+            let mut stats = ContextStats::default();
+
+            let (written, compressed) = tokio::join!(
+                async {
+                    // Dropping the writer ends the archive for the compressor
+                    self.write_tar(tar_writer, &mut stats).await.map(drop)
+                },
+                compress(tar_reader, &sender)
+            );
+
+            if sender.is_closed() {
+                // The build stopped reading the context, and reports why itself
+                tracing::debug!(?stats, "Stopped archiving context");
+                return Ok(stats);
+            }
+
+            let result = match (written, compressed) {
+                (Err(err), _) | (Ok(()), Err(err)) => Err(err),
+                (Ok(()), Ok(compressed_bytes)) => {
+                    stats.compressed_bytes = compressed_bytes;
+                    Ok(stats)
+                }
+            };
+
+            // Fail the body, so the build does not succeed on a truncated context
+            if let Err(err) = &result {
+                let _ = sender
+                    .send(Err(std::io::Error::other(err.to_string())))
+                    .await;
+            }
+
+            result
+        });
+
+        ContextStream {
+            body: ReceiverStream::new(receiver),
+            stats,
+        }
+    }
+
+    async fn write_tar<W>(&self, writer: W, stats: &mut ContextStats) -> Result<W, ContextError>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut tar = Builder::new(writer);
 
         // First lets add the actual dockerfile
-        let mut file = fs_err::tokio::File::open(&self.dockerfile).await?;
-        let mut buffer_content = Vec::new();
-        file.read_to_end(&mut buffer_content).await?;
+        let buffer_content = fs_err::tokio::read(&self.dockerfile).await?;
 
-        // Prepare header for Dockerfile
+        // Prepare header for Dockerfile, which is generated and has no metadata worth keeping
         let mut header = Header::new_gnu();
         header.set_size(buffer_content.len() as u64);
         header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
         header.set_cksum();
 
         // Add Dockerfile to tar
-        tar.append_data(
-            &mut header,
-            self.dockerfile
-                .file_name()
-                .expect("Infallible; No file name"),
-            &*buffer_content,
-        )
-        .await?;
+        tar.append_data(&mut header, &self.dockerfile_name, &*buffer_content)
+            .await?;
+        stats.add_file(buffer_content.len() as u64);
 
-        for entry in self.iter() {
-            let Ok(entry) = entry else {
-                tracing::warn!(?entry, "Failed to read entry");
-                continue;
+        for entry in self.entries() {
+            let (relative_path, entry) = match entry? {
+                Entry::Host { path, entry } => (path, entry),
+                Entry::Memory(file) => {
+                    tracing::debug!(path = ?file.destination, "Adding file from memory to tar");
+                    let mut header = Header::new_gnu();
+                    header.set_size(file.contents.len() as u64);
+                    header.set_mode(file.mode);
+                    header.set_uid(0);
+                    header.set_gid(0);
+                    header.set_mtime(0);
+
+                    tar.append_data(&mut header, &file.destination, &*file.contents)
+                        .await?;
+                    stats.add_file(file.contents.len() as u64);
+                    continue;
+                }
             };
+            let relative_path = relative_path.as_path();
             let path = entry.path();
 
-            let Ok(relative_path) = path.strip_prefix(&self.context_path) else {
-                tracing::warn!(?path, "Failed to strip prefix on path");
+            let Ok(metadata) = entry.metadata() else {
+                tracing::warn!(?path, "Failed to read metadata");
                 continue;
             };
 
             if path.is_dir() && !path.is_symlink() {
                 tracing::debug!(path = ?path, relative_path = ?relative_path, "Adding directory to tar");
-                if let Err(err) = tar.append_path(relative_path).await {
-                    tracing::warn!(?err, "Failed to append path to tar");
+                let mut header = self.header(&metadata, EntryType::Directory, 0);
+                if let Err(err) = tar
+                    .append_data(&mut header, relative_path, tokio::io::empty())
+                    .await
+                {
+                    tracing::warn!(?err, "Failed to append directory to tar");
                 }
                 continue;
             }
 
-            if self.is_ignored(path) {
-                tracing::debug!(path = ?path, "Ignored file");
-                continue;
-            }
-
             if path.is_symlink() {
                 tracing::debug!(path = ?path, "Adding symlink to tar");
                 let Ok(link_target) = tokio::fs::read_link(path).await else {
                     continue;
                 }; // The target of the symlink
-                let Ok(metadata) = entry.metadata() else {
-                    continue;
-                };
                 tracing::debug!(link_target = ?link_target, "Symlink target");
-                let mut header = Header::new_gnu();
 
-                // Indicate it's a symlink
-                header.set_entry_type(EntryType::Symlink);
+                // Symlinks don’t store file data in the tar, so size is 0
+                let mut header = self.header(&metadata, EntryType::Symlink, 0);
                 // The tar specification requires setting the link name for a symlink
                 if let Err(error) = header.set_link_name(&link_target) {
                     tracing::warn!(?error, "Failed to set link name on {link_target:#?}");
                     continue;
                 }
 
-                // Set ownership, permissions, etc.
-                header.set_uid(metadata.uid() as u64);
-                header.set_gid(metadata.gid() as u64);
-                // For a symlink, the "mode" is often ignored by many tools,
-                // but we’ll set it anyway to match the source:
-                header.set_mode(metadata.mode());
-                // Set modification time (use 0 or a real timestamp as you prefer)
-                header.set_mtime(metadata.mtime() as u64);
-                // Symlinks don’t store file data in the tar, so size is 0
-                header.set_size(0);
-
-                if let Err(error) = tar.append_data(&mut header, path, tokio::io::empty()).await {
+                if let Err(error) = tar
+                    .append_data(&mut header, relative_path, tokio::io::empty())
+                    .await
+                {
                     tracing::warn!(
                         ?error,
                         "Failed to append symlink to tar on {link_target:#?}"
                     );
                     continue;
                 }
+                stats.add_file(0);
                 continue;
             }
 
             tracing::debug!(path = ?path, "Adding file to tar");
-            let mut file = fs_err::tokio::File::open(path).await?;
-            let mut buffer_content = Vec::new();
-            file.read_to_end(&mut buffer_content).await?;
-
-            let mut header = Header::new_gnu();
-            header.set_size(buffer_content.len() as u64);
-            header.set_mode(0o644);
-            header.set_cksum();
-
-            let relative_path = path.strip_prefix(&self.context_path)?;
-            tar.append_data(&mut header, relative_path, &*buffer_content)
-                .await?;
+            let file = fs_err::tokio::File::open(path).await?;
+            let size = file.metadata().await?.len();
+
+            let mut header = self.header(&metadata, EntryType::Regular, size);
+
+            // Files that grow while archiving are cut off at the size in the header, files that
+            // shrink fail the archive instead of leaving it shorter than the header
+            let mut data = ExactSize::new(file, size);
+            if let Err(err) = tar.append_data(&mut header, relative_path, &mut data).await {
+                return Err(if data.ended_early {
+                    ContextError::FileShrank(path.to_path_buf())
+                } else {
+                    err.into()
+                });
+            }
+            stats.add_file(size);
         }
 
         let result = tar.into_inner().await?;
 
+        tracing::info!(
+            files = stats.files,
+            bytes = stats.bytes,
+            "Archived context from {}",
+            self.context_path.display()
+        );
+
         Ok(result)
     }
 }
 
+/// An entry of the context, as listed by [`ContextBuilder::dry_run`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextEntry {
+    /// Path in the archive, relative to the context
+    pub path: PathBuf,
+    pub kind: ContextEntryKind,
+    /// Size in bytes, zero for directories and symlinks
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextEntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Number of files and bytes in a context archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextStats {
+    /// Files and symlinks in the archive, including the Dockerfile
+    pub files: u64,
+    /// Size of the archived files before compression
+    pub bytes: u64,
+    /// Size of the gzipped archive
+    pub compressed_bytes: u64,
+}
+
+impl ContextStats {
+    fn add_file(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+
+        if self.files.is_multiple_of(PROGRESS_INTERVAL) {
+            tracing::info!(files = self.files, bytes = self.bytes, "Archiving context");
+        }
+    }
+}
+
+/// A gzipped archive of the context that is built while it is read
+#[derive(Debug)]
+pub struct ContextStream {
+    /// Chunks of the gzipped archive
+    pub body: ReceiverStream<std::io::Result<Bytes>>,
+    /// Resolves to the stats of the archive once it is complete, or the error that stopped it
+    pub stats: JoinHandle<Result<ContextStats, ContextError>>,
+}
+
+/// Gzips everything read from the reader into chunks, returning the compressed size
+///
+/// Stops early without an error if nobody is receiving anymore.
+async fn compress(
+    mut reader: impl AsyncRead + Unpin,
+    sender: &mpsc::Sender<std::io::Result<Bytes>>,
+) -> Result<u64, ContextError> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(CHUNK_SIZE), Compression::default());
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut compressed_bytes = 0;
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        encoder.write_all(&buffer[..read])?;
+
+        if encoder.get_ref().len() >= CHUNK_SIZE {
+            let chunk = std::mem::take(encoder.get_mut());
+            compressed_bytes += chunk.len() as u64;
+            if sender.send(Ok(chunk.into())).await.is_err() {
+                return Ok(compressed_bytes);
+            }
+        }
+    }
+
+    let chunk = encoder.finish()?;
+    compressed_bytes += chunk.len() as u64;
+    let _ = sender.send(Ok(chunk.into())).await;
+
+    Ok(compressed_bytes)
+}
+
+/// Reads exactly `size` bytes, failing if the reader ends before that
+struct ExactSize<R> {
+    inner: tokio::io::Take<R>,
+    ended_early: bool,
+}
+
+impl<R: AsyncRead + Unpin> ExactSize<R> {
+    fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner: inner.take(size),
+            ended_early: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ExactSize<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if buf.filled().len() == filled && buf.remaining() > 0 && self.inner.limit() > 0 {
+            self.ended_early = true;
+            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// An entry of the archive
+enum Entry<'a> {
+    /// Walked on the host, with its path in the archive
+    Host {
+        path: PathBuf,
+        entry: DirEntry,
+    },
+    Memory(&'a ContextFile),
+}
+
+/// Walks a path in a stable order, so archives and hashes are reproducible
+///
+/// Ignored entries are left out. Ignored directories are not walked at all, unless a
+/// `.dockerignore` exception could include something in them. A file is walked as itself.
+fn walk<'a>(root: &'a Path, ignore: &'a IgnoreRules) -> impl Iterator<Item = DirEntry> + 'a {
+    WalkDir::new(root)
+        .min_depth(usize::from(root.is_dir()))
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !(entry.file_type().is_dir() && ignore.can_skip_dir(entry.path())))
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                tracing::warn!(?err, "Failed to read entry");
+                None
+            }
+        })
+        .filter(|entry| {
+            if entry.file_type().is_dir() {
+                !ignore.is_ignored_dir(entry.path())
+            } else {
+                !ignore.is_ignored(entry.path())
+            }
+        })
+}
+
+/// Walks an extra path with its own ignore files, placing the entries at its destination
+fn walk_extra_path<'a>(extra: &ExtraPath) -> Result<Vec<Entry<'a>>, ContextError> {
+    check_destination(&extra.destination)?;
+    let ignore = IgnoreRules::from_path(&extra.host_path)?;
+
+    walk(&extra.host_path, &ignore)
+        .map(|entry| {
+            let relative_path = entry.path().strip_prefix(&extra.host_path)?;
+            let path = if relative_path.as_os_str().is_empty() {
+                extra.destination.clone()
+            } else {
+                extra.destination.join(relative_path)
+            };
+
+            Ok(Entry::Host { path, entry })
+        })
+        .collect()
+}
+
+/// Destinations must stay inside the context
+fn check_destination(destination: &Path) -> Result<(), ContextError> {
+    let is_inside = destination
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+
+    if destination.as_os_str().is_empty() || !is_inside {
+        return Err(ContextError::InvalidDestination(destination.to_path_buf()));
+    }
+
+    Ok(())
+}
+
+/// Permission bits of a file, including setuid, setgid and sticky bits
+fn permissions(metadata: &std::fs::Metadata) -> u32 {
+    metadata.mode() & 0o7777
+}
+
+/// Hashes the kind, path, mode and contents of an entry, length prefixed so entries cannot run
+/// together
+fn hash_entry(hasher: &mut Sha256, kind: u8, path: &Path, mode: u32, content: &[u8]) {
+    hash_entry_header(hasher, kind, path, mode, content.len() as u64);
+    hasher.update(content);
+}
+
+/// Hashes a file on the host like [`hash_entry`], streaming its contents in chunks instead of
+/// reading it into memory
+///
+/// Like the archive, the contents are cut off at the size the file had when opened, and a file
+/// that shrinks fails the hash.
+async fn hash_file(
+    hasher: &mut Sha256,
+    path: &Path,
+    relative_path: &Path,
+    mode: u32,
+) -> Result<(), ContextError> {
+    let file = fs_err::tokio::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    hash_entry_header(hasher, b'f', relative_path, mode, size);
+
+    let mut data = ExactSize::new(file, size);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = match data.read(&mut buffer).await {
+            Ok(read) => read,
+            Err(_) if data.ended_early => {
+                return Err(ContextError::FileShrank(path.to_path_buf()));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Hashes everything of an entry but its contents
+fn hash_entry_header(hasher: &mut Sha256, kind: u8, path: &Path, mode: u32, content_len: u64) {
+    let path = path.as_os_str().as_bytes();
+
+    hasher.update([kind]);
+    hasher.update(mode.to_le_bytes());
+    hasher.update((path.len() as u64).to_le_bytes());
+    hasher.update(path);
+    hasher.update(content_len.to_le_bytes());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
-    use std::io::Write;
+    use std::io::{Read as _, Write};
+    use swiftide_core::prelude::StreamExt as _;
     use tempfile::{NamedTempFile, tempdir};
 
     #[test_log::test(tokio::test)]
@@ -240,9 +882,9 @@ mod tests {
         fs::File::create(&tmp_file).unwrap();
         fs::File::create(&txt_file).unwrap();
 
-        assert!(context_builder.is_ignored(&log_file));
-        assert!(context_builder.is_ignored(&tmp_file));
-        assert!(!context_builder.is_ignored(&txt_file));
+        assert!(context_builder.ignore.is_ignored(&log_file));
+        assert!(context_builder.ignore.is_ignored(&tmp_file));
+        assert!(!context_builder.ignore.is_ignored(&txt_file));
     }
 
     #[test_log::test(tokio::test)]
@@ -257,7 +899,7 @@ mod tests {
         let dockerfile = NamedTempFile::new().unwrap();
         let context_builder = ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
 
-        assert!(!context_builder.is_ignored(".git"));
+        assert!(!context_builder.ignore.is_ignored(".git"));
     }
 
     #[test_log::test(tokio::test)]
@@ -271,11 +913,401 @@ mod tests {
 
         let context_builder = ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
 
-        assert!(!context_builder.is_ignored(".git"));
-        assert!(!context_builder.is_ignored("Dockerfile"));
+        assert!(!context_builder.ignore.is_ignored(".git"));
+        assert!(!context_builder.ignore.is_ignored("Dockerfile"));
 
         fs::File::create(context_path.join("Dockerfile")).unwrap();
 
-        assert!(!context_builder.is_ignored("Dockerfile"));
+        assert!(!context_builder.ignore.is_ignored("Dockerfile"));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_content_hash() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        fs::write(context_path.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(context_path.join("main.rs"), "fn main() {}").unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let hash = || async {
+            ContextBuilder::from_path(&context_path, dockerfile.path())
+                .unwrap()
+                .content_hash()
+                .await
+                .unwrap()
+        };
+
+        let original = hash().await;
+        assert_eq!(original.len(), 64);
+
+        // Ignored files and modification times do not matter
+        fs::write(context_path.join("debug.log"), "noise").unwrap();
+        let file = fs::File::options()
+            .write(true)
+            .open(context_path.join("main.rs"))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(hash().await, original);
+
+        fs::write(context_path.join("main.rs"), "fn main() { changed() }").unwrap();
+        let changed = hash().await;
+        assert_ne!(changed, original);
+
+        fs::write(dockerfile.path(), "FROM ubuntu").unwrap();
+        assert_ne!(hash().await, changed);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_content_hash_of_files_larger_than_a_chunk() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        let mut large = vec![b'a'; 3 * CHUNK_SIZE + 1];
+        fs::write(context_path.join("large.bin"), &large).unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let hash = || async {
+            ContextBuilder::from_path(&context_path, dockerfile.path())
+                .unwrap()
+                .content_hash()
+                .await
+                .unwrap()
+        };
+
+        let original = hash().await;
+        assert_eq!(hash().await, original);
+
+        // The last byte is in a chunk of its own
+        *large.last_mut().unwrap() = b'b';
+        fs::write(context_path.join("large.bin"), &large).unwrap();
+        assert_ne!(hash().await, original);
+    }
+
+    #[tokio::test]
+    async fn test_content_hash_with_preserved_metadata() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        fs::write(context_path.join("main.rs"), "fn main() {}").unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let dockerfile_path = dockerfile.path();
+        let hash = |archive_metadata| {
+            let context_path = &context_path;
+            async move {
+                ContextBuilder::from_path(context_path, dockerfile_path)
+                    .unwrap()
+                    .with_archive_metadata(archive_metadata)
+                    .content_hash()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let normalized = hash(ArchiveMetadata::Normalized).await;
+        let preserved = hash(ArchiveMetadata::Preserved).await;
+        assert_ne!(normalized, preserved);
+
+        // Modification times are in the archive, so they change the image
+        let file = fs::File::options()
+            .write(true)
+            .open(context_path.join("main.rs"))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert_ne!(hash(ArchiveMetadata::Preserved).await, preserved);
+        assert_eq!(hash(ArchiveMetadata::Normalized).await, normalized);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_stream_tar() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        fs::write(context_path.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(context_path.join("debug.log"), "noise").unwrap();
+        fs::create_dir(context_path.join("src")).unwrap();
+        // Larger than a chunk after compression, so the archive is sent in several
+        let mut seed = 42_u64;
+        let large = (0..4 * CHUNK_SIZE)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                char::from(b'a' + (seed % 26) as u8)
+            })
+            .collect::<String>();
+        fs::write(context_path.join("src/main.rs"), &large).unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let context_builder = ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
+        let expected = context_builder.build_tar().await.unwrap();
+
+        let ContextStream { body, stats } = context_builder.stream_tar();
+        let chunks = body.collect::<Vec<_>>().await;
+        assert!(chunks.len() > 1);
+        let compressed = chunks
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .concat();
+
+        let mut archive = Vec::new();
+        flate2::read::GzDecoder::new(&*compressed)
+            .read_to_end(&mut archive)
+            .unwrap();
+        assert_eq!(archive, expected);
+
+        let stats = stats.await.unwrap().unwrap();
+        assert_eq!(
+            stats,
+            ContextStats {
+                // The Dockerfile, .gitignore and main.rs
+                files: 3,
+                bytes: ("FROM alpine".len() + "*.log\n".len() + large.len()) as u64,
+                compressed_bytes: compressed.len() as u64,
+            }
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_archive_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        fs::create_dir(context_path.join("bin")).unwrap();
+        fs::write(context_path.join("bin/setup"), "#!/bin/sh\necho hi").unwrap();
+        fs::set_permissions(
+            context_path.join("bin/setup"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(context_path.join("README.md"), "# Readme").unwrap();
+        fs::set_permissions(
+            context_path.join("README.md"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        let mut context_builder =
+            ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
+
+        let headers = |archive: Vec<u8>| async move {
+            let mut archive = tokio_tar::Archive::new(&*archive);
+            let mut entries = archive.entries().unwrap();
+            let mut headers = std::collections::HashMap::new();
+            while let Some(entry) = entries.next().await {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                headers.insert(
+                    entry
+                        .path()
+                        .unwrap()
+                        .to_string_lossy()
+                        .trim_end_matches('/')
+                        .to_string(),
+                    (
+                        header.mode().unwrap(),
+                        header.uid().unwrap(),
+                        header.mtime().unwrap(),
+                    ),
+                );
+            }
+            headers
+        };
+
+        let normalized = headers(context_builder.build_tar().await.unwrap()).await;
+        assert_eq!(normalized["bin/setup"], (0o755, 0, 0));
+        assert_eq!(normalized["README.md"], (0o640, 0, 0));
+        assert_eq!(normalized["bin"].1, 0);
+
+        let metadata = fs::metadata(context_path.join("bin/setup")).unwrap();
+        context_builder.with_archive_metadata(ArchiveMetadata::Preserved);
+        let preserved = headers(context_builder.build_tar().await.unwrap()).await;
+        assert_eq!(
+            preserved["bin/setup"],
+            (0o755, metadata.uid().into(), metadata.mtime() as u64)
+        );
+
+        // Changing permissions changes the image
+        let hash = context_builder.content_hash().await.unwrap();
+        fs::set_permissions(
+            context_path.join("bin/setup"),
+            fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        assert_ne!(context_builder.content_hash().await.unwrap(), hash);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_dry_run_prunes_ignored_directories() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        let dockerignore = "target\nnode_modules\n!node_modules/keep.js\n**/*.log\n";
+        fs::write(context_path.join(".dockerignore"), dockerignore).unwrap();
+        for dir in ["src/logs", "target/debug", "node_modules/react"] {
+            fs::create_dir_all(context_path.join(dir)).unwrap();
+        }
+        for file in [
+            "src/main.rs",
+            "src/logs/debug.log",
+            "target/debug/app",
+            "node_modules/keep.js",
+            "node_modules/react/index.js",
+        ] {
+            fs::write(context_path.join(file), "content").unwrap();
+        }
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let mut context_builder =
+            ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
+        context_builder.with_dockerfile_name("Dockerfile");
+
+        let entries = context_builder.dry_run().unwrap();
+        let listed = entries
+            .iter()
+            .map(|entry| (entry.path.to_str().unwrap(), entry.kind, entry.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            [
+                ("Dockerfile", ContextEntryKind::File, 11),
+                (
+                    ".dockerignore",
+                    ContextEntryKind::File,
+                    dockerignore.len() as u64
+                ),
+                ("node_modules/keep.js", ContextEntryKind::File, 7),
+                ("src", ContextEntryKind::Directory, 0),
+                ("src/logs", ContextEntryKind::Directory, 0),
+                ("src/main.rs", ContextEntryKind::File, 7),
+            ]
+        );
+
+        // The archive has the same entries
+        let archive = context_builder.build_tar().await.unwrap();
+        let mut archive = tokio_tar::Archive::new(&*archive);
+        let mut entries = archive.entries().unwrap();
+        let mut archived = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let path = entry.unwrap().path().unwrap().into_owned();
+            archived.push(path.to_str().unwrap().trim_end_matches('/').to_string());
+        }
+        assert_eq!(
+            archived,
+            listed
+                .iter()
+                .map(|(path, _, _)| path.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_extra_paths_and_files() {
+        let dir = tempdir().unwrap();
+        let context_path = dir.path().to_path_buf();
+        fs::write(context_path.join("main.rs"), "fn main() {}").unwrap();
+
+        let extra = tempdir().unwrap();
+        fs::write(extra.path().join(".dockerignore"), "*.log\n").unwrap();
+        fs::write(extra.path().join("fixture.json"), "{}").unwrap();
+        fs::write(extra.path().join("debug.log"), "noise").unwrap();
+        let single = NamedTempFile::new().unwrap();
+        fs::write(single.path(), "single").unwrap();
+
+        let dockerfile = NamedTempFile::new().unwrap();
+        fs::write(dockerfile.path(), "FROM alpine").unwrap();
+        let mut context_builder =
+            ContextBuilder::from_path(&context_path, dockerfile.path()).unwrap();
+        context_builder.with_dockerfile_name("Dockerfile");
+        let hash = context_builder.content_hash().await.unwrap();
+
+        context_builder
+            .with_extra_path(extra.path(), "fixtures")
+            .with_extra_path(single.path(), "config/single.txt")
+            .with_file("bin/run.sh", "#!/bin/sh", 0o755);
+
+        let listed = context_builder
+            .dry_run()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.path.to_str().unwrap().to_string(), entry.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            [
+                ("Dockerfile".to_string(), ContextEntryKind::File),
+                ("main.rs".to_string(), ContextEntryKind::File),
+                ("fixtures/.dockerignore".to_string(), ContextEntryKind::File),
+                ("fixtures/fixture.json".to_string(), ContextEntryKind::File),
+                ("config/single.txt".to_string(), ContextEntryKind::File),
+                ("bin/run.sh".to_string(), ContextEntryKind::File),
+            ]
+        );
+
+        let archive = context_builder.build_tar().await.unwrap();
+        let mut archive = tokio_tar::Archive::new(&*archive);
+        let mut entries = archive.entries().unwrap();
+        let mut archived = std::collections::HashMap::new();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let mode = entry.header().mode().unwrap();
+            let mut contents = String::new();
+            tokio::io::AsyncReadExt::read_to_string(&mut entry, &mut contents)
+                .await
+                .unwrap();
+            archived.insert(path, (contents, mode));
+        }
+        assert_eq!(archived["config/single.txt"].0, "single");
+        assert_eq!(archived["bin/run.sh"], ("#!/bin/sh".to_string(), 0o755));
+
+        // Extra paths and files are part of the hash
+        let with_extras = context_builder.content_hash().await.unwrap();
+        assert_ne!(with_extras, hash);
+        context_builder.with_file("bin/run.sh", "#!/bin/bash", 0o755);
+        assert_ne!(context_builder.content_hash().await.unwrap(), with_extras);
+    }
+
+    #[tokio::test]
+    async fn test_exact_size_fails_on_short_files() {
+        let mut data = ExactSize::new(&b"fn main() {}"[..], 4);
+        let mut read = Vec::new();
+        data.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"fn m");
+        assert!(!data.ended_early);
+
+        // A file that shrank since its size was read
+        let mut data = ExactSize::new(&b"fn main() {}"[..], 64);
+        let mut read = Vec::new();
+        let err = data.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(data.ended_early);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_destinations_stay_inside_the_context() {
+        let dir = tempdir().unwrap();
+        let dockerfile = NamedTempFile::new().unwrap();
+
+        for destination in ["../outside", "/etc/passwd", "", "a/../../b"] {
+            let mut context_builder =
+                ContextBuilder::from_path(dir.path(), dockerfile.path()).unwrap();
+            context_builder.with_file(destination, "", 0o644);
+
+            assert!(
+                matches!(
+                    context_builder.dry_run(),
+                    Err(ContextError::InvalidDestination(_))
+                ),
+                "{destination}"
+            );
+            assert!(context_builder.build_tar().await.is_err(), "{destination}");
+        }
     }
 }
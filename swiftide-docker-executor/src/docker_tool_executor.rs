@@ -1,14 +1,83 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{DockerExecutorError, RunningDockerExecutor};
+use crate::{
+    ArchiveMetadata, BuildContext, ContextBuilder, ContextEntry, ContextError, DockerExecutorError,
+    RunningDockerExecutor,
+    build_events::BuildEvent,
+    container_configurator::ResourceLimits,
+    context_builder::{ContextFile, ExtraPath},
+    dockerfile_mangler::build_context_dir,
+    image_builder::BuildOptions,
+};
+
+/// The published image with the service binaries for this version of the executor
+pub const SERVICE_IMAGE: &str = concat!(
+    "bosunai/swiftide-docker-service:",
+    env!("CARGO_PKG_VERSION")
+);
+
+/// The binaries that are copied into built images, from `/usr/bin` of the service image
+pub const SERVICE_BINARIES: [&str; 3] = ["swiftide-docker-service", "rg", "fd"];
+
+/// Where the service binaries in a built image come from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServiceSource {
+    /// An image with the binaries in `/usr/bin`, like the published service image or a mirror
+    Image(String),
+    /// A directory on the host with the binaries, which is used without pulling anything
+    Binaries(PathBuf),
+}
+
+impl Default for ServiceSource {
+    fn default() -> Self {
+        Self::Image(SERVICE_IMAGE.to_string())
+    }
+}
+
+/// The networks the container is connected to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// The network of the container the executor runs in if there is one, or the default
+    /// bridge with a published port otherwise
+    #[default]
+    Default,
+    /// No network at all. The service is reached over a unix socket in a temporary directory on
+    /// the host that is mounted into the container, that only the user of the executor can use.
+    ///
+    /// Not supported if the executor runs in a container or on Docker Desktop. The container must
+    /// run as root or as a numeric uid.
+    None,
+    /// A network without outside access that is created for the executor and removed when it is
+    /// dropped. If the executor runs in a container, that container joins the network as well.
+    ///
+    /// Otherwise the container is reached on its ip, which is not supported on Docker Desktop.
+    Internal,
+    /// An existing network by name, i.e. one shared with the services an agent may use
+    Named(String),
+}
+
+/// The Dockerfile to build the image from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DockerfileSource {
+    /// A file, relative to the context path unless absolute
+    Path(PathBuf),
+    /// The contents of a Dockerfile that is not on disk
+    Contents(String),
+}
 
 /// Build a docker image with bollard and start it up
 #[derive(Clone, Debug)]
 pub struct DockerExecutor {
     pub(crate) context_path: PathBuf,
     pub(crate) image_name: String,
-    pub(crate) dockerfile: Option<PathBuf>,
+    pub(crate) dockerfile: Option<DockerfileSource>,
     pub(crate) container_uuid: Uuid,
     pub(crate) user: Option<String>,
     pub(crate) env_clear: bool,
@@ -17,6 +86,23 @@ pub struct DockerExecutor {
     pub(crate) retain_on_drop: bool,
     pub(crate) default_timeout: Option<Duration>,
     pub(crate) workdir: PathBuf,
+    pub(crate) workspace_sync: bool,
+    pub(crate) orphan_sweep: bool,
+    pub(crate) docker_socket: bool,
+    pub(crate) build_options: BuildOptions,
+    pub(crate) archive_metadata: ArchiveMetadata,
+    pub(crate) extra_context_paths: Vec<ExtraPath>,
+    pub(crate) context_files: Vec<ContextFile>,
+    pub(crate) build_contexts: BTreeMap<String, BuildContext>,
+    pub(crate) service_source: ServiceSource,
+    pub(crate) build_events: Option<UnboundedSender<BuildEvent>>,
+    pub(crate) image_cache_dir: Option<PathBuf>,
+    pub(crate) build_timeout: Option<Duration>,
+    pub(crate) build_cancel_token: Option<CancellationToken>,
+    pub(crate) resource_limits: ResourceLimits,
+    pub(crate) network_mode: NetworkMode,
+    pub(crate) extra_hosts: Vec<String>,
+    pub(crate) dns: Vec<String>,
 }
 
 impl Default for DockerExecutor {
@@ -25,7 +111,7 @@ impl Default for DockerExecutor {
             container_uuid: Uuid::new_v4(),
             context_path: ".".into(),
             image_name: "docker-executor".into(),
-            dockerfile: Some("Dockerfile".into()),
+            dockerfile: Some(DockerfileSource::Path("Dockerfile".into())),
             user: None,
             env: HashMap::new(),
             env_clear: false,
@@ -33,6 +119,23 @@ impl Default for DockerExecutor {
             retain_on_drop: false,
             default_timeout: None,
             workdir: "/app".into(),
+            workspace_sync: false,
+            orphan_sweep: false,
+            docker_socket: false,
+            build_options: BuildOptions::default(),
+            archive_metadata: ArchiveMetadata::default(),
+            extra_context_paths: Vec::new(),
+            context_files: Vec::new(),
+            build_contexts: BTreeMap::new(),
+            service_source: ServiceSource::default(),
+            build_events: None,
+            image_cache_dir: None,
+            build_timeout: None,
+            build_cancel_token: None,
+            resource_limits: ResourceLimits::default(),
+            network_mode: NetworkMode::default(),
+            extra_hosts: Vec::new(),
+            dns: Vec::new(),
         }
     }
 }
@@ -101,6 +204,92 @@ impl DockerExecutor {
         self
     }
 
+    /// Limit the memory of the container in bytes, like `--memory`
+    ///
+    /// Commands that are killed because the container ran out of memory fail with
+    /// `DockerExecutorError::OutOfMemory`. To tell that docker killed them, the container is not
+    /// removed by docker as soon as it stops, only when the executor is dropped.
+    pub fn with_memory_limit(&mut self, bytes: i64) -> &mut Self {
+        self.resource_limits.memory = Some(bytes);
+
+        self
+    }
+
+    /// Limit memory plus swap of the container in bytes, like `--memory-swap`. Set it to the
+    /// memory limit to disable swap, or `-1` to allow unlimited swap.
+    pub fn with_memory_swap_limit(&mut self, bytes: i64) -> &mut Self {
+        self.resource_limits.memory_swap = Some(bytes);
+
+        self
+    }
+
+    /// Limit the container to this many CPUs, i.e. `1.5`, like `--cpus`
+    pub fn with_cpus(&mut self, cpus: f64) -> &mut Self {
+        self.resource_limits.cpus = Some(cpus);
+
+        self
+    }
+
+    /// Set the relative weight of the container for CPU time, like `--cpu-shares`
+    pub fn with_cpu_shares(&mut self, shares: i64) -> &mut Self {
+        self.resource_limits.cpu_shares = Some(shares);
+
+        self
+    }
+
+    /// Only run the container on these CPUs, i.e. `0-3` or `0,2`, like `--cpuset-cpus`
+    pub fn with_cpuset(&mut self, cpus: impl Into<String>) -> &mut Self {
+        self.resource_limits.cpuset = Some(cpus.into());
+
+        self
+    }
+
+    /// Limit the number of processes in the container, like `--pids-limit`
+    pub fn with_pids_limit(&mut self, pids: i64) -> &mut Self {
+        self.resource_limits.pids = Some(pids);
+
+        self
+    }
+
+    /// Set a ulimit in the container, i.e. `nofile`, like `--ulimit nofile=1024:4096`
+    pub fn with_ulimit(&mut self, name: impl Into<String>, soft: i64, hard: i64) -> &mut Self {
+        self.resource_limits
+            .ulimits
+            .insert(name.into(), (soft, hard));
+
+        self
+    }
+
+    /// Set the size of `/dev/shm` in the container in bytes, like `--shm-size`
+    pub fn with_shm_size(&mut self, bytes: i64) -> &mut Self {
+        self.resource_limits.shm_size = Some(bytes);
+
+        self
+    }
+
+    /// Set the networks of the container, i.e. to run untrusted code without internet access
+    pub fn with_network_mode(&mut self, mode: NetworkMode) -> &mut Self {
+        self.network_mode = mode;
+
+        self
+    }
+
+    /// Resolve a host name to an ip in the container, like `--add-host`. Use `host-gateway` as
+    /// the ip to resolve it to the host.
+    pub fn with_extra_host(&mut self, host: impl AsRef<str>, ip: impl AsRef<str>) -> &mut Self {
+        self.extra_hosts
+            .push(format!("{}:{}", host.as_ref(), ip.as_ref()));
+
+        self
+    }
+
+    /// Use this DNS server in the container instead of the one of the host, like `--dns`
+    pub fn with_dns_server(&mut self, ip: impl Into<String>) -> &mut Self {
+        self.dns.push(ip.into());
+
+        self
+    }
+
     /// Set the user (or user_id:group_id) to run the container as (default None, which means root)
     pub fn with_user(&mut self, user: impl Into<String>) -> &mut Self {
         self.user = Some(user.into());
@@ -135,10 +324,315 @@ impl DockerExecutor {
 
     /// Overwrite the dockerfile to use (default "Dockerfile")
     pub fn with_dockerfile(&mut self, path: impl Into<PathBuf>) -> &mut Self {
-        self.dockerfile = Some(path.into());
+        self.dockerfile = Some(DockerfileSource::Path(path.into()));
+        self
+    }
+
+    /// Build from the contents of a Dockerfile instead of a file, i.e. one that is generated.
+    /// The contents are only added to the build context, nothing is written to the context path.
+    pub fn with_dockerfile_contents(&mut self, contents: impl Into<String>) -> &mut Self {
+        self.dockerfile = Some(DockerfileSource::Contents(contents.into()));
+
         self
     }
 
+    /// Set which ownership and modification times of the context files are copied into the
+    /// image (default is normalized). Permission bits are always kept.
+    pub fn with_archive_metadata(&mut self, archive_metadata: ArchiveMetadata) -> &mut Self {
+        self.archive_metadata = archive_metadata;
+
+        self
+    }
+
+    /// Add a file or directory on the host to the build context at the destination, a path
+    /// relative to the context. Directories are added with their own ignore files applied.
+    pub fn with_extra_context_path(
+        &mut self,
+        host_path: impl Into<PathBuf>,
+        destination: impl Into<PathBuf>,
+    ) -> &mut Self {
+        self.extra_context_paths.push(ExtraPath {
+            host_path: host_path.into(),
+            destination: destination.into(),
+        });
+
+        self
+    }
+
+    /// Add a file with the given contents and permissions, i.e. `0o644`, to the build context at
+    /// the destination, a path relative to the context
+    pub fn with_context_file(
+        &mut self,
+        destination: impl Into<PathBuf>,
+        contents: impl Into<Vec<u8>>,
+        mode: u32,
+    ) -> &mut Self {
+        self.context_files.push(ContextFile {
+            destination: destination.into(),
+            contents: contents.into(),
+            mode,
+        });
+
+        self
+    }
+
+    /// Add a named build context, that the Dockerfile can use with `FROM <name>` and
+    /// `COPY --from=<name>`, like `docker build --build-context <name>=<source>`. The name must be
+    /// a valid stage name.
+    pub fn with_build_context(
+        &mut self,
+        name: impl Into<String>,
+        context: BuildContext,
+    ) -> &mut Self {
+        self.build_contexts.insert(name.into(), context);
+
+        self
+    }
+
+    /// Copy the service binaries from another image than the published one, i.e. from a registry
+    /// mirror. The image must have `swiftide-docker-service`, `rg` and `fd` in `/usr/bin`.
+    pub fn with_service_image(&mut self, image: impl Into<String>) -> &mut Self {
+        self.service_source = ServiceSource::Image(image.into());
+
+        self
+    }
+
+    /// Use the `swiftide-docker-service`, `rg` and `fd` binaries in a directory on the host,
+    /// i.e. a local build or binaries bundled with an application, so nothing has to be pulled
+    pub fn with_service_binaries(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.service_source = ServiceSource::Binaries(dir.into());
+
+        self
+    }
+
+    /// Send the progress of image builds to a channel, see [`BuildEvent`]
+    pub fn with_build_events(&mut self, sender: UnboundedSender<BuildEvent>) -> &mut Self {
+        self.build_events = Some(sender);
+
+        self
+    }
+
+    /// Load the image from a tarball in this directory instead of building it, and save built
+    /// images to it. Archives are named by the hash of the inputs of the image, so the
+    /// directory can be kept in an artifact cache of a CI without a registry.
+    ///
+    /// The cache is best effort: an archive that cannot be loaded is built instead, and failing
+    /// to save one is only logged. Images built with `with_no_cache` or `with_pull` replace the
+    /// archive. The small image with the service binaries, built when they come from the host,
+    /// is not cached.
+    pub fn with_image_cache_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.image_cache_dir = Some(dir.into());
+
+        self
+    }
+
+    /// Set a build argument for the image build, like `--build-arg`
+    pub fn with_build_arg(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> &mut Self {
+        self.build_options
+            .build_args
+            .insert(key.into(), value.into());
+
+        self
+    }
+
+    /// Set multiple build arguments for the image build
+    pub fn with_build_args(&mut self, args: impl Into<HashMap<String, String>>) -> &mut Self {
+        self.build_options.build_args.extend(args.into());
+
+        self
+    }
+
+    /// Build the given stage of a multi-stage Dockerfile, like `--target`. The service binaries
+    /// are copied into that stage.
+    pub fn with_build_target(&mut self, target: impl Into<String>) -> &mut Self {
+        self.build_options.target = Some(target.into());
+
+        self
+    }
+
+    /// Add a label to the built image
+    pub fn with_image_label(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> &mut Self {
+        self.build_options.labels.insert(key.into(), value.into());
+
+        self
+    }
+
+    /// Abort image builds that take longer than this, i.e. when a step hangs on the network.
+    /// The time is shared by all images `start` builds, which then fails with
+    /// `ImageBuildError::Timeout`.
+    pub fn with_build_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.build_timeout = Some(timeout);
+
+        self
+    }
+
+    /// Abort image builds when the token is cancelled. `start` then fails with
+    /// `ImageBuildError::Cancelled`.
+    pub fn with_build_cancel_token(&mut self, cancel_token: CancellationToken) -> &mut Self {
+        self.build_cancel_token = Some(cancel_token);
+
+        self
+    }
+
+    /// Build without using the docker build cache, like `--no-cache`. Default is false.
+    ///
+    /// This also rebuilds images that exist already for an unchanged context.
+    pub fn with_no_cache(&mut self, no_cache: bool) -> &mut Self {
+        self.build_options.no_cache = no_cache;
+
+        self
+    }
+
+    /// Always pull newer versions of base images, like `--pull`. Default is false.
+    ///
+    /// This also rebuilds images that exist already for an unchanged context.
+    pub fn with_pull(&mut self, pull: bool) -> &mut Self {
+        self.build_options.pull = pull;
+
+        self
+    }
+
+    /// Set the size of `/dev/shm` during the build in bytes, like `--shm-size`. Docker takes at
+    /// most `i32::MAX` bytes unless the build runs over a buildkit session, larger sizes fail the
+    /// build with `ImageBuildError::InvalidShmSize`.
+    pub fn with_build_shm_size(&mut self, bytes: i64) -> &mut Self {
+        self.build_options.shm_size = Some(bytes);
+
+        self
+    }
+
+    /// Makes the contents of a file available to `RUN --mount=type=secret,id=<id>`
+    ///
+    /// Secrets are not stored in the image, and do not change its tag.
+    #[cfg(feature = "buildkit")]
+    pub fn with_build_secret_file(
+        &mut self,
+        id: impl Into<String>,
+        path: impl Into<PathBuf>,
+    ) -> &mut Self {
+        self.build_options.secrets.insert(
+            id.into(),
+            bollard::grpc::build::SecretSource::File(path.into()),
+        );
+
+        self
+    }
+
+    /// Makes the value of an environment variable available to
+    /// `RUN --mount=type=secret,id=<id>`
+    #[cfg(feature = "buildkit")]
+    pub fn with_build_secret_env(
+        &mut self,
+        id: impl Into<String>,
+        env_var: impl Into<String>,
+    ) -> &mut Self {
+        self.build_options.secrets.insert(
+            id.into(),
+            bollard::grpc::build::SecretSource::Env(env_var.into()),
+        );
+
+        self
+    }
+
+    /// Forwards the ssh agent at `SSH_AUTH_SOCK` to `RUN --mount=type=ssh`
+    #[cfg(feature = "buildkit")]
+    pub fn with_ssh_forwarding(&mut self, enabled: bool) -> &mut Self {
+        self.build_options.ssh = enabled;
+
+        self
+    }
+
+    /// Snapshot the workdir of the container when it starts, so changes can later be synced
+    /// between the host and the container without mistaking host edits for container edits.
+    /// Default is false.
+    ///
+    /// See `RunningDockerExecutor::as_workspace_sync`.
+    pub fn with_workspace_sync(&mut self, enabled: bool) -> &mut Self {
+        self.workspace_sync = enabled;
+
+        self
+    }
+
+    /// Before starting, remove containers left behind by executors of processes on this host
+    /// that have exited, i.e. after a crash. Default is false.
+    ///
+    /// See `cleanup::Cleanup` for cleaning up on demand.
+    pub fn with_orphan_sweep(&mut self, enabled: bool) -> &mut Self {
+        self.orphan_sweep = enabled;
+
+        self
+    }
+
+    /// Mount the docker socket of the host at `/var/run/docker.sock` in the container, so
+    /// commands can run docker themselves. Default is false.
+    ///
+    /// Access to the socket is access to the host, so it is never mounted with
+    /// `NetworkMode::None` or `NetworkMode::Internal`.
+    pub fn with_docker_socket(&mut self, enabled: bool) -> &mut Self {
+        self.docker_socket = enabled;
+
+        self
+    }
+
+    /// Lists what would be sent to docker as the build context, without building anything
+    ///
+    /// The Dockerfile is listed as is, not as the executor prepares it. Without a Dockerfile
+    /// nothing is sent.
+    pub fn dry_run_context(&self) -> Result<Vec<ContextEntry>, DockerExecutorError> {
+        let Some(dockerfile) = &self.dockerfile else {
+            return Ok(Vec::new());
+        };
+
+        // Contents are listed from a temporary file, like they are built
+        let mut tmp_dockerfile = None;
+        let dockerfile_path = match dockerfile {
+            DockerfileSource::Path(path) => self.context_path.join(path),
+            DockerfileSource::Contents(contents) => {
+                let file = tempfile::NamedTempFile::new().map_err(ContextError::Io)?;
+                fs_err::write(file.path(), contents).map_err(ContextError::Io)?;
+                tmp_dockerfile.insert(file).path().to_path_buf()
+            }
+        };
+
+        let mut context_builder = self.context_builder(&dockerfile_path)?;
+        if let DockerfileSource::Contents(_) = dockerfile {
+            context_builder.with_dockerfile_name("Dockerfile");
+        }
+
+        Ok(context_builder.dry_run()?)
+    }
+
+    /// A context builder for the context path and the extra paths and files
+    pub(crate) fn context_builder(
+        &self,
+        dockerfile: &Path,
+    ) -> Result<ContextBuilder, ContextError> {
+        let mut context_builder = ContextBuilder::from_path(&self.context_path, dockerfile)?;
+        context_builder.with_archive_metadata(self.archive_metadata);
+
+        for extra in &self.extra_context_paths {
+            context_builder.with_extra_path(&extra.host_path, &extra.destination);
+        }
+        for file in &self.context_files {
+            context_builder.with_file(&file.destination, file.contents.clone(), file.mode);
+        }
+        for (name, context) in &self.build_contexts {
+            if let BuildContext::Path(path) = context {
+                context_builder.with_extra_path(path, build_context_dir(name));
+            }
+        }
+
+        Ok(context_builder)
+    }
+
     /// Starts the docker executor
     ///
     /// Note that on dropping the `RunningDockerExecutor`, the container will be stopped
@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::Path;
 
-use crate::DockerfileError;
-use crate::dockerfile_mangler::mangle;
+use crate::dockerfile_mangler::{LineMap, mangle, mangle_contents};
+use crate::image_builder::BuildOptions;
+use crate::{BuildContext, DockerfileError, DockerfileSource};
 
 pub struct DockerfileManager {
     context_path: std::path::PathBuf,
@@ -15,17 +17,41 @@ impl DockerfileManager {
         }
     }
 
+    /// Writes the mangled Dockerfile to a temporary file, and returns it with the lines of its
+    /// instructions in the original
     pub async fn prepare_dockerfile(
         &self,
-        dockerfile: &Path,
-    ) -> Result<tempfile::NamedTempFile, DockerfileError> {
-        let valid_dockerfile_path = if dockerfile.is_relative() {
-            self.context_path.join(dockerfile)
-        } else {
-            dockerfile.to_path_buf()
-        };
+        dockerfile: &DockerfileSource,
+        options: &BuildOptions,
+        build_contexts: &BTreeMap<String, BuildContext>,
+        service_image: &str,
+        static_service: bool,
+    ) -> Result<(tempfile::NamedTempFile, LineMap), DockerfileError> {
+        let mangled_dockerfile = match dockerfile {
+            DockerfileSource::Path(path) => {
+                let valid_dockerfile_path = if path.is_relative() {
+                    self.context_path.join(path)
+                } else {
+                    path.clone()
+                };
 
-        let mangled_dockerfile = mangle(&valid_dockerfile_path).await?;
+                mangle(
+                    &valid_dockerfile_path,
+                    options,
+                    build_contexts,
+                    service_image,
+                    static_service,
+                )
+                .await?
+            }
+            DockerfileSource::Contents(contents) => mangle_contents(
+                contents,
+                options,
+                build_contexts,
+                service_image,
+                static_service,
+            )?,
+        };
 
         let mut tmp_dockerfile =
             tempfile::NamedTempFile::new().map_err(DockerfileError::TempFileError)?;
@@ -43,6 +69,6 @@ impl DockerfileManager {
             tmp_dockerfile.path().display()
         );
 
-        Ok(tmp_dockerfile)
+        Ok((tmp_dockerfile, mangled_dockerfile.line_map))
     }
 }
@@ -1,65 +1,273 @@
 /// Adds copy statements to the Dockerfile to copy the built binary into the image.
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path};
 
 use tokio::fs::read_to_string;
 
-use crate::MangleError;
+use crate::build_events::StepPosition;
+use crate::dockerfile_parser::{Dockerfile, Instruction, Item, Stage};
+use crate::image_builder::BuildOptions;
+use crate::{BUILD_CONTEXTS_DIR, BuildContext, MangleError, SERVICE_BINARIES};
 
 pub struct MangledDockerfile {
     pub content: String,
+    pub line_map: LineMap,
 }
 
-pub async fn mangle(path: &Path) -> Result<MangledDockerfile, MangleError> {
+/// Maps the instructions docker reports for a mangled Dockerfile back to the lines of the
+/// original
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineMap {
+    instructions: Vec<MappedInstruction>,
+    /// The names of the stages, in order
+    stages: Vec<Option<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MappedInstruction {
+    /// The keyword and arguments, with whitespace collapsed
+    text: String,
+    /// The line in the original Dockerfile, `None` if it was added
+    original_line: Option<usize>,
+    /// The index of the stage, `None` for arguments before the first `FROM`
+    stage: Option<usize>,
+    /// The instruction in the stage, counting from 1 for its `FROM`
+    instruction_in_stage: usize,
+}
+
+impl LineMap {
+    fn new(dockerfile: &Dockerfile) -> Self {
+        let stages = dockerfile.stages();
+        let mut stage = None;
+        let mut instruction_in_stage = 0;
+        let instructions = dockerfile
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| match item {
+                Item::Instruction(instruction) => {
+                    if let Some(position) = stages.iter().position(|stage| stage.index == index) {
+                        stage = Some(position);
+                        instruction_in_stage = 0;
+                    }
+                    instruction_in_stage += 1;
+
+                    Some(MappedInstruction {
+                        text: normalize(&format!(
+                            "{} {}",
+                            instruction.keyword, instruction.arguments
+                        )),
+                        original_line: instruction.line,
+                        stage,
+                        instruction_in_stage,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            instructions,
+            stages: stages.into_iter().map(|stage| stage.name).collect(),
+        }
+    }
+
+    /// The original line of an instruction as docker reports it, i.e. `RUN make`, at the
+    /// position the builder reported if known. Without a position, or if the instruction is not
+    /// at it, the instruction is only found if it occurs once.
+    pub(crate) fn find(&self, instruction: &str, position: Option<&StepPosition>) -> Option<usize> {
+        let text = normalize(instruction);
+        let matches = |mapped: &&MappedInstruction| same_instruction(&mapped.text, &text);
+
+        if let Some(mapped) = position
+            .and_then(|position| self.at(position))
+            .filter(matches)
+        {
+            return mapped.original_line;
+        }
+
+        let mut candidates = self.instructions.iter().filter(matches);
+        let found = candidates.next()?;
+        if candidates.next().is_some() {
+            return None;
+        }
+        found.original_line
+    }
+
+    /// The instruction at a position reported by the builder
+    fn at(&self, position: &StepPosition) -> Option<&MappedInstruction> {
+        match position {
+            StepPosition::Instruction(number) => self.instructions.get(number.checked_sub(1)?),
+            StepPosition::Stage { stage, instruction } => {
+                let stage = match stage {
+                    Some(stage) => self.stage_index(stage)?,
+                    None if self.stages.len() == 1 => 0,
+                    None => return None,
+                };
+                self.instructions.iter().find(|mapped| {
+                    mapped.stage == Some(stage) && mapped.instruction_in_stage == *instruction
+                })
+            }
+        }
+    }
+
+    /// The index of a stage by its name, or by `stage-<index>` if it has none
+    fn stage_index(&self, stage: &str) -> Option<usize> {
+        self.stages
+            .iter()
+            .enumerate()
+            .position(|(index, name)| match name {
+                Some(name) => name.eq_ignore_ascii_case(stage),
+                None => stage == format!("stage-{index}"),
+            })
+    }
+}
+
+/// Whether two normalized instructions are the same, keywords are case insensitive
+fn same_instruction(instruction: &str, other: &str) -> bool {
+    let (keyword, arguments) = instruction.split_once(' ').unwrap_or((instruction, ""));
+    let (other_keyword, other_arguments) = other.split_once(' ').unwrap_or((other, ""));
+
+    keyword.eq_ignore_ascii_case(other_keyword) && arguments == other_arguments
+}
+
+/// Collapses whitespace, as docker does in the steps it reports
+fn normalize(instruction: &str) -> String {
+    instruction.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The binaries are copied from the service image into the target stage if given, or the last
+/// stage otherwise. Unless the binaries are static, the libraries they need are installed for
+/// the base image of that stage.
+///
+/// Named build contexts are added as stages before the first stage, so that `FROM <name>` and
+/// `COPY --from=<name>` refer to them. Images are used as they are, directories are copied from
+/// the build context into an empty stage. References to stages by their index are moved along.
+pub async fn mangle(
+    path: &Path,
+    options: &BuildOptions,
+    build_contexts: &BTreeMap<String, BuildContext>,
+    service_image: &str,
+    static_service: bool,
+) -> Result<MangledDockerfile, MangleError> {
     tracing::warn!("Mangling Dockerfile at {:?}", path);
 
-    let this_crate = env!("CARGO_PKG_VERSION");
-    let mut content = read_to_string(path)
+    let content = read_to_string(path)
         .await
         .map_err(MangleError::DockerfileReadError)?;
 
-    let image_name = format!("bosunai/swiftide-docker-service:{this_crate}");
+    mangle_contents(
+        &content,
+        options,
+        build_contexts,
+        service_image,
+        static_service,
+    )
+}
+
+/// Mangles the contents of a Dockerfile, see `mangle`
+pub fn mangle_contents(
+    content: &str,
+    options: &BuildOptions,
+    build_contexts: &BTreeMap<String, BuildContext>,
+    service_image: &str,
+    static_service: bool,
+) -> Result<MangledDockerfile, MangleError> {
+    let mut dockerfile = Dockerfile::parse(content)?;
+
     // Remove existing CMD or ENTRYPOINT instructions
-    content = content
-        .lines()
-        .filter(|line| {
-            !line.trim_start().to_lowercase().starts_with("cmd")
-                && !line.trim_start().to_lowercase().starts_with("entrypoint")
-        })
-        .collect::<Vec<&str>>()
-        .join("\n");
+    dockerfile.items.retain(|item| {
+        !matches!(item, Item::Instruction(instruction)
+            if instruction.is("CMD") || instruction.is("ENTRYPOINT"))
+    });
 
-    // Find the position to insert the copy lines
-    let mut lines = content.lines().collect::<Vec<_>>();
-    let insert_pos = lines
-        .iter()
-        .enumerate()
-        .rfind(|(_, line)| line.trim_start().to_lowercase().starts_with("from"))
-        .map(|(idx, _)| idx)
-        .ok_or(MangleError::InvalidDockerfile)?
-        + 1;
+    // Find the stage to copy the binaries into
+    let stage = match options.target.as_deref() {
+        Some(target) => dockerfile
+            .stage(target)
+            .ok_or_else(|| MangleError::UnknownTarget(target.to_string()))?,
+        None => dockerfile
+            .stages()
+            .pop()
+            .ok_or(MangleError::InvalidDockerfile)?,
+    };
+    let base = resolve_base(&dockerfile, &stage, &options.build_args);
 
     // Copy swiftide-docker-service, rg, and fd into the image
-    let copy_lines = ["swiftide-docker-service", "rg", "fd"]
+    let mut instructions = SERVICE_BINARIES
         .iter()
-        .map(|binary| format!("COPY --from={image_name} /usr/bin/{binary} /usr/bin/{binary}"))
-        .collect::<Vec<String>>()
-        .join("\n");
+        .map(|binary| {
+            Item::Instruction(Instruction::new(
+                "COPY",
+                format!("--from={service_image} /usr/bin/{binary} /usr/bin/{binary}"),
+            ))
+        })
+        .collect::<Vec<_>>();
 
-    lines.insert(insert_pos, &copy_lines);
+    // Static binaries run anywhere, others need glibc and libgcc
+    if !static_service {
+        match BaseImage::detect(&base) {
+            BaseImage::Alpine => instructions.push(Item::Instruction(Instruction::new(
+                "RUN",
+                "apk add --no-cache gcompat libgcc pcre2 ripgrep fd",
+            ))),
+            BaseImage::Wolfi => instructions.push(Item::Instruction(Instruction::new(
+                "RUN",
+                "apk add --no-cache libgcc",
+            ))),
+            BaseImage::Glibc => {}
+            BaseImage::Minimal => tracing::warn!(
+                base,
+                "The service binaries are not static and might not run on this base image"
+            ),
+        }
+    }
 
-    // If the last FROM line is alpine, add gcompat and libgcc
-    if let Some(last_from) = lines
-        .iter()
-        .rfind(|line| line.trim_start().to_lowercase().starts_with("from"))
-        && last_from.to_lowercase().contains("alpine")
-    {
-        lines.insert(
-            insert_pos.saturating_add(1),
-            "RUN apk add --no-cache gcompat libgcc pcre2 ripgrep fd",
-        );
+    let insert_pos = stage.index + 1;
+    dockerfile
+        .items
+        .splice(insert_pos..insert_pos, instructions);
+
+    // Stages are only ever inserted before the first stage, so earlier positions stay valid
+    let mut context_stages = Vec::new();
+    for (name, context) in build_contexts {
+        if !is_valid_stage_name(name) {
+            return Err(MangleError::InvalidContextName(name.clone()));
+        }
+        if dockerfile.stage(name).is_some() {
+            return Err(MangleError::DuplicateStage(name.clone()));
+        }
+
+        match context {
+            BuildContext::Image(image) => context_stages.push(Item::Instruction(Instruction::new(
+                "FROM",
+                format!("{image} AS {name}"),
+            ))),
+            BuildContext::Path(_) => {
+                context_stages.push(Item::Instruction(Instruction::new(
+                    "FROM",
+                    format!("scratch AS {name}"),
+                )));
+                context_stages.push(Item::Instruction(Instruction::new(
+                    "COPY",
+                    format!("{}/ /", build_context_dir(name)),
+                )));
+            }
+        }
     }
+    if !build_contexts.is_empty() {
+        for item in &mut dockerfile.items {
+            if let Item::Instruction(instruction) = item {
+                shift_stage_indices(instruction, build_contexts.len());
+            }
+        }
 
-    let new_dockerfile = lines.join("\n");
+        let first_from = dockerfile.stages()[0].index;
+        dockerfile
+            .items
+            .splice(first_from..first_from, context_stages);
+    }
+
+    let new_dockerfile = dockerfile.to_string();
     tracing::debug!(
         original = content,
         mangled = new_dockerfile,
@@ -67,12 +275,235 @@ pub async fn mangle(path: &Path) -> Result<MangledDockerfile, MangleError> {
     );
     Ok(MangledDockerfile {
         content: new_dockerfile,
+        line_map: LineMap::new(&dockerfile),
     })
 }
 
+/// Where a directory used as named build context is put in the build context
+pub(crate) fn build_context_dir(name: &str) -> String {
+    format!("{BUILD_CONTEXTS_DIR}/{}", name.to_lowercase())
+}
+
+/// Stage names are a letter followed by letters, digits, `-`, `_` and `.`, case insensitive
+fn is_valid_stage_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Moves references to stages by index in `COPY --from=<index>` and
+/// `RUN --mount=from=<index>` along with the stages inserted before them
+fn shift_stage_indices(instruction: &mut Instruction, shift: usize) {
+    let shifted = |reference: &str| {
+        reference
+            .parse::<usize>()
+            .ok()
+            .map(|index| (index + shift).to_string())
+    };
+
+    let flags = instruction
+        .arguments
+        .split_whitespace()
+        .take_while(|word| word.starts_with("--"))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    for flag in flags {
+        let new_flag = if instruction.is("COPY") {
+            flag.strip_prefix("--from=")
+                .and_then(shifted)
+                .map(|index| format!("--from={index}"))
+        } else if instruction.is("RUN") {
+            flag.strip_prefix("--mount=").and_then(|mount| {
+                let mut changed = false;
+                let options = mount
+                    .split(',')
+                    .map(
+                        |option| match option.strip_prefix("from=").and_then(shifted) {
+                            Some(index) => {
+                                changed = true;
+                                format!("from={index}")
+                            }
+                            None => option.to_string(),
+                        },
+                    )
+                    .collect::<Vec<_>>();
+
+                changed.then(|| format!("--mount={}", options.join(",")))
+            })
+        } else {
+            None
+        };
+
+        // Flags come before anything else, so their first occurrence is the flag itself
+        if let Some(new_flag) = new_flag {
+            instruction.arguments = instruction.arguments.replacen(&flag, &new_flag, 1);
+            instruction.raw = instruction.raw.replacen(&flag, &new_flag, 1);
+        }
+    }
+}
+
+/// What a base image needs to run binaries that are linked against glibc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BaseImage {
+    /// Based on musl, needs gcompat
+    Alpine,
+    /// Based on glibc, without libgcc
+    Wolfi,
+    /// Has glibc and libgcc, like debian or ubuntu
+    Glibc,
+    /// Has nothing to install packages with, like scratch, busybox or distroless
+    Minimal,
+}
+
+impl BaseImage {
+    fn detect(image: &str) -> Self {
+        let image = image.to_lowercase();
+        let image = image.split('@').next().unwrap_or_default();
+        let (repository, tag) = match image.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (image, ""),
+        };
+        let name = repository.rsplit('/').next().unwrap_or(repository);
+
+        if repository.contains("wolfi") {
+            Self::Wolfi
+        } else if repository == "scratch"
+            || name == "busybox"
+            || repository.contains("distroless")
+            || repository.starts_with("cgr.dev/chainguard/")
+        {
+            Self::Minimal
+        } else if name == "alpine" || tag.contains("alpine") {
+            Self::Alpine
+        } else {
+            Self::Glibc
+        }
+    }
+}
+
+/// The image a stage is based on, following stages that are based on earlier stages and
+/// substituting the arguments declared before the first stage
+fn resolve_base(
+    dockerfile: &Dockerfile,
+    stage: &Stage,
+    build_args: &BTreeMap<String, String>,
+) -> String {
+    let stages = dockerfile.stages();
+
+    // Arguments before the first stage can be used in FROM, the build args override them
+    let mut args = BTreeMap::new();
+    for item in &dockerfile.items[..stages.first().map_or(0, |stage| stage.index)] {
+        if let Item::Instruction(instruction) = item
+            && instruction.is("ARG")
+        {
+            // Arguments without a default stay unset
+            for arg in instruction.arguments.split_whitespace() {
+                if let Some((name, default)) = arg.split_once('=') {
+                    args.insert(name.to_string(), default.trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    args.extend(build_args.clone());
+
+    let mut stage = stage;
+    for _ in 0..stages.len() {
+        match stages
+            .iter()
+            .take_while(|earlier| earlier.index < stage.index)
+            .find(|earlier| {
+                earlier
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(&stage.base))
+            }) {
+            Some(earlier) => stage = earlier,
+            None => break,
+        }
+    }
+
+    substitute_args(&stage.base, &args)
+}
+
+/// Substitutes `$NAME`, `${NAME}`, `${NAME:-default}`, `${NAME-default}`, `${NAME:+alternative}`
+/// and `${NAME+alternative}` like docker does in `FROM`. Unset arguments are empty.
+fn substitute_args(word: &str, args: &BTreeMap<String, String>) -> String {
+    let is_name_char = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    let mut result = String::new();
+    let mut chars = word.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => result.push(chars.next().unwrap_or('$')),
+            '$' if chars.next_if_eq(&'{').is_some() => {
+                let mut expression = String::new();
+                let mut depth = 0;
+                for c in chars.by_ref() {
+                    match c {
+                        '{' => depth += 1,
+                        '}' if depth == 0 => break,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    expression.push(c);
+                }
+
+                let name_len = expression
+                    .chars()
+                    .take_while(is_name_char)
+                    .map(char::len_utf8)
+                    .sum();
+                let (name, modifier) = expression.split_at(name_len);
+                let value = args.get(name).map(String::as_str);
+                let is_set = value.is_some_and(|value| !value.is_empty());
+
+                let substituted = if let Some(default) = modifier.strip_prefix(":-") {
+                    if is_set {
+                        value.unwrap_or_default().to_string()
+                    } else {
+                        substitute_args(default, args)
+                    }
+                } else if let Some(default) = modifier.strip_prefix('-') {
+                    value.map_or_else(|| substitute_args(default, args), str::to_string)
+                } else if let Some(alternative) = modifier.strip_prefix(":+") {
+                    if is_set {
+                        substitute_args(alternative, args)
+                    } else {
+                        String::new()
+                    }
+                } else if let Some(alternative) = modifier.strip_prefix('+') {
+                    if value.is_some() {
+                        substitute_args(alternative, args)
+                    } else {
+                        String::new()
+                    }
+                } else {
+                    value.unwrap_or_default().to_string()
+                };
+                result.push_str(&substituted);
+            }
+            '$' if chars.peek().is_some_and(is_name_char) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(is_name_char) {
+                    name.push(c);
+                }
+                result.push_str(args.get(&name).map_or("", String::as_str));
+            }
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::SERVICE_IMAGE;
     use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
@@ -88,6 +519,13 @@ mod tests {
         }}
     }
 
+    fn target(target: &str) -> BuildOptions {
+        BuildOptions {
+            target: Some(target.to_string()),
+            ..BuildOptions::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_mangle() {
         let dir = tempdir().unwrap();
@@ -95,7 +533,15 @@ mod tests {
         let mut file = File::create(&file_path).unwrap();
         writeln!(file, "FROM alpine").unwrap();
 
-        let result = mangle(&file_path).await.unwrap();
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
 
         assert!(
             result
@@ -121,7 +567,15 @@ mod tests {
         )
         .unwrap();
 
-        let result = mangle(&file_path).await.unwrap();
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
 
         assert!(
             !result.content.contains("CMD [\"echo\", \"Hello World\"]"),
@@ -143,7 +597,15 @@ mod tests {
         )
         .unwrap();
 
-        let result = mangle(&file_path).await.unwrap();
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
 
         assert!(!result.content.contains("CMD [\"echo\", \"Hello World\"]"));
         assert!(!result.content.contains("ENTRYPOINT [\"/bin/sh\"]"));
@@ -161,8 +623,487 @@ mod tests {
         )
         .unwrap();
 
-        let result = mangle(&file_path).await.unwrap();
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
 
         assert_snapshot!(result.content)
     }
+
+    #[tokio::test]
+    async fn test_mangle_for_target() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("Dockerfile");
+        let mut file = File::create(&file_path).unwrap();
+        writeln!(
+            file,
+            "FROM alpine AS dev\nRUN echo dev\nFROM ubuntu AS release\nRUN echo release"
+        )
+        .unwrap();
+
+        let result = mangle(
+            &file_path,
+            &target("dev"),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
+        let lines = result.content.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "FROM alpine AS dev");
+        assert!(lines[1].contains("/usr/bin/swiftide-docker-service"));
+        assert!(lines[4].starts_with("RUN apk add"));
+        assert_eq!(lines[5], "RUN echo dev");
+
+        let result = mangle(
+            &file_path,
+            &target("missing"),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await;
+        assert!(matches!(result, Err(MangleError::UnknownTarget(_))));
+    }
+
+    #[tokio::test]
+    async fn test_mangle_with_build_contexts() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("Dockerfile");
+        let mut file = File::create(&file_path).unwrap();
+        writeln!(
+            file,
+            "# syntax=docker/dockerfile:1\nARG VERSION=1\nFROM ubuntu\nCOPY --from=fixtures / /fixtures"
+        )
+        .unwrap();
+
+        let build_contexts = BTreeMap::from([
+            (
+                "Fixtures".to_string(),
+                BuildContext::Path("../fixtures".into()),
+            ),
+            (
+                "base".to_string(),
+                BuildContext::Image("alpine:3.20".into()),
+            ),
+        ]);
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &build_contexts,
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
+        let lines = result.content.lines().collect::<Vec<_>>();
+
+        assert_eq!(
+            lines[..6],
+            [
+                "# syntax=docker/dockerfile:1",
+                "ARG VERSION=1",
+                "FROM scratch AS Fixtures",
+                "COPY .swiftide-build-contexts/fixtures/ /",
+                "FROM alpine:3.20 AS base",
+                "FROM ubuntu",
+            ]
+        );
+        assert!(lines[6].contains("/usr/bin/swiftide-docker-service"));
+
+        let build_contexts =
+            BTreeMap::from([("base".to_string(), BuildContext::Image("alpine".into()))]);
+        writeln!(file, "FROM ubuntu AS base").unwrap();
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &build_contexts,
+            SERVICE_IMAGE,
+            false,
+        )
+        .await;
+        assert!(matches!(result, Err(MangleError::DuplicateStage(_))));
+
+        for name in ["1st", "with/slash", "with space", ""] {
+            let build_contexts =
+                BTreeMap::from([(name.to_string(), BuildContext::Image("alpine".into()))]);
+            let result = mangle(
+                &file_path,
+                &BuildOptions::default(),
+                &build_contexts,
+                SERVICE_IMAGE,
+                false,
+            )
+            .await;
+            assert!(
+                matches!(result, Err(MangleError::InvalidContextName(_))),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_contexts_keep_stage_indices() {
+        let content = indoc::indoc! {r#"
+            FROM rust AS builder
+            RUN cargo build
+
+            FROM ubuntu
+            COPY --from=0 /app/target /app
+            COPY --chown=1:1 --from=builder /app/README.md /app/
+            RUN --mount=type=bind,from=0,target=/build cp /build/out /out
+            RUN echo --from=0
+        "#};
+        let build_contexts =
+            BTreeMap::from([("assets".to_string(), BuildContext::Image("alpine".into()))]);
+
+        let result = mangle_contents(
+            content,
+            &BuildOptions::default(),
+            &build_contexts,
+            SERVICE_IMAGE,
+            false,
+        )
+        .unwrap();
+        let lines = result.content.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "FROM alpine AS assets");
+        assert_eq!(lines[1], "FROM rust AS builder");
+        assert!(
+            result
+                .content
+                .contains("\nCOPY --from=1 /app/target /app\n")
+        );
+        assert!(
+            result
+                .content
+                .contains("\nCOPY --chown=1:1 --from=builder /app/README.md /app/\n")
+        );
+        assert!(
+            result
+                .content
+                .contains("\nRUN --mount=type=bind,from=1,target=/build cp /build/out /out\n")
+        );
+        assert!(result.content.contains("\nRUN echo --from=0"));
+    }
+
+    #[tokio::test]
+    async fn test_mangle_keeps_continuations_and_heredocs() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("Dockerfile");
+        std::fs::write(
+            &file_path,
+            indoc::indoc! {r#"
+                # syntax=docker/dockerfile:1
+                FROM rust:1.89-alpine AS builder
+                RUN apk add \
+                    # cmdtest is a package, not an instruction
+                    cmdtest \
+                    fromager
+                ENTRYPOINT ["cargo", \
+                    "run"]
+
+                FROM debian:bookworm-slim
+                COPY --from=builder /app /app
+                RUN <<EOF
+                cmd=$(cat /app/cmd)
+                from=$(cat /app/from)
+                EOF
+                CMD ["/app/run"]
+            "#},
+        )
+        .unwrap();
+
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(result.content.contains("    cmdtest \\\n    fromager"));
+        assert!(result.content.contains("cmd=$(cat /app/cmd)"));
+        assert!(!result.content.contains("\"run\"]"));
+        assert_snapshot!(result.content);
+
+        let result = mangle(
+            &file_path,
+            &target("builder"),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_snapshot!(result.content);
+    }
+
+    #[test]
+    fn test_line_map() {
+        let content = indoc::indoc! {r#"
+            # syntax=docker/dockerfile:1
+            FROM rust AS builder
+            RUN cargo build \
+                --release
+
+            FROM ubuntu
+            RUN make
+            CMD ["/app/run"]
+        "#};
+        let build_contexts =
+            BTreeMap::from([("assets".to_string(), BuildContext::Image("alpine".into()))]);
+
+        let result = mangle_contents(
+            content,
+            &BuildOptions::default(),
+            &build_contexts,
+            SERVICE_IMAGE,
+            false,
+        )
+        .unwrap();
+
+        let line_map = result.line_map;
+        assert_eq!(line_map.find("RUN make", None), Some(7));
+        assert_eq!(line_map.find("run   cargo build --release", None), Some(3));
+        assert_eq!(line_map.find("FROM ubuntu", None), Some(6));
+        // Added by the executor, or not in the Dockerfile at all
+        assert_eq!(line_map.find("FROM alpine AS assets", None), None);
+        assert_eq!(
+            line_map.find(
+                &format!("COPY --from={SERVICE_IMAGE} /usr/bin/rg /usr/bin/rg"),
+                None
+            ),
+            None
+        );
+        assert_eq!(line_map.find("RUN make install", None), None);
+    }
+
+    #[test]
+    fn test_line_map_duplicate_instructions() {
+        let content = indoc::indoc! {r#"
+            ARG VERSION=1
+            FROM alpine
+            RUN make
+
+            FROM ubuntu
+            RUN make
+            RUN
+        "#};
+        let mangle = |build_contexts: &BTreeMap<String, BuildContext>| {
+            mangle_contents(
+                content,
+                &BuildOptions::default(),
+                build_contexts,
+                SERVICE_IMAGE,
+                true,
+            )
+            .unwrap()
+            .line_map
+        };
+        let stage = |stage: Option<&str>, instruction| StepPosition::Stage {
+            stage: stage.map(str::to_string),
+            instruction,
+        };
+
+        // The binaries are copied right after `FROM ubuntu`
+        let line_map = mangle(&BTreeMap::new());
+        // Arguments before the first stage are counted by the classic builder
+        assert_eq!(
+            line_map.find("RUN make", Some(&StepPosition::Instruction(3))),
+            Some(3)
+        );
+        assert_eq!(
+            line_map.find("RUN make", Some(&StepPosition::Instruction(8))),
+            Some(6)
+        );
+        assert_eq!(
+            line_map.find("RUN make", Some(&stage(Some("stage-0"), 2))),
+            Some(3)
+        );
+        assert_eq!(
+            line_map.find("RUN make", Some(&stage(Some("stage-1"), 5))),
+            Some(6)
+        );
+        // Ambiguous without a position, or with one that is not the instruction
+        assert_eq!(line_map.find("RUN make", None), None);
+        assert_eq!(
+            line_map.find("RUN make", Some(&StepPosition::Instruction(4))),
+            None
+        );
+        assert_eq!(line_map.find("RUN make", Some(&stage(None, 2))), None);
+        // Instructions without arguments
+        assert_eq!(line_map.find("RUN", None), Some(7));
+        assert_eq!(
+            line_map.find("RUN", Some(&StepPosition::Instruction(9))),
+            Some(7)
+        );
+
+        // Build contexts are stages before those of the Dockerfile
+        let build_contexts =
+            BTreeMap::from([("assets".to_string(), BuildContext::Image("alpine".into()))]);
+        let line_map = mangle(&build_contexts);
+        assert_eq!(
+            line_map.find("RUN make", Some(&stage(Some("stage-2"), 5))),
+            Some(6)
+        );
+        assert_eq!(
+            line_map.find("RUN make", Some(&StepPosition::Instruction(9))),
+            Some(6)
+        );
+
+        // With one stage, BuildKit leaves out its name
+        let line_map = mangle_contents(
+            "FROM alpine\nRUN make\nRUN make\n",
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            SERVICE_IMAGE,
+            true,
+        )
+        .unwrap()
+        .line_map;
+        assert_eq!(line_map.find("RUN make", Some(&stage(None, 6))), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_mangle_with_service_image() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("Dockerfile");
+        std::fs::write(&file_path, "FROM ubuntu").unwrap();
+
+        let result = mangle(
+            &file_path,
+            &BuildOptions::default(),
+            &BTreeMap::new(),
+            "mirror.local/swiftide-docker-service:1",
+            true,
+        )
+        .await
+        .unwrap();
+
+        assert!(!result.content.contains("bosunai"));
+        for binary in SERVICE_BINARIES {
+            assert!(result.content.contains(&format!(
+                "COPY --from=mirror.local/swiftide-docker-service:1 /usr/bin/{binary} /usr/bin/{binary}"
+            )));
+        }
+    }
+
+    #[test]
+    fn test_detect_base_image() {
+        for (image, expected) in [
+            ("alpine", BaseImage::Alpine),
+            ("alpine:3.20", BaseImage::Alpine),
+            ("rust:1.89-alpine", BaseImage::Alpine),
+            ("docker.io/library/alpine@sha256:abc", BaseImage::Alpine),
+            ("cgr.dev/chainguard/wolfi-base", BaseImage::Wolfi),
+            ("debian:bookworm-slim", BaseImage::Glibc),
+            ("ubuntu", BaseImage::Glibc),
+            ("registry:5000/team/rust:1.89", BaseImage::Glibc),
+            ("scratch", BaseImage::Minimal),
+            ("busybox:1.36", BaseImage::Minimal),
+            ("gcr.io/distroless/cc-debian12", BaseImage::Minimal),
+            ("cgr.dev/chainguard/static", BaseImage::Minimal),
+        ] {
+            assert_eq!(BaseImage::detect(image), expected, "{image}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_base() {
+        let dockerfile = Dockerfile::parse(indoc::indoc! {r#"
+            ARG BASE=alpine DISTRO="bookworm"
+            FROM ${BASE}:3.20 AS base
+            FROM base AS dev
+            FROM debian:$DISTRO-slim
+        "#})
+        .unwrap();
+        let stages = dockerfile.stages();
+
+        let resolve = |stage: &Stage, args: &[(&str, &str)]| {
+            let build_args = args
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            resolve_base(&dockerfile, stage, &build_args)
+        };
+
+        assert_eq!(resolve(&stages[1], &[]), "alpine:3.20");
+        assert_eq!(resolve(&stages[1], &[("BASE", "busybox")]), "busybox:3.20");
+        assert_eq!(resolve(&stages[2], &[]), "debian:bookworm-slim");
+    }
+
+    #[test]
+    fn test_substitute_args() {
+        let args = BTreeMap::from([
+            ("BASE".to_string(), "alpine".to_string()),
+            ("BASE_IMAGE".to_string(), "debian".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+
+        for (word, expected) in [
+            ("$BASE_IMAGE:bookworm", "debian:bookworm"),
+            ("${BASE}_IMAGE", "alpine_IMAGE"),
+            ("$BASE-$BASE_IMAGE", "alpine-debian"),
+            ("${MISSING:-busybox}:latest", "busybox:latest"),
+            ("${EMPTY:-busybox}", "busybox"),
+            ("${EMPTY-busybox}", ""),
+            ("${MISSING-$BASE}", "alpine"),
+            ("${BASE:+ghcr.io/}alpine", "ghcr.io/alpine"),
+            ("${MISSING:+ghcr.io/}alpine", "alpine"),
+            ("$MISSING/alpine", "/alpine"),
+            ("\\$BASE", "$BASE"),
+        ] {
+            assert_eq!(substitute_args(word, &args), expected, "{word}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mangle_for_base_images() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("Dockerfile");
+
+        let mut mangled = String::new();
+        for base in [
+            "alpine:3.20",
+            "cgr.dev/chainguard/wolfi-base",
+            "debian:bookworm-slim",
+            "busybox",
+            "gcr.io/distroless/static-debian12",
+        ] {
+            std::fs::write(&file_path, format!("FROM {base}\nWORKDIR /app")).unwrap();
+
+            for static_service in [false, true] {
+                let result = mangle(
+                    &file_path,
+                    &BuildOptions::default(),
+                    &BTreeMap::new(),
+                    SERVICE_IMAGE,
+                    static_service,
+                )
+                .await
+                .unwrap();
+
+                // Static binaries never need packages
+                if static_service {
+                    assert!(!result.content.contains("RUN"), "{}", result.content);
+                }
+                mangled.push_str(&format!(
+                    "# {base}, static: {static_service}\n{}\n\n",
+                    result.content
+                ));
+            }
+        }
+
+        assert_snapshot!(mangled);
+    }
 }
@@ -0,0 +1,471 @@
+//! Parses Dockerfiles into instructions, keeping the original text so they can be edited and
+//! written back
+//!
+//! Follows the rules of docker's own parser: parser directives are only recognized at the top,
+//! comments and empty lines inside continuations are skipped, the escape character can be
+//! changed with `# escape=`, and `RUN`, `COPY` and `ADD` can have heredocs.
+use std::fmt;
+
+use crate::DockerfileParseError;
+
+/// Instructions that can have heredocs
+const HEREDOC_INSTRUCTIONS: [&str; 3] = ["RUN", "COPY", "ADD"];
+
+/// Directives docker recognizes, anything else at the top is a comment
+const DIRECTIVES: [&str; 3] = ["syntax", "escape", "check"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dockerfile {
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    /// A parser directive like `# syntax=docker/dockerfile:1`
+    Directive {
+        name: String,
+        value: String,
+        raw: String,
+    },
+    /// A comment or an empty line between instructions
+    Text(String),
+    Instruction(Instruction),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// The instruction in upper case, i.e. `FROM`
+    pub keyword: String,
+    /// Everything after the keyword, with continuations joined and comments removed
+    pub arguments: String,
+    pub heredocs: Vec<Heredoc>,
+    /// The instruction as written, including continuation lines and heredocs
+    pub raw: String,
+    /// The line the instruction starts on in the original Dockerfile, counting from 1, or `None`
+    /// if it was added
+    pub line: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heredoc {
+    pub delimiter: String,
+    pub body: String,
+}
+
+/// A `FROM` instruction and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    /// Index of the `FROM` instruction in the items
+    pub index: usize,
+    /// The image or stage the stage is based on
+    pub base: String,
+    /// The name after `AS`, if any
+    pub name: Option<String>,
+}
+
+impl Dockerfile {
+    pub fn parse(content: &str) -> Result<Self, DockerfileParseError> {
+        let lines = content.lines().collect::<Vec<_>>();
+        let mut items = Vec::new();
+        let mut escape = '\\';
+        let mut in_directives = true;
+        let mut pos = 0;
+
+        while pos < lines.len() {
+            let line = lines[pos];
+
+            if in_directives {
+                if let Some((name, value)) = parse_directive(line) {
+                    if name == "escape" {
+                        escape = match value.as_str() {
+                            "\\" => '\\',
+                            "`" => '`',
+                            _ => return Err(DockerfileParseError::InvalidEscape(value)),
+                        };
+                    }
+                    items.push(Item::Directive {
+                        name,
+                        value,
+                        raw: line.to_string(),
+                    });
+                    pos += 1;
+                    continue;
+                }
+                in_directives = false;
+            }
+
+            if is_comment_or_empty(line) {
+                items.push(Item::Text(line.to_string()));
+                pos += 1;
+                continue;
+            }
+
+            let (instruction, next) = parse_instruction(&lines, pos, escape)?;
+            items.push(Item::Instruction(instruction));
+            pos = next;
+        }
+
+        Ok(Self { items })
+    }
+
+    /// All stages in order
+    pub fn stages(&self) -> Vec<Stage> {
+        self.items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| match item {
+                Item::Instruction(instruction) if instruction.is("FROM") => {
+                    let mut words = instruction
+                        .arguments
+                        .split_whitespace()
+                        .skip_while(|word| word.starts_with("--"));
+                    let base = words.next()?.to_string();
+                    let name = words
+                        .next()
+                        .filter(|word| word.eq_ignore_ascii_case("as"))
+                        .and_then(|_| words.next())
+                        .map(str::to_string);
+
+                    Some(Stage { index, base, name })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The stage with the given name, names are case insensitive like in docker
+    pub fn stage(&self, name: &str) -> Option<Stage> {
+        self.stages().into_iter().find(|stage| {
+            stage
+                .name
+                .as_deref()
+                .is_some_and(|stage_name| stage_name.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+impl fmt::Display for Dockerfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, item) in self.items.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            match item {
+                Item::Directive { raw, .. } | Item::Text(raw) => f.write_str(raw)?,
+                Item::Instruction(instruction) => f.write_str(&instruction.raw)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Instruction {
+    /// An instruction that is not in the original Dockerfile
+    pub fn new(keyword: &str, arguments: impl Into<String>) -> Self {
+        let keyword = keyword.to_uppercase();
+        let arguments = arguments.into();
+
+        Self {
+            raw: format!("{keyword} {arguments}"),
+            keyword,
+            arguments,
+            heredocs: Vec::new(),
+            line: None,
+        }
+    }
+
+    pub fn is(&self, keyword: &str) -> bool {
+        self.keyword.eq_ignore_ascii_case(keyword)
+    }
+}
+
+/// Parses the instruction starting at `start`, returning it and the position of the next line
+fn parse_instruction(
+    lines: &[&str],
+    start: usize,
+    escape: char,
+) -> Result<(Instruction, usize), DockerfileParseError> {
+    let mut logical_line = String::new();
+    let mut pos = start;
+
+    loop {
+        let line = lines[pos];
+        pos += 1;
+
+        // Comments and empty lines do not end a continuation, and are not part of it
+        if pos - 1 > start && is_comment_or_empty(line) {
+            if pos == lines.len() {
+                break;
+            }
+            continue;
+        }
+
+        let trimmed = line.trim_end();
+        match trimmed.strip_suffix(escape) {
+            Some(continued) if pos < lines.len() => logical_line.push_str(continued),
+            Some(continued) => {
+                logical_line.push_str(continued);
+                break;
+            }
+            None => {
+                logical_line.push_str(line);
+                break;
+            }
+        }
+    }
+
+    let logical_line = logical_line.trim();
+    let (keyword, arguments) = logical_line
+        .split_once(char::is_whitespace)
+        .unwrap_or((logical_line, ""));
+    let keyword = keyword.to_uppercase();
+    let arguments = arguments.trim().to_string();
+
+    let mut heredocs = Vec::new();
+    if HEREDOC_INSTRUCTIONS.contains(&keyword.as_str()) {
+        for (delimiter, strip_tabs) in heredoc_delimiters(&arguments) {
+            let mut body = Vec::new();
+            loop {
+                let Some(line) = lines.get(pos) else {
+                    return Err(DockerfileParseError::UnterminatedHeredoc {
+                        delimiter,
+                        line: start + 1,
+                    });
+                };
+                pos += 1;
+
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == delimiter {
+                    break;
+                }
+                body.push(line);
+            }
+
+            heredocs.push(Heredoc {
+                delimiter,
+                body: body.join("\n"),
+            });
+        }
+    }
+
+    let instruction = Instruction {
+        keyword,
+        arguments,
+        heredocs,
+        raw: lines[start..pos].join("\n"),
+        line: Some(start + 1),
+    };
+
+    Ok((instruction, pos))
+}
+
+/// Parses `# name=value` if the name is a known directive
+fn parse_directive(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.trim().strip_prefix('#')?.split_once('=')?;
+    let name = name.trim().to_lowercase();
+    let value = value.trim();
+
+    (DIRECTIVES.contains(&name.as_str()) && !value.is_empty()).then(|| (name, value.to_string()))
+}
+
+fn is_comment_or_empty(line: &str) -> bool {
+    let trimmed = line.trim_start();
+
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+/// The delimiters of the heredocs in the arguments, like `<<EOF`, `<<-EOF` or `<<"EOF"`, and
+/// whether leading tabs are stripped from their bodies
+fn heredoc_delimiters(arguments: &str) -> Vec<(String, bool)> {
+    arguments
+        .split_whitespace()
+        .filter_map(|word| {
+            // A file descriptor can come first, like `3<<EOF`
+            let word = word.trim_start_matches(|c: char| c.is_ascii_digit());
+            let word = word.strip_prefix("<<")?;
+            let (word, strip_tabs) = match word.strip_prefix('-') {
+                Some(word) => (word, true),
+                None => (word, false),
+            };
+
+            let delimiter = ['"', '\'']
+                .iter()
+                .find_map(|quote| word.strip_prefix(*quote)?.strip_suffix(*quote))
+                .unwrap_or(word);
+
+            // Here strings like `<<<word` are not heredocs
+            (!delimiter.is_empty() && !delimiter.contains('<'))
+                .then(|| (delimiter.to_string(), strip_tabs))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instructions(dockerfile: &Dockerfile) -> impl Iterator<Item = &Instruction> {
+        dockerfile.items.iter().filter_map(|item| match item {
+            Item::Instruction(instruction) => Some(instruction),
+            _ => None,
+        })
+    }
+
+    fn keywords(dockerfile: &Dockerfile) -> Vec<(&str, &str)> {
+        instructions(dockerfile)
+            .map(|instruction| (instruction.keyword.as_str(), instruction.arguments.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_continuation_lines() {
+        let content = indoc::indoc! {r#"
+            FROM debian
+            RUN apt-get update \
+                # comments in a continuation are skipped
+                && apt-get install -y \
+
+                cmdtest \
+                fromager
+            CMD ["true"]
+        "#};
+        let dockerfile = Dockerfile::parse(content).unwrap();
+
+        assert_eq!(
+            keywords(&dockerfile),
+            [
+                ("FROM", "debian"),
+                (
+                    "RUN",
+                    "apt-get update     && apt-get install -y     cmdtest     fromager"
+                ),
+                ("CMD", "[\"true\"]"),
+            ]
+        );
+        assert_eq!(
+            instructions(&dockerfile)
+                .map(|instruction| instruction.line)
+                .collect::<Vec<_>>(),
+            [Some(1), Some(2), Some(8)]
+        );
+        assert_eq!(dockerfile.to_string(), content.trim_end());
+        insta::assert_debug_snapshot!(dockerfile);
+    }
+
+    #[test]
+    fn test_heredocs() {
+        let content = indoc::indoc! {r#"
+            # syntax=docker/dockerfile:1
+            FROM alpine
+            RUN <<EOF
+            cmd --not-an-instruction
+            from here
+            EOF
+            COPY <<-"CONFIG" /etc/app.conf <<END /etc/other.conf
+            	key=value
+            	CONFIG
+            other
+            END
+            RUN cat <<<"not a heredoc"
+        "#};
+        let dockerfile = Dockerfile::parse(content).unwrap();
+
+        assert_eq!(
+            keywords(&dockerfile),
+            [
+                ("FROM", "alpine"),
+                ("RUN", "<<EOF"),
+                ("COPY", "<<-\"CONFIG\" /etc/app.conf <<END /etc/other.conf"),
+                ("RUN", "cat <<<\"not a heredoc\""),
+            ]
+        );
+        assert_eq!(dockerfile.to_string(), content.trim_end());
+        insta::assert_debug_snapshot!(dockerfile);
+
+        let result = Dockerfile::parse("FROM alpine\nRUN <<EOF\necho never ends\n");
+        assert!(matches!(
+            result,
+            Err(DockerfileParseError::UnterminatedHeredoc { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_directives_and_comments() {
+        let content = indoc::indoc! {r#"
+            # syntax=docker/dockerfile:1
+            # escape=`
+            # a comment ends the directives
+            # check=skip=all
+
+            FROM mcr.microsoft.com/windows/servercore
+            RUN echo one `
+                two
+            # FROM not-a-stage
+        "#};
+        let dockerfile = Dockerfile::parse(content).unwrap();
+
+        assert_eq!(
+            keywords(&dockerfile),
+            [
+                ("FROM", "mcr.microsoft.com/windows/servercore"),
+                ("RUN", "echo one     two"),
+            ]
+        );
+        assert_eq!(dockerfile.stages().len(), 1);
+        assert_eq!(dockerfile.to_string(), content.trim_end());
+        insta::assert_debug_snapshot!(dockerfile);
+
+        let result = Dockerfile::parse("# escape=x\nFROM alpine");
+        assert!(matches!(
+            result,
+            Err(DockerfileParseError::InvalidEscape(_))
+        ));
+    }
+
+    #[test]
+    fn test_stages() {
+        let content = indoc::indoc! {r#"
+            ARG RUST_VERSION=1.89
+            FROM --platform=$BUILDPLATFORM rust:${RUST_VERSION} AS Builder
+            RUN cargo build --release
+
+            from debian:bookworm-slim as runtime
+            COPY --from=builder /app/target/release/app /usr/bin/app
+
+            FROM runtime
+            fromage is not an instruction we know, but it is one
+        "#};
+        let dockerfile = Dockerfile::parse(content).unwrap();
+
+        assert_eq!(
+            dockerfile.stages(),
+            [
+                Stage {
+                    index: 1,
+                    base: "rust:${RUST_VERSION}".to_string(),
+                    name: Some("Builder".to_string()),
+                },
+                Stage {
+                    index: 4,
+                    base: "debian:bookworm-slim".to_string(),
+                    name: Some("runtime".to_string()),
+                },
+                Stage {
+                    index: 7,
+                    base: "runtime".to_string(),
+                    name: None,
+                },
+            ]
+        );
+        assert_eq!(dockerfile.stage("builder").unwrap().index, 1);
+        assert!(dockerfile.stage("missing").is_none());
+        assert_eq!(instructions(&dockerfile).last().unwrap().keyword, "FROMAGE");
+        insta::assert_debug_snapshot!(dockerfile);
+    }
+}
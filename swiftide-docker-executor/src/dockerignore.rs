@@ -0,0 +1,300 @@
+//! Matching paths against a `.dockerignore` the way docker does
+//!
+//! Unlike `.gitignore`, patterns are anchored at the root of the context, `**` matches any number
+//! of directories, and the last matching pattern decides whether a path is ignored, so `!`
+//! exceptions only apply to what comes before them.
+use std::path::{Component, Path};
+
+/// The patterns of a `.dockerignore`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DockerIgnore {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    /// The cleaned pattern, without a leading `!` or `/`
+    pattern: String,
+    /// True for `!` exceptions, which re-include what earlier patterns ignored
+    exception: bool,
+}
+
+impl DockerIgnore {
+    /// Reads the `.dockerignore` in a context path, if there is one
+    pub(crate) fn from_path(context_path: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(context_path.join(".dockerignore")) {
+            Ok(contents) => Ok(Self::parse(&contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .trim_start_matches('\u{feff}')
+            .lines()
+            .filter_map(|line| {
+                if line.starts_with('#') {
+                    return None;
+                }
+
+                let line = line.trim();
+                let (exception, pattern) = match line.strip_prefix('!') {
+                    Some(pattern) => (true, pattern.trim()),
+                    None => (false, line),
+                };
+
+                let pattern = clean(pattern);
+                (!pattern.is_empty()).then_some(Pattern { pattern, exception })
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    /// True if a path relative to the context is ignored, directly or through a parent
+    pub(crate) fn is_ignored(&self, relative_path: &Path) -> bool {
+        let path = to_slash(relative_path);
+        if path.is_empty() {
+            return false;
+        }
+
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            // Only patterns that can flip the outcome need to be checked
+            if pattern.exception != ignored {
+                continue;
+            }
+
+            if pattern.matches_or_parent_matches(&path) {
+                ignored = !pattern.exception;
+            }
+        }
+
+        ignored
+    }
+
+    /// True if nothing below an ignored directory can be re-included by an exception, so the
+    /// directory does not need to be walked
+    pub(crate) fn can_skip_dir(&self, relative_dir: &Path) -> bool {
+        let dir = to_slash(relative_dir);
+
+        self.is_ignored(relative_dir)
+            && !self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.exception && pattern.may_match_below(&dir))
+    }
+}
+
+impl Pattern {
+    fn matches_or_parent_matches(&self, path: &str) -> bool {
+        if glob_match(self.pattern.as_bytes(), path.as_bytes()) {
+            return true;
+        }
+
+        path.match_indices('/')
+            .any(|(index, _)| glob_match(self.pattern.as_bytes(), &path.as_bytes()[..index]))
+    }
+
+    /// True if the pattern could match a path inside the directory
+    fn may_match_below(&self, dir: &str) -> bool {
+        let mut pattern = self.pattern.split('/');
+
+        for component in dir.split('/') {
+            match pattern.next() {
+                Some(part) if part.contains("**") => return true,
+                Some(part) if glob_match(part.as_bytes(), component.as_bytes()) => {}
+                _ => return false,
+            }
+        }
+
+        pattern.next().is_some()
+    }
+}
+
+/// Cleans a pattern like docker does: separators are collapsed, `.` and `..` are resolved and
+/// patterns are relative to the root of the context
+fn clean(pattern: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+
+    for component in pattern.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components.join("/")
+}
+
+fn to_slash(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Matches a whole path against a docker ignore pattern
+///
+/// `*` and `?` do not match `/`, `**` matches any number of directories, `[...]` matches a
+/// class of characters and `\` escapes the next character.
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` also matches no directory at all
+            if let [b'/', after @ ..] = rest
+                && glob_match(after, path)
+            {
+                return true;
+            }
+
+            (0..=path.len()).any(|skip| glob_match(rest, &path[skip..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+
+            (0..=segment).any(|skip| glob_match(rest, &path[skip..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [c, path @ ..] if *c != b'/' => glob_match(rest, path),
+            _ => false,
+        },
+        [b'[', class @ ..] => {
+            let Some((matches, rest)) = path.first().and_then(|&c| match_class(class, c)) else {
+                return false;
+            };
+
+            matches && glob_match(rest, &path[1..])
+        }
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match path {
+            [p, path @ ..] if p == c => glob_match(rest, path),
+            _ => false,
+        },
+    }
+}
+
+/// Matches a character against a class after its opening `[`, returning whether it matched and
+/// the pattern after the closing `]`
+fn match_class(class: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    if c == b'/' {
+        return None;
+    }
+
+    let (negated, mut class) = match class {
+        [b'^' | b'!', rest @ ..] => (true, rest),
+        class => (false, class),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (low, rest) = match class {
+            [b']', rest @ ..] if !first => return Some((matched != negated, rest)),
+            [b'\\', low, rest @ ..] | [low, rest @ ..] => (*low, rest),
+            [] => return None,
+        };
+        first = false;
+
+        let (high, rest) = match rest {
+            [b'-', high, rest @ ..] if *high != b']' => (*high, rest),
+            rest => (low, rest),
+        };
+
+        matched |= (low..=high).contains(&c);
+        class = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(dockerignore: &str, path: &str) -> bool {
+        DockerIgnore::parse(dockerignore).is_ignored(Path::new(path))
+    }
+
+    #[test]
+    fn test_patterns_are_anchored_at_the_root() {
+        assert!(ignored("*.log", "debug.log"));
+        assert!(!ignored("*.log", "logs/debug.log"));
+        assert!(ignored("/logs/*.log", "logs/debug.log"));
+        assert!(ignored("./logs", "logs/debug.log"));
+        assert!(ignored("target", "target/debug/app"));
+        assert!(!ignored("target", "crates/target/debug/app"));
+    }
+
+    #[test]
+    fn test_double_star() {
+        assert!(ignored("**/*.log", "debug.log"));
+        assert!(ignored("**/*.log", "a/b/debug.log"));
+        assert!(ignored(
+            "**/node_modules",
+            "web/node_modules/react/index.js"
+        ));
+        assert!(ignored("docs/**/*.md", "docs/README.md"));
+        assert!(ignored("docs/**/*.md", "docs/guide/intro.md"));
+        assert!(!ignored("docs/**/*.md", "README.md"));
+        assert!(ignored("**", "anything/at/all"));
+    }
+
+    #[test]
+    fn test_wildcards_and_classes() {
+        assert!(ignored("file?.txt", "file1.txt"));
+        assert!(!ignored("file?.txt", "file10.txt"));
+        assert!(ignored("file[0-9].txt", "file7.txt"));
+        assert!(!ignored("file[!0-9].txt", "file7.txt"));
+        assert!(ignored("file[!0-9].txt", "filex.txt"));
+        assert!(ignored(r"\*.txt", "*.txt"));
+        assert!(!ignored(r"\*.txt", "a.txt"));
+        assert!(!ignored("a*b", "a/b"));
+        assert!(ignored("*/b", "a/b"));
+    }
+
+    #[test]
+    fn test_exceptions_apply_in_order() {
+        let dockerignore = "*.md\n!README.md\n";
+        assert!(ignored(dockerignore, "CHANGELOG.md"));
+        assert!(!ignored(dockerignore, "README.md"));
+
+        // A later pattern ignores the exception again
+        let dockerignore = "*.md\n!README*.md\nREADME-secret.md\n";
+        assert!(!ignored(dockerignore, "README-public.md"));
+        assert!(ignored(dockerignore, "README-secret.md"));
+
+        // Exceptions before the pattern they should undo do nothing
+        assert!(ignored("!README.md\n*.md\n", "README.md"));
+    }
+
+    #[test]
+    fn test_comments_and_whitespace() {
+        let dockerignore = "# a comment\n\n  target  \n! keep.txt\n*.txt\n!keep.txt";
+        let ignore = DockerIgnore::parse(dockerignore);
+        assert_eq!(ignore.patterns.len(), 4);
+        assert!(ignore.is_ignored(Path::new("target/app")));
+        assert!(!ignore.is_ignored(Path::new("keep.txt")));
+        assert!(!ignore.is_ignored(Path::new("# a comment")));
+    }
+
+    #[test]
+    fn test_can_skip_dir() {
+        let ignore = DockerIgnore::parse("target\nnode_modules\n!node_modules/keep.js\n");
+        assert!(ignore.can_skip_dir(Path::new("target")));
+        assert!(!ignore.can_skip_dir(Path::new("node_modules")));
+        assert!(!ignore.can_skip_dir(Path::new("src")));
+        assert!(!ignore.is_ignored(Path::new("node_modules/keep.js")));
+
+        let ignore = DockerIgnore::parse("vendor\n!**/LICENSE\n");
+        assert!(!ignore.can_skip_dir(Path::new("vendor")));
+
+        let ignore = DockerIgnore::parse("build\n!src/build/keep\n");
+        assert!(ignore.can_skip_dir(Path::new("build")));
+    }
+}
@@ -0,0 +1,104 @@
+//! Just enough of ELF to tell static binaries apart from dynamically linked ones
+use std::path::Path;
+
+/// Program header type of the dynamic loader a binary needs
+const PT_INTERP: u32 = 3;
+
+/// True if the file is an ELF binary that does not need a dynamic loader, so it runs on any
+/// linux base image regardless of its libc
+pub(crate) fn is_static_binary(path: &Path) -> std::io::Result<bool> {
+    Ok(is_static_elf(&fs_err::read(path)?))
+}
+
+pub(crate) fn is_static_elf(bytes: &[u8]) -> bool {
+    let Some(header) = bytes.get(..64) else {
+        return false;
+    };
+    if header[..4] != *b"\x7fELF" {
+        return false;
+    }
+
+    let little_endian = header[5] == 1;
+    let read = |offset: usize, len: usize| -> Option<u64> {
+        let field = bytes.get(offset..offset.checked_add(len)?)?;
+        let mut value = 0_u64;
+        for (index, byte) in field.iter().enumerate() {
+            let shift = if little_endian {
+                index
+            } else {
+                len - 1 - index
+            };
+            value |= u64::from(*byte) << (8 * shift);
+        }
+        Some(value)
+    };
+
+    // 32 and 64 bit binaries have their program headers at different offsets
+    let (phoff, phentsize, phnum) = match header[4] {
+        1 => (read(0x1c, 4), read(0x2a, 2), read(0x2c, 2)),
+        2 => (read(0x20, 8), read(0x36, 2), read(0x38, 2)),
+        _ => return false,
+    };
+    let (Some(phoff), Some(phentsize), Some(phnum)) = (phoff, phentsize, phnum) else {
+        return false;
+    };
+
+    // Offsets come from the file, so they can point anywhere
+    (0..phnum).all(|index| {
+        index
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .and_then(|offset| usize::try_from(offset).ok())
+            .and_then(|offset| read(offset, 4))
+            .is_some_and(|kind| kind != u64::from(PT_INTERP))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64 bit little endian ELF header with program headers of the given types
+    fn elf(program_headers: &[u32]) -> Vec<u8> {
+        let mut bytes = vec![0; 64];
+        bytes[..4].copy_from_slice(b"\x7fELF");
+        bytes[4] = 2;
+        bytes[5] = 1;
+        bytes[0x20..0x28].copy_from_slice(&64_u64.to_le_bytes());
+        bytes[0x36..0x38].copy_from_slice(&56_u16.to_le_bytes());
+        bytes[0x38..0x3a].copy_from_slice(&(program_headers.len() as u16).to_le_bytes());
+
+        for kind in program_headers {
+            let mut program_header = vec![0; 56];
+            program_header[..4].copy_from_slice(&kind.to_le_bytes());
+            bytes.extend(program_header);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_is_static_elf() {
+        assert!(is_static_elf(&elf(&[1, 1, 4])));
+        assert!(!is_static_elf(&elf(&[6, PT_INTERP, 1])));
+
+        // Not an ELF binary, or cut short
+        assert!(!is_static_elf(b"#!/bin/sh\necho hello"));
+        assert!(!is_static_elf(&elf(&[1, 1])[..100]));
+
+        // Program headers past the end of the address space
+        let mut bytes = elf(&[1]);
+        bytes[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(!is_static_elf(&bytes));
+        bytes[0x20..0x28].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        bytes[0x38..0x3a].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(!is_static_elf(&bytes));
+    }
+
+    #[cfg(target_env = "gnu")]
+    #[test]
+    fn test_test_binary_is_dynamic() {
+        let test_binary = std::env::current_exe().unwrap();
+
+        assert!(!is_static_binary(&test_binary).unwrap());
+    }
+}
@@ -1,4 +1,8 @@
-use std::{convert::Infallible, net::AddrParseError, path::StripPrefixError};
+use std::{
+    convert::Infallible,
+    net::AddrParseError,
+    path::{PathBuf, StripPrefixError},
+};
 
 use thiserror::Error;
 
@@ -33,6 +37,24 @@ pub enum DockerExecutorError {
 
     #[error(transparent)]
     ContainerStart(#[from] ContainerStartError),
+
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+
+    #[error(transparent)]
+    ImageArchive(#[from] ImageArchiveError),
+
+    #[error("network mode is not supported here: {0}")]
+    UnsupportedNetworkMode(String),
+
+    #[error("without a network the container must run as root or a numeric uid, not {0}")]
+    NonNumericUser(String),
+
+    #[error("container {container_id} ran out of memory, it is limited to {memory_limit} bytes")]
+    OutOfMemory {
+        container_id: String,
+        memory_limit: i64,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -51,6 +73,18 @@ pub enum ContextError {
 
     #[error("error with custom dockerfile: {0}")]
     CustomDockerfile(String),
+
+    #[error("destination {0} is not a relative path inside the context")]
+    InvalidDestination(PathBuf),
+
+    #[error("build context {0} is not a directory")]
+    NotADirectory(PathBuf),
+
+    #[error("service binary {0} does not exist")]
+    MissingServiceBinary(PathBuf),
+
+    #[error("file {0} shrank while the context was archived")]
+    FileShrank(PathBuf),
 }
 
 #[derive(Error, Debug)]
@@ -62,11 +96,31 @@ pub enum ClientError {
 #[derive(Error, Debug)]
 pub enum MangleError {
     #[error("Failed to read Dockerfile: {0}")]
-    DockerfileReadError(std::io::Error), // #[]
-    //
+    DockerfileReadError(std::io::Error),
+
     #[error("invalid dockerfile")]
-    InvalidDockerfile, // IoError(std::io::Error),
-                       // Utf8Error(std::string::FromUtf8Error),
+    InvalidDockerfile,
+
+    #[error("build target {0} is not a stage in the dockerfile")]
+    UnknownTarget(String),
+
+    #[error("build context {0} has the same name as a stage in the dockerfile")]
+    DuplicateStage(String),
+
+    #[error("build context name {0} is not a valid stage name")]
+    InvalidContextName(String),
+
+    #[error("failed to parse dockerfile: {0}")]
+    Parse(#[from] DockerfileParseError),
+}
+
+#[derive(Error, Debug)]
+pub enum DockerfileParseError {
+    #[error("invalid escape directive {0}, must be \\ or `")]
+    InvalidEscape(String),
+
+    #[error("heredoc {delimiter} starting on line {line} is never closed")]
+    UnterminatedHeredoc { delimiter: String, line: usize },
 }
 
 #[derive(Error, Debug)]
@@ -77,11 +131,57 @@ pub enum ImageBuildError {
     #[error("build failed: {0}")]
     BuildFailed(String),
 
+    #[error("failed to send build context: {0}")]
+    Context(ContextError),
+
     #[error("build error: {0}")]
-    BuildError(String),
+    BuildError(Box<BuildFailure>),
 
     #[error("invalid image name: {0}")]
     InvalidImageName(String),
+
+    #[error("invalid size of /dev/shm for the build: {0} bytes")]
+    InvalidShmSize(i64),
+
+    #[error("build timed out after {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("build was cancelled")]
+    Cancelled,
+}
+
+/// Why a build failed, with the output leading up to it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildFailure {
+    /// The error reported by docker
+    pub message: String,
+    /// The instruction that failed, i.e. `RUN cargo build`
+    pub step: Option<String>,
+    /// The line of the failing instruction in the original Dockerfile, counting from 1. `None`
+    /// if unknown, or if the instruction was added by the executor.
+    pub line: Option<usize>,
+    /// The last lines of output of the failing step, or the build if it printed nothing. At most
+    /// [`LOG_TAIL_LINES`](crate::build_events::LOG_TAIL_LINES).
+    pub log_tail: Vec<String>,
+    /// Where the failing step is in the Dockerfile that was built, to find its line
+    pub(crate) position: Option<crate::build_events::StepPosition>,
+}
+
+impl std::fmt::Display for BuildFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(step) = &self.step {
+            write!(f, " in `{step}`")?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " at line {line} of the Dockerfile")?;
+        }
+        for line in &self.log_tail {
+            write!(f, "\n  {line}")?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -113,6 +213,43 @@ pub enum ContainerStartError {
     #[error("Invalid address: {0}")]
     InvalidAddress(#[from] AddrParseError),
 }
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error(transparent)]
+    Context(#[from] ContextError),
+
+    #[error("failed to download workspace from container: {0}")]
+    Download(bollard::errors::Error),
+
+    #[error("failed to upload workspace to container: {0}")]
+    Upload(bollard::errors::Error),
+
+    #[error("failed to remove files from container: {0}")]
+    Remove(anyhow::Error),
+
+    #[error("error with io {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to convert to relative path{0}")]
+    RelativePath(#[from] StripPrefixError),
+}
+
+#[derive(Error, Debug)]
+pub enum ImageArchiveError {
+    #[error("failed to save image {0}: {1}")]
+    Export(String, bollard::errors::Error),
+
+    #[error("failed to load image archive {0}: {1}")]
+    Import(PathBuf, String),
+
+    #[error("image archive {0} does not have image {1}")]
+    MissingImage(PathBuf, String),
+
+    #[error("error with io {0}")]
+    Io(#[from] std::io::Error),
+}
+
 impl From<Infallible> for DockerExecutorError {
     fn from(_: Infallible) -> Self {
         unreachable!()
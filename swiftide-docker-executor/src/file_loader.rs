@@ -1,22 +1,105 @@
-use std::{borrow::Cow, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::Context as _;
 use codegen::{LoadFilesRequest, NodeResponse, loader_client::LoaderClient};
 use swiftide_core::{Loader, indexing::TextNode};
-use tokio::runtime::Handle;
+use tokio::sync::mpsc::Sender;
+use tonic::codec::Streaming;
 
-use crate::RunningDockerExecutor;
+use crate::{RunningDockerExecutor, container_starter::ServiceAddress};
 
 pub mod codegen {
     tonic::include_proto!("loader");
 }
 
+/// Metadata key set to `true` on nodes for files that were deleted since the cursor
+pub const DELETED_METADATA_KEY: &str = "deleted";
+/// Metadata key with the sha256 hex digest of the file contents
+pub const CONTENT_HASH_METADATA_KEY: &str = "content_hash";
+/// Metadata key set to `true` on nodes for binary files, which have an empty chunk
+pub const BINARY_METADATA_KEY: &str = "binary";
+
 #[derive(Debug, Clone)]
 pub struct FileLoader<'a> {
     path: PathBuf,
     extensions: Vec<String>,
+    include_globs: Vec<String>,
+    exclude_globs: Vec<String>,
+    max_file_size: Option<u64>,
+    include_hidden: bool,
+    ignore_files: Vec<String>,
+    cursor: Option<LoadCursor>,
+    next_cursor: NextCursor,
+    chunking: Option<Chunking>,
+    binary_files: BinaryFiles,
+    max_retries: u32,
+    retry_delay: Duration,
     executor: Cow<'a, RunningDockerExecutor>,
 }
 
+/// How the service splits files into chunks before streaming them
+///
+/// Every chunk is streamed as its own node, with its byte offset in the UTF-8 text of the file as
+/// the node offset. This is the offset in the file itself, unless the file was transcoded from
+/// the encoding in its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of at most `max_bytes`, each starting `overlap_bytes` before the end of the previous
+    FixedSize { max_bytes: u64, overlap_bytes: u64 },
+    /// Chunks of at most `max_lines`, each repeating the last `overlap_lines` of the previous
+    Lines { max_lines: u32, overlap_lines: u32 },
+    /// Chunks on headings, merging sections up to `max_bytes`. Every section is a chunk if 0.
+    Markdown { max_bytes: u64 },
+    /// Chunks on top level declarations, merging them up to `max_bytes`. Every declaration is a
+    /// chunk if 0. The language is derived from the file extension unless given.
+    Code {
+        max_bytes: u64,
+        language: Option<String>,
+    },
+}
+
+/// What the loader does with files that are not text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BinaryFiles {
+    /// Leave binary files out of the stream
+    #[default]
+    Skip,
+    /// Stream a node with an empty chunk and `BINARY_METADATA_KEY` set
+    Report,
+}
+
+/// The state of a previous load, so that only files changed since can be streamed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadCursor {
+    /// Files not modified after this point in time are assumed to be unchanged
+    pub modified_since: Option<SystemTime>,
+    /// Content hashes of the files known after the previous load, by path
+    pub manifest: BTreeMap<PathBuf, String>,
+}
+
+/// Receives the cursor for the next incremental load, once a stream has been fully consumed
+#[derive(Debug, Clone, Default)]
+pub struct NextCursor(Arc<Mutex<Option<LoadCursor>>>);
+
+impl NextCursor {
+    /// Returns the cursor, or `None` if the stream has not completed successfully (yet)
+    pub fn get(&self) -> Option<LoadCursor> {
+        self.0.lock().ok().and_then(|cursor| cursor.clone())
+    }
+
+    fn set(&self, cursor: LoadCursor) {
+        if let Ok(mut next) = self.0.lock() {
+            *next = Some(cursor);
+        }
+    }
+}
+
 impl RunningDockerExecutor {
     /// Creates an owned file loader from the executor. If needed it is safe to clone the executor.
     ///
@@ -26,13 +109,7 @@ impl RunningDockerExecutor {
         path: impl Into<PathBuf>,
         extensions: V,
     ) -> FileLoader<'static> {
-        let path = path.into();
-        let extensions = extensions.into_iter().map(Into::into).collect::<Vec<_>>();
-        FileLoader {
-            path,
-            extensions,
-            executor: Cow::Owned(self),
-        }
+        FileLoader::new(path, extensions, Cow::Owned(self))
     }
 
     /// Creates a borrowed file loader from the executor.
@@ -41,74 +118,442 @@ impl RunningDockerExecutor {
         path: impl Into<PathBuf>,
         extensions: V,
     ) -> FileLoader<'a> {
-        let path = path.into();
-        let extensions = extensions.into_iter().map(Into::into).collect::<Vec<_>>();
+        FileLoader::new(path, extensions, Cow::Borrowed(self))
+    }
+}
+
+impl<'a> FileLoader<'a> {
+    fn new<V: IntoIterator<Item = T>, T: Into<String>>(
+        path: impl Into<PathBuf>,
+        extensions: V,
+        executor: Cow<'a, RunningDockerExecutor>,
+    ) -> Self {
         FileLoader {
-            path,
-            extensions,
-            executor: Cow::Borrowed(self),
+            path: path.into(),
+            extensions: extensions.into_iter().map(Into::into).collect(),
+            include_globs: Vec::new(),
+            exclude_globs: Vec::new(),
+            max_file_size: None,
+            include_hidden: false,
+            ignore_files: Vec::new(),
+            cursor: None,
+            next_cursor: NextCursor::default(),
+            chunking: None,
+            binary_files: BinaryFiles::default(),
+            max_retries: 3,
+            retry_delay: Duration::from_millis(250),
+            executor,
         }
     }
+
+    /// Let the service split files into chunks, instead of streaming every file as a single node
+    pub fn with_chunking(&mut self, chunking: Chunking) -> &mut Self {
+        self.chunking = Some(chunking);
+
+        self
+    }
+
+    /// What to do with files that are not text. Binary files are skipped by default.
+    ///
+    /// Text files in other encodings than UTF-8 are transcoded by the service.
+    pub fn with_binary_files(&mut self, binary_files: BinaryFiles) -> &mut Self {
+        self.binary_files = binary_files;
+
+        self
+    }
+
+    /// Retry connecting to and starting a load on the service this many times (default 3)
+    ///
+    /// Failures after files have been streamed are not retried, to avoid duplicate nodes.
+    pub fn with_max_retries(&mut self, retries: u32) -> &mut Self {
+        self.max_retries = retries;
+
+        self
+    }
+
+    /// Wait this long before the first retry, doubling on every next retry (default 250ms)
+    pub fn with_retry_delay(&mut self, delay: Duration) -> &mut Self {
+        self.retry_delay = delay;
+
+        self
+    }
+
+    /// Only stream files changed since the cursor of a previous load, followed by nodes for
+    /// deleted files. Deleted files have an empty chunk and `DELETED_METADATA_KEY` set.
+    pub fn with_cursor(&mut self, cursor: LoadCursor) -> &mut Self {
+        self.cursor = Some(cursor);
+
+        self
+    }
+
+    /// Returns a handle to the cursor for the next incremental load
+    ///
+    /// The cursor becomes available once the stream of this loader has been fully consumed
+    /// without errors.
+    pub fn next_cursor(&self) -> NextCursor {
+        self.next_cursor.clone()
+    }
+
+    /// Only load files matching at least one of the globs (gitignore syntax, relative to the path).
+    /// Ignored and hidden files stay out even if they match.
+    pub fn with_include_globs<V: IntoIterator<Item = T>, T: Into<String>>(
+        &mut self,
+        globs: V,
+    ) -> &mut Self {
+        self.include_globs.extend(globs.into_iter().map(Into::into));
+
+        self
+    }
+
+    /// Skip files matching any of the globs, i.e. `vendor/**` or `*.lock`
+    pub fn with_exclude_globs<V: IntoIterator<Item = T>, T: Into<String>>(
+        &mut self,
+        globs: V,
+    ) -> &mut Self {
+        self.exclude_globs.extend(globs.into_iter().map(Into::into));
+
+        self
+    }
+
+    /// Skip files larger than the given number of bytes
+    pub fn with_max_file_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_file_size = Some(bytes);
+
+        self
+    }
+
+    /// Also load hidden files and directories. Default is false.
+    pub fn include_hidden(&mut self, include: bool) -> &mut Self {
+        self.include_hidden = include;
+
+        self
+    }
+
+    /// Respect additional ignore files with gitignore syntax, i.e. `.indexignore`, next to
+    /// `.gitignore` and `.ignore`
+    pub fn with_ignore_files<V: IntoIterator<Item = T>, T: Into<String>>(
+        &mut self,
+        file_names: V,
+    ) -> &mut Self {
+        self.ignore_files
+            .extend(file_names.into_iter().map(Into::into));
+
+        self
+    }
 }
 
 impl Loader for FileLoader<'_> {
     type Output = String;
+
+    /// Streams the files from the container
+    ///
+    /// Connecting happens in the background. Connection and load failures are retried as
+    /// configured, and are sent as error items on the stream once retries are exhausted.
     fn into_stream(self) -> swiftide_core::indexing::IndexingStream<String> {
-        let container_ip = &self.executor.container_ip;
-        let container_port = &self.executor.container_port;
-        let mut client = tokio::task::block_in_place(|| {
-            Handle::current().block_on(async {
-                LoaderClient::connect(format!("http://{container_ip}:{container_port}")).await
-            })
-        })
-        .expect("Failed to connect to service");
+        let remote = RemoteLoad {
+            address: self.executor.service_address.clone(),
+            manifest: self
+                .cursor
+                .as_ref()
+                .map(|cursor| cursor.manifest.clone())
+                .unwrap_or_default(),
+            request: LoadFilesRequest {
+                root_path: self.path.to_string_lossy().to_string(),
+                file_extensions: self.extensions,
+                include_globs: self.include_globs,
+                exclude_globs: self.exclude_globs,
+                max_file_size: self.max_file_size,
+                include_hidden: self.include_hidden,
+                ignore_files: self.ignore_files,
+                cursor: self.cursor.map(Into::into),
+                chunking: self.chunking.map(Into::into),
+                binary_files: codegen::BinaryFiles::from(self.binary_files).into(),
+            },
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            next_cursor: self.next_cursor,
+        };
 
         let (tx, rx) = tokio::sync::mpsc::channel::<anyhow::Result<TextNode>>(1000);
 
-        tokio::task::spawn(async move {
-            let stream = match client
-                .load_files(LoadFilesRequest {
-                    root_path: self.path.to_string_lossy().to_string(),
-                    file_extensions: self.extensions,
-                })
-                .await
-            {
-                Ok(stream) => stream,
+        tokio::task::spawn(remote.run(tx));
+
+        rx.into()
+    }
+}
+
+/// A single load against the service, detached from the executor so it can run in the background
+struct RemoteLoad {
+    address: ServiceAddress,
+    request: LoadFilesRequest,
+    manifest: BTreeMap<PathBuf, String>,
+    max_retries: u32,
+    retry_delay: Duration,
+    next_cursor: NextCursor,
+}
+
+impl RemoteLoad {
+    async fn run(mut self, tx: Sender<anyhow::Result<TextNode>>) {
+        let started_at = SystemTime::now();
+
+        let mut stream = match self.open_stream().await {
+            Ok(stream) => stream,
+            Err(error) => {
+                tracing::error!(error = ?error, "Failed to load files");
+                let _ = tx.send(Err(error)).await;
+                return;
+            }
+        };
+
+        let mut completed = true;
+
+        while let Some(result) = stream.message().await.transpose() {
+            match &result {
+                Ok(node) if node.deleted => {
+                    self.manifest.remove(&PathBuf::from(&node.path));
+                }
+                // Files that failed to load are unknown to the next load, so it reads them again
+                Ok(node) if !node.error.is_empty() => {
+                    self.manifest.remove(&PathBuf::from(&node.path));
+                }
+                Ok(node) => {
+                    self.manifest
+                        .insert(node.path.clone().into(), node.content_hash.clone());
+                }
                 Err(error) => {
-                    tracing::error!(error = ?error, "Failed to load files");
-                    return;
+                    tracing::error!(error = ?error, "Error while loading files");
+                    completed = false;
                 }
+            }
+
+            let node = result
+                .context("error while loading files")
+                .and_then(TryInto::try_into);
+
+            if let Err(e) = tx.send(node).await {
+                tracing::error!(error = ?e, "error sending node");
+                return;
+            }
+        }
+
+        if completed {
+            self.next_cursor.set(LoadCursor {
+                modified_since: Some(started_at),
+                manifest: self.manifest,
+            });
+        }
+    }
+
+    /// Connects and starts the load, retrying transient failures with exponential backoff
+    async fn open_stream(&self) -> anyhow::Result<Streaming<NodeResponse>> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            let error = match self.address.connect().await.map(LoaderClient::new) {
+                Ok(mut client) => match client.load_files(self.request.clone()).await {
+                    Ok(response) => return Ok(response.into_inner()),
+                    Err(status) if !is_retryable(&status) => {
+                        return Err(status).context("failed to load files");
+                    }
+                    Err(status) => anyhow::Error::from(status).context("failed to load files"),
+                },
+                Err(error) => anyhow::Error::from(error).context(format!(
+                    "failed to connect to loader service at {}",
+                    self.address
+                )),
             };
 
-            let mut stream = stream.into_inner();
-
-            while let Some(result) = stream.message().await.transpose() {
-                if let Err(e) = tx
-                    .send(
-                        result
-                            .map_err(anyhow::Error::from)
-                            .and_then(TryInto::try_into),
-                    )
-                    .await
-                {
-                    tracing::error!(error = ?e, "error sending node");
-                    break;
-                }
+            if attempt >= self.max_retries {
+                return Err(error);
             }
-        });
 
-        rx.into()
+            attempt += 1;
+            tracing::warn!(
+                error = ?error,
+                attempt,
+                max_retries = self.max_retries,
+                "Retrying load in {delay:?}"
+            );
+            tokio::time::sleep(delay).await;
+            delay = delay.saturating_mul(2);
+        }
+    }
+}
+
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::ResourceExhausted
+            | tonic::Code::Aborted
+    )
+}
+
+impl From<Chunking> for codegen::ChunkingStrategy {
+    fn from(chunking: Chunking) -> Self {
+        use codegen::chunking_strategy::Strategy;
+
+        let strategy = match chunking {
+            Chunking::FixedSize {
+                max_bytes,
+                overlap_bytes,
+            } => Strategy::FixedSize(codegen::FixedSize {
+                max_bytes,
+                overlap_bytes,
+            }),
+            Chunking::Lines {
+                max_lines,
+                overlap_lines,
+            } => Strategy::Lines(codegen::Lines {
+                max_lines,
+                overlap_lines,
+            }),
+            Chunking::Markdown { max_bytes } => Strategy::Markdown(codegen::Markdown { max_bytes }),
+            Chunking::Code {
+                max_bytes,
+                language,
+            } => Strategy::Code(codegen::Code {
+                max_bytes,
+                language,
+            }),
+        };
+
+        codegen::ChunkingStrategy {
+            strategy: Some(strategy),
+        }
+    }
+}
+
+impl From<BinaryFiles> for codegen::BinaryFiles {
+    fn from(binary_files: BinaryFiles) -> Self {
+        match binary_files {
+            BinaryFiles::Skip => codegen::BinaryFiles::Skip,
+            BinaryFiles::Report => codegen::BinaryFiles::Report,
+        }
+    }
+}
+
+impl From<LoadCursor> for codegen::LoadCursor {
+    fn from(cursor: LoadCursor) -> Self {
+        codegen::LoadCursor {
+            modified_since_ms: cursor.modified_since.map(|since| {
+                since
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO)
+                    .as_millis() as u64
+            }),
+            manifest: cursor
+                .manifest
+                .into_iter()
+                .map(|(path, hash)| (path.to_string_lossy().to_string(), hash))
+                .collect(),
+        }
     }
 }
 
 impl TryInto<TextNode> for NodeResponse {
     type Error = anyhow::Error;
 
+    /// Besides the file contents, nodes carry the `metadata` sent by the service (i.e.
+    /// `modified_at`, `permissions`, `language`, `git_status` and `encoding`) and the content hash
+    ///
+    /// Files that could not be loaded by the service become errors.
     fn try_into(self) -> Result<TextNode, Self::Error> {
-        TextNode::builder()
+        if !self.error.is_empty() {
+            anyhow::bail!(self.error);
+        }
+
+        // Services predating 64-bit sizes only send `original_size`
+        let size = if self.size > 0 {
+            self.size
+        } else {
+            self.original_size.max(0) as u64
+        };
+
+        let mut node = TextNode::builder()
             .path(self.path)
             .chunk(self.chunk)
-            .original_size(self.original_size as usize)
-            .build()
+            .original_size(usize::try_from(size)?)
+            .offset(usize::try_from(self.chunk_start)?)
+            .build()?;
+
+        for (key, value) in self.metadata {
+            node.metadata.insert(key, value);
+        }
+
+        if !self.content_hash.is_empty() {
+            node.metadata
+                .insert(CONTENT_HASH_METADATA_KEY, self.content_hash);
+        }
+
+        if self.deleted {
+            node.metadata.insert(DELETED_METADATA_KEY, true);
+        }
+
+        if self.binary {
+            node.metadata.insert(BINARY_METADATA_KEY, true);
+        }
+
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unreachable_service_yields_error() {
+        let remote = RemoteLoad {
+            address: ServiceAddress::Tcp {
+                ip: [127, 0, 0, 1].into(),
+                port: "1".to_string(),
+            },
+            request: LoadFilesRequest::default(),
+            manifest: BTreeMap::new(),
+            max_retries: 1,
+            retry_delay: Duration::from_millis(1),
+            next_cursor: NextCursor::default(),
+        };
+        let next_cursor = remote.next_cursor.clone();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        remote.run(tx).await;
+
+        let error = rx.recv().await.unwrap().unwrap_err();
+        assert!(
+            format!("{error:#}").contains("failed to connect to loader service"),
+            "{error:#}"
+        );
+        assert!(rx.recv().await.is_none());
+        assert!(next_cursor.get().is_none());
+    }
+
+    #[test]
+    fn test_error_and_binary_nodes() {
+        let error: anyhow::Result<TextNode> = NodeResponse {
+            path: "broken.rs".into(),
+            error: "failed to read broken.rs: permission denied".into(),
+            ..Default::default()
+        }
+        .try_into();
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "failed to read broken.rs: permission denied"
+        );
+
+        let node: TextNode = NodeResponse {
+            path: "logo.png".into(),
+            size: 42,
+            binary: true,
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        assert!(node.chunk.is_empty());
+        assert_eq!(node.original_size, 42);
+        assert!(node.metadata.get(BINARY_METADATA_KEY).is_some());
     }
 }
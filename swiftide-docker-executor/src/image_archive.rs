@@ -0,0 +1,160 @@
+//! Saving built images to tarballs and loading them back
+//!
+//! Images are tagged with the hash of their inputs, so an archive of an image can be loaded on
+//! another machine, i.e. an ephemeral CI runner without access to a registry, and is used by
+//! `DockerExecutor::start` instead of building the image again. Set a directory with
+//! `DockerExecutor::with_image_cache_dir` to do this automatically.
+use std::path::{Path, PathBuf};
+
+use bollard::query_parameters::ImportImageOptions;
+use tokio::io::AsyncWriteExt as _;
+use tokio_stream::StreamExt as _;
+use tokio_util::io::ReaderStream;
+
+use crate::{DockerExecutorError, ImageArchiveError, client::Client};
+
+/// An image saved to a tarball
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageArchive {
+    /// The image with its tag, i.e. `docker-executor:1a2b3c4d5e6f`
+    pub image: String,
+    /// The hash of the inputs of the image, which is its tag
+    pub hash: String,
+    pub path: PathBuf,
+}
+
+/// The file an image is saved to in a cache directory, i.e.
+/// `docker-executor-1a2b3c4d5e6f.tar` for `docker-executor:1a2b3c4d5e6f`
+pub fn archive_file_name(image: &str) -> String {
+    let file_name = image
+        .chars()
+        .map(|c| if c == '/' || c == ':' { '-' } else { c })
+        .collect::<String>();
+
+    format!("{file_name}.tar")
+}
+
+/// Saves an image, like `docker save`
+///
+/// # Example
+///
+/// ```no_run
+/// # use swiftide_docker_executor::{DockerExecutor, image_archive};
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let executor = DockerExecutor::default().start().await?;
+/// let archive = image_archive::export_image(&executor.image, "executor.tar".as_ref()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn export_image(image: &str, path: &Path) -> Result<ImageArchive, DockerExecutorError> {
+    let docker = Client::lazy_client().await?;
+
+    Ok(save(&docker, image, path).await?)
+}
+
+/// Loads the images in an archive, like `docker load`, and returns their tags
+pub async fn import_image(path: &Path) -> Result<Vec<String>, DockerExecutorError> {
+    let docker = Client::lazy_client().await?;
+
+    Ok(load(&docker, path).await?)
+}
+
+pub(crate) async fn save(
+    docker: &Client,
+    image: &str,
+    path: &Path,
+) -> Result<ImageArchive, ImageArchiveError> {
+    tracing::info!("Saving image {image} to {}", path.display());
+
+    // Write next to the archive first, so an interrupted save leaves no broken archive
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let result: Result<(), ImageArchiveError> = async {
+        if let Some(dir) = path.parent() {
+            fs_err::tokio::create_dir_all(dir).await?;
+        }
+        let mut file = fs_err::tokio::File::create(&partial).await?;
+        let mut stream = docker.export_image(image);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|err| ImageArchiveError::Export(image.to_string(), err))?;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        fs_err::tokio::rename(&partial, path).await?;
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs_err::tokio::remove_file(&partial).await;
+    }
+    result?;
+
+    Ok(ImageArchive {
+        image: image.to_string(),
+        hash: image
+            .rsplit_once(':')
+            .map(|(_, tag)| tag.to_string())
+            .unwrap_or_default(),
+        path: path.to_path_buf(),
+    })
+}
+
+pub(crate) async fn load(docker: &Client, path: &Path) -> Result<Vec<String>, ImageArchiveError> {
+    tracing::info!("Loading images from {}", path.display());
+
+    let file = fs_err::tokio::File::open(path).await?;
+    let mut stream =
+        docker.import_image_stream(ImportImageOptions::default(), ReaderStream::new(file), None);
+
+    let mut images = Vec::new();
+    while let Some(info) = stream.next().await {
+        let info =
+            info.map_err(|err| ImageArchiveError::Import(path.to_path_buf(), err.to_string()))?;
+
+        if let Some(error) = info.error_detail.and_then(|detail| detail.message) {
+            return Err(ImageArchiveError::Import(path.to_path_buf(), error));
+        }
+        if let Some(image) = info.stream.as_deref().and_then(loaded_image) {
+            images.push(image);
+        }
+    }
+
+    Ok(images)
+}
+
+/// The image in a line like `Loaded image: docker-executor:1a2b3c4d5e6f`
+fn loaded_image(line: &str) -> Option<String> {
+    line.trim()
+        .strip_prefix("Loaded image: ")
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_file_name() {
+        assert_eq!(
+            archive_file_name("docker-executor:1a2b3c4d5e6f"),
+            "docker-executor-1a2b3c4d5e6f.tar"
+        );
+        assert_eq!(
+            archive_file_name("registry.local/team/executor:abc"),
+            "registry.local-team-executor-abc.tar"
+        );
+    }
+
+    #[test]
+    fn test_loaded_image() {
+        assert_eq!(
+            loaded_image("Loaded image: docker-executor:1a2b3c4d5e6f\n").as_deref(),
+            Some("docker-executor:1a2b3c4d5e6f")
+        );
+        assert_eq!(loaded_image("Loaded image ID: sha256:abc"), None);
+    }
+}
@@ -1,41 +1,243 @@
-use std::{io::Write as _, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 #[cfg(feature = "buildkit")]
+use bollard::grpc::build::SecretSource;
+#[cfg(feature = "buildkit")]
 use bollard::models::BuildInfoAux;
 use bollard::query_parameters::BuildImageOptions;
-use http_body_util::{Either, Full};
+#[cfg(feature = "buildkit")]
+use bytes::Bytes;
+use sha2::{Digest as _, Sha256};
 use swiftide_core::prelude::StreamExt as _;
+use tokio::task::JoinHandle;
+#[cfg(feature = "buildkit")]
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ContextError, ContextStats, ContextStream, ImageArchiveError, ImageBuildError,
+    build_events::{BuildEvent, BuildEvents, BuildProgress},
+    client::Client,
+    image_archive,
+};
+
+/// Number of characters of the hash used as image tag
+const IMAGE_TAG_LEN: usize = 16;
+
+/// Compressed size above which holding the context of a session build in memory is logged
+#[cfg(feature = "buildkit")]
+const LARGE_SESSION_CONTEXT: usize = 256 * 1024 * 1024;
+
+/// How long the status of a build over a session is read after the build ended
+#[cfg(feature = "buildkit")]
+const STATUS_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Options passed to docker when building the image
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BuildOptions {
+    pub build_args: BTreeMap<String, String>,
+    pub target: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub no_cache: bool,
+    pub pull: bool,
+    pub shm_size: Option<i64>,
+    /// Secrets available to `RUN --mount=type=secret`, by id
+    #[cfg(feature = "buildkit")]
+    pub secrets: BTreeMap<String, SecretSource>,
+    /// Forward the ssh agent from `SSH_AUTH_SOCK` to `RUN --mount=type=ssh`
+    #[cfg(feature = "buildkit")]
+    pub ssh: bool,
+}
+
+impl BuildOptions {
+    /// Returns the image tag for a context hash. Options that change the resulting image are
+    /// part of the tag, so that changing them results in a new image.
+    pub fn image_tag(&self, context_hash: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(context_hash);
+
+        for (kind, map) in [("arg", &self.build_args), ("label", &self.labels)] {
+            for (key, value) in map {
+                hasher.update(format!("\0{kind}\0{key}\0{value}"));
+            }
+        }
+        if let Some(target) = &self.target {
+            hasher.update(format!("\0target\0{target}"));
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        hash[..IMAGE_TAG_LEN].to_string()
+    }
+
+    /// True if an existing image should not be reused, because a fresh build was asked for
+    pub fn force_rebuild(&self) -> bool {
+        self.no_cache || self.pull
+    }
 
-use crate::{ImageBuildError, client::Client};
+    /// True if the build needs a buildkit session that serves secrets or the ssh agent
+    #[cfg(feature = "buildkit")]
+    fn needs_session_services(&self) -> bool {
+        !self.secrets.is_empty() || self.ssh
+    }
+}
 
 pub struct ImageBuilder {
     docker: Arc<Client>,
+    events: BuildEvents,
+    deadline: Option<Deadline>,
+    cancel_token: Option<CancellationToken>,
+}
+
+/// When builds are aborted, for a timeout that is shared by every build
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    timeout: Duration,
+    at: tokio::time::Instant,
 }
 
 impl ImageBuilder {
     pub fn new(docker: Arc<Client>) -> Self {
-        Self { docker }
+        Self {
+            docker,
+            events: BuildEvents::default(),
+            deadline: None,
+            cancel_token: None,
+        }
+    }
+
+    /// Abort builds that are still running this long from now. The time is shared by all builds,
+    /// so a start that builds more than one image is aborted once it runs out.
+    pub fn with_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.deadline = timeout.map(|timeout| Deadline {
+            timeout,
+            at: tokio::time::Instant::now() + timeout,
+        });
+
+        self
+    }
+
+    /// Abort builds when the token is cancelled
+    pub fn with_cancel_token(&mut self, cancel_token: Option<CancellationToken>) -> &mut Self {
+        self.cancel_token = cancel_token;
+
+        self
+    }
+
+    /// Send the progress of builds to a channel
+    pub fn with_events(&mut self, events: BuildEvents) -> &mut Self {
+        self.events = events;
+
+        self
+    }
+
+    /// Reports that an existing image is used instead of building one
+    pub fn reuse_image(&self, image: &str) {
+        tracing::info!("Reusing existing image {image}");
+        self.events.emit(BuildEvent::Reused {
+            image: image.to_string(),
+        });
+    }
+
+    /// Loads an image from an archive in the image cache directory
+    pub async fn load_image(&self, image: &str, path: &Path) -> Result<(), ImageArchiveError> {
+        let images = image_archive::load(&self.docker, path).await?;
+        if !images.iter().any(|loaded| loaded == image) {
+            return Err(ImageArchiveError::MissingImage(
+                path.to_path_buf(),
+                image.to_string(),
+            ));
+        }
+
+        self.events.emit(BuildEvent::Loaded {
+            image: image.to_string(),
+            path: path.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    /// Returns true if an image with the given name and tag exists locally
+    pub async fn image_exists(&self, image: &str) -> Result<bool, bollard::errors::Error> {
+        match self.docker.inspect_image(image).await {
+            Ok(_) => Ok(true),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
+            Err(err) => Err(err),
+        }
     }
 
     pub async fn build_image(
         &self,
-        context: Vec<u8>,
+        context: ContextStream,
         dockerfile: &str,
         image_name: &str,
         tag: &str,
+        labels: &HashMap<String, String>,
+        options: &BuildOptions,
     ) -> Result<String, ImageBuildError> {
-        let mut c = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
-        c.write_all(&context)
-            .map_err(ImageBuildError::Compression)?;
-        let compressed = c.finish().map_err(ImageBuildError::Compression)?;
-
+        let ContextStream { body, stats } = context;
         let image_name_with_tag = format!("{image_name}:{tag}");
+        let started = Instant::now();
+        self.events.emit(BuildEvent::Started {
+            image: image_name_with_tag.clone(),
+        });
+
+        // The labels of the executor take precedence over custom ones
+        let mut all_labels = options
+            .labels
+            .clone()
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        all_labels.extend(labels.clone());
+
+        #[cfg(feature = "buildkit")]
+        if options.needs_session_services() {
+            let result = self
+                .build_with_session(body, dockerfile, &image_name_with_tag, &all_labels, options)
+                .await;
+            let result = context_stats(stats, result).await;
+            self.finish(&image_name_with_tag, started, result)?;
+
+            return Ok(image_name_with_tag);
+        }
+
+        // `/build` takes the size as a 32 bit number
+        let shm_size = options
+            .shm_size
+            .map(|bytes| {
+                i32::try_from(bytes)
+                    .ok()
+                    .filter(|bytes| *bytes >= 0)
+                    .ok_or(ImageBuildError::InvalidShmSize(bytes))
+            })
+            .transpose();
+        let shm_size = match shm_size {
+            Ok(shm_size) => shm_size,
+            Err(err) => {
+                return self
+                    .finish(&image_name_with_tag, started, Err(err))
+                    .map(|()| image_name_with_tag);
+            }
+        };
 
         let build_options = BuildImageOptions {
             t: Some(image_name_with_tag.clone()),
             rm: true,
+            // Also remove the containers of failed or aborted steps
+            forcerm: true,
             dockerfile: dockerfile.to_string(),
+            labels: Some(all_labels),
+            buildargs: Some(options.build_args.clone().into_iter().collect()),
+            target: options.target.clone().unwrap_or_default(),
+            nocache: options.no_cache,
+            pull: options.pull.then(|| "true".to_string()),
+            shmsize: shm_size,
             // nocache: cfg!(debug_assertions),
             #[cfg(feature = "buildkit")]
             version: bollard::query_parameters::BuilderVersion::BuilderBuildKit,
@@ -44,48 +246,388 @@ impl ImageBuilder {
             ..Default::default()
         };
 
-        let mut build_stream = self.docker.build_image(
-            build_options,
-            None,
-            Some(Either::Left(Full::new(compressed.into()))),
-        );
+        let mut build_stream =
+            self.docker
+                .build_image(build_options, None, Some(bollard::body_try_stream(body)));
 
-        while let Some(log) = build_stream.next().await {
-            match log {
-                Ok(output) => {
-                    if let Some(output) = output.stream {
-                        tracing::info!("{}", output);
-                    }
+        let mut progress = BuildProgress::default();
+        let result = abortable(
+            async {
+                while let Some(log) = build_stream.next().await {
+                    match log {
+                        Ok(output) => {
+                            if let Some(output) = output.stream {
+                                tracing::info!("{}", output);
+                                progress
+                                    .classic(&output)
+                                    .into_iter()
+                                    .for_each(|event| self.events.emit(event));
+                            }
 
-                    // TODO: Verify to_string() is good enough
-                    #[cfg(feature = "buildkit")]
-                    if let Some(BuildInfoAux::BuildKit(inner)) = output.aux {
-                        inner
-                            .vertexes
-                            .iter()
-                            .take(1)
-                            .for_each(|log| tracing::info!("{}", log.name))
-                    }
+                            #[cfg(feature = "buildkit")]
+                            if let Some(BuildInfoAux::BuildKit(status)) = output.aux {
+                                for event in progress.buildkit(&status) {
+                                    if let BuildEvent::StepStarted { name, .. } = &event {
+                                        tracing::info!("{name}");
+                                    }
+                                    self.events.emit(event);
+                                }
+                            }
 
-                    if let Some(error_detail) = output.error_detail {
-                        let message = error_detail.message.unwrap_or_default();
+                            if let Some(error_detail) = output.error_detail {
+                                let message = error_detail.message.unwrap_or_default();
 
-                        tracing::error!(message, "Build error");
+                                tracing::error!(message, "Build error");
 
-                        return Err(ImageBuildError::BuildError(message));
+                                return Err(ImageBuildError::BuildError(Box::new(
+                                    progress.failure(message),
+                                )));
+                            }
+                        }
+                        Err(e) => {
+                            return Err(
+                                if let bollard::errors::Error::DockerStreamError { error } = e {
+                                    ImageBuildError::BuildError(Box::new(
+                                        progress.failure(format!("error during build: {error}")),
+                                    ))
+                                } else {
+                                    ImageBuildError::BuildFailed(e.to_string())
+                                },
+                            );
+                        }
                     }
                 }
-                Err(e) => {
-                    if let bollard::errors::Error::DockerStreamError { error } = e {
-                        return Err(ImageBuildError::BuildFailed(format!(
-                            "error during build: {error}"
-                        )));
+                Ok(())
+            },
+            self.deadline,
+            self.cancel_token.as_ref(),
+        )
+        .await;
+        // Stop sending the context, if the build ended early
+        drop(build_stream);
+
+        if let Some(event) = progress.finish_step() {
+            self.events.emit(event);
+        }
+        let result = context_stats(stats, result).await;
+        self.finish(&image_name_with_tag, started, result)?;
+
+        Ok(image_name_with_tag)
+    }
+
+    /// Reports how the build ended
+    fn finish(
+        &self,
+        image: &str,
+        started: Instant,
+        result: Result<(), ImageBuildError>,
+    ) -> Result<(), ImageBuildError> {
+        match &result {
+            Ok(()) => self.events.emit(BuildEvent::Finished {
+                image: image.to_string(),
+                duration: started.elapsed(),
+            }),
+            Err(err) => self.events.emit(BuildEvent::Error {
+                message: err.to_string(),
+            }),
+        }
+
+        result
+    }
+
+    /// Builds through a buildkit session that serves secrets and the ssh agent
+    ///
+    /// The `/build` endpoint only serves registry credentials to buildkit, so builds with secrets
+    /// or ssh forwarding solve over a session with the daemon instead. Their status is read from
+    /// buildkit while they run, and turned into the same events as those of `/build`.
+    ///
+    /// Aborting the build closes the connection that solves it, which makes buildkit cancel it.
+    /// The build is then waited for and removed from the build history.
+    #[cfg(feature = "buildkit")]
+    async fn build_with_session(
+        &self,
+        body: ReceiverStream<std::io::Result<Bytes>>,
+        dockerfile: &str,
+        image_name_with_tag: &str,
+        labels: &HashMap<String, String>,
+        options: &BuildOptions,
+    ) -> Result<(), ImageBuildError> {
+        use bollard::grpc::{
+            build::{ImageBuildFrontendOptions, ImageBuildLoadInput},
+            driver::{Build as _, moby::Moby},
+        };
+
+        let mut frontend_options = ImageBuildFrontendOptions::builder()
+            .dockerfile(std::path::Path::new(dockerfile))
+            .nocache(options.no_cache)
+            .pull(options.pull)
+            .enable_ssh(options.ssh);
+
+        if let Some(target) = &options.target {
+            frontend_options = frontend_options.target(target);
+        }
+        if let Some(shm_size) = options.shm_size {
+            let shm_size =
+                u64::try_from(shm_size).map_err(|_| ImageBuildError::InvalidShmSize(shm_size))?;
+            frontend_options = frontend_options.shmsize(shm_size);
+        }
+        for (key, value) in &options.build_args {
+            frontend_options = frontend_options.buildarg(key, value);
+        }
+        for (key, value) in labels {
+            frontend_options = frontend_options.label(key, value);
+        }
+        for (id, source) in &options.secrets {
+            frontend_options = frontend_options.set_secret(id, source);
+        }
+
+        tracing::info!(
+            image = image_name_with_tag,
+            secrets = ?options.secrets.keys().collect::<Vec<_>>(),
+            ssh = options.ssh,
+            "Building image with a buildkit session"
+        );
+
+        let build_ref = std::sync::OnceLock::new();
+        let mut progress = BuildProgress::default();
+        let build = async {
+            let compressed = session_context(body)
+                .await
+                .map_err(ImageBuildError::Compression)?;
+
+            let finished = CancellationToken::new();
+            let solve = async {
+                let result = Moby::new(&self.docker)
+                    .docker_build(
+                        image_name_with_tag,
+                        frontend_options.build(),
+                        ImageBuildLoadInput::Upload(compressed),
+                        None,
+                    )
+                    .await;
+                finished.cancel();
+                result
+            };
+
+            // The build does not depend on its status, it is only read for the events
+            let status = async {
+                let Some(socket_path) = &self.docker.socket_path else {
+                    tracing::debug!("Docker is not reached over a socket, the build has no status");
+                    return;
+                };
+                let watch = crate::buildkit_status::watch(
+                    socket_path,
+                    labels,
+                    |started| {
+                        let _ = build_ref.set(started.to_string());
+                    },
+                    |status| {
+                        for event in progress.buildkit(status) {
+                            if let BuildEvent::StepStarted { name, .. } = &event {
+                                tracing::info!("{name}");
+                            }
+                            self.events.emit(event);
+                        }
+                    },
+                );
+
+                // The status ends with the build, the last updates name the step that failed
+                let drained = async {
+                    finished.cancelled().await;
+                    tokio::time::sleep(STATUS_DRAIN_TIMEOUT).await;
+                };
+                tokio::select! {
+                    result = watch => {
+                        if let Err(err) = result {
+                            tracing::debug!(error = ?err, "Failed to read the status of the build");
+                        }
                     }
-                    return Err(ImageBuildError::BuildFailed(e.to_string()));
+                    () = drained => {}
                 }
-            }
+            };
+
+            let (result, ()) = tokio::join!(solve, status);
+            result
+                .map_err(|e| ImageBuildError::BuildError(Box::new(progress.failure(e.to_string()))))
+        };
+        let result = abortable(build, self.deadline, self.cancel_token.as_ref()).await;
+
+        if matches!(
+            result,
+            Err(ImageBuildError::Timeout(_) | ImageBuildError::Cancelled)
+        ) && let (Some(socket_path), Some(build_ref)) =
+            (&self.docker.socket_path, build_ref.get())
+            && let Err(err) = crate::buildkit_status::remove(socket_path, build_ref).await
+        {
+            tracing::warn!(error = ?err, build_ref, "Failed to clean up the aborted build");
         }
 
-        Ok(image_name_with_tag)
+        result
+    }
+}
+
+/// Collects the compressed context for a build over a session
+///
+/// Bollard uploads the context of a session in one piece, so unlike builds on `/build` the
+/// compressed archive is held in memory. Chunks are appended as they arrive, so it is only held
+/// once.
+#[cfg(feature = "buildkit")]
+async fn session_context(
+    mut body: ReceiverStream<std::io::Result<Bytes>>,
+) -> std::io::Result<Bytes> {
+    let mut compressed = bytes::BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        compressed.extend_from_slice(&chunk?);
+    }
+
+    if compressed.len() > LARGE_SESSION_CONTEXT {
+        tracing::warn!(
+            bytes = compressed.len(),
+            "The context of a build over a buildkit session is held in memory, ignore files \
+             that are not needed in .dockerignore"
+        );
+    }
+
+    Ok(compressed.freeze())
+}
+
+/// Runs a build until it ends, times out or is cancelled. Dropping the build stops streaming
+/// from docker, which stops the build and removes its intermediate containers.
+async fn abortable(
+    build: impl Future<Output = Result<(), ImageBuildError>>,
+    deadline: Option<Deadline>,
+    cancel_token: Option<&CancellationToken>,
+) -> Result<(), ImageBuildError> {
+    let timed_out = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.at).await,
+            None => std::future::pending().await,
+        }
+    };
+    let cancelled = async {
+        match cancel_token {
+            Some(cancel_token) => cancel_token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = build => result,
+        () = timed_out => Err(ImageBuildError::Timeout(
+            deadline.map(|deadline| deadline.timeout).unwrap_or_default()
+        )),
+        () = cancelled => Err(ImageBuildError::Cancelled),
+    }
+}
+
+/// Waits for the context to be archived and logs its size
+///
+/// A failure to archive the context is reported over the build error it caused.
+async fn context_stats(
+    stats: JoinHandle<Result<ContextStats, ContextError>>,
+    build_result: Result<(), ImageBuildError>,
+) -> Result<(), ImageBuildError> {
+    let stats = stats
+        .await
+        .map_err(|e| ImageBuildError::BuildFailed(format!("archiving context failed: {e}")))?;
+
+    match (stats, build_result) {
+        (Err(err), _) => Err(ImageBuildError::Context(err)),
+        (Ok(_), Err(err)) => Err(err),
+        (Ok(stats), Ok(())) => {
+            tracing::info!(
+                files = stats.files,
+                bytes = stats.bytes,
+                compressed_bytes = stats.compressed_bytes,
+                "Sent build context"
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_abortable_builds() {
+        let hanging = std::future::pending::<Result<(), ImageBuildError>>;
+
+        assert!(abortable(async { Ok(()) }, None, None).await.is_ok());
+
+        let timeout = Duration::from_millis(10);
+        let deadline = Deadline {
+            timeout,
+            at: tokio::time::Instant::now() + timeout,
+        };
+        let result = abortable(hanging(), Some(deadline), None).await;
+        assert!(matches!(result, Err(ImageBuildError::Timeout(after)) if after == timeout));
+
+        // Time spent on earlier builds counts towards the same deadline
+        let started = tokio::time::Instant::now();
+        let result = abortable(hanging(), Some(deadline), None).await;
+        assert!(matches!(result, Err(ImageBuildError::Timeout(_))));
+        assert!(started.elapsed() < timeout);
+
+        let cancel_token = CancellationToken::new();
+        cancel_token.cancel();
+        let result = abortable(hanging(), None, Some(&cancel_token)).await;
+        assert!(matches!(result, Err(ImageBuildError::Cancelled)));
+    }
+
+    #[test]
+    fn test_image_tag() {
+        let options = BuildOptions::default();
+        let tag = options.image_tag("hash");
+        assert_eq!(tag.len(), IMAGE_TAG_LEN);
+        assert_eq!(options.image_tag("hash"), tag);
+        assert_ne!(options.image_tag("other"), tag);
+
+        let with_arg = BuildOptions {
+            build_args: BTreeMap::from([("VERSION".to_string(), "1".to_string())]),
+            ..Default::default()
+        };
+        assert_ne!(with_arg.image_tag("hash"), tag);
+
+        let with_target = BuildOptions {
+            target: Some("dev".to_string()),
+            ..Default::default()
+        };
+        assert_ne!(with_target.image_tag("hash"), tag);
+        assert_ne!(with_target.image_tag("hash"), with_arg.image_tag("hash"));
+
+        // Options that do not change the image keep the tag
+        let uncached = BuildOptions {
+            no_cache: true,
+            pull: true,
+            shm_size: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(uncached.image_tag("hash"), tag);
+        assert!(uncached.force_rebuild());
+    }
+
+    #[cfg(feature = "buildkit")]
+    #[test]
+    fn test_secrets_keep_the_tag() {
+        let options = BuildOptions::default();
+        assert!(!options.needs_session_services());
+
+        let with_secret = BuildOptions {
+            secrets: BTreeMap::from([(
+                "token".to_string(),
+                SecretSource::Env("TOKEN".to_string()),
+            )]),
+            ..Default::default()
+        };
+        assert!(with_secret.needs_session_services());
+        assert_eq!(with_secret.image_tag("hash"), options.image_tag("hash"));
+
+        let with_ssh = BuildOptions {
+            ssh: true,
+            ..Default::default()
+        };
+        assert!(with_ssh.needs_session_services());
     }
 }
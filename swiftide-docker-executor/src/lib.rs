@@ -22,6 +22,8 @@
 //! # Ok(())
 //! # }
 //! ```
+#[cfg(feature = "buildkit")]
+mod buildkit_status;
 mod client;
 mod container_configurator;
 mod container_starter;
@@ -29,15 +31,23 @@ mod context_builder;
 mod docker_tool_executor;
 mod dockerfile_manager;
 mod dockerfile_mangler;
+mod dockerfile_parser;
+mod dockerignore;
+mod elf;
 mod errors;
 mod image_builder;
 mod running_docker_executor;
 
+pub mod build_events;
+pub mod cleanup;
 pub mod file_loader;
+pub mod image_archive;
+pub mod workspace_sync;
 
 #[cfg(test)]
 mod tests;
 
+pub use container_starter::ServiceAddress;
 pub use context_builder::*;
 pub use docker_tool_executor::*;
 pub use errors::*;
@@ -1,39 +1,78 @@
 use anyhow::Context as _;
 use async_trait::async_trait;
 use bollard::{
-    models::{ContainerState, ContainerStateStatusEnum},
-    query_parameters::{InspectContainerOptions, KillContainerOptions, RemoveContainerOptions},
+    exec::StartExecResults,
+    models::{
+        ContainerCreateBody, ContainerState, ContainerStateStatusEnum, ExecConfig,
+        NetworkConnectRequest, NetworkCreateRequest, NetworkDisconnectRequest,
+    },
+    query_parameters::{
+        CreateContainerOptions, CreateImageOptions, DownloadFromContainerOptions,
+        InspectContainerOptions, KillContainerOptions, RemoveContainerOptions,
+    },
 };
 use codegen::shell_executor_client::ShellExecutorClient;
 use futures_util::Stream;
 use std::{
     collections::HashMap,
-    net::IpAddr,
+    io::Write as _,
+    net::{IpAddr, Ipv4Addr},
+    os::unix::fs::{MetadataExt as _, PermissionsExt as _},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, LazyLock, Mutex},
     time::Duration,
 };
 pub use swiftide_core::ToolExecutor;
 use swiftide_core::{Command, CommandError, CommandOutput, Loader as _, prelude::StreamExt as _};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt as _;
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_tar::Archive;
+use tokio_util::io::StreamReader;
 use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
 use crate::{
-    ContextBuilder, ContextError, DockerExecutor, DockerExecutorError, client::Client,
-    container_configurator::ContainerConfigurator, container_starter::ContainerStarter,
-    dockerfile_manager::DockerfileManager, image_builder::ImageBuilder,
+    BuildContext, ContextBuilder, ContextError, DockerExecutor, DockerExecutorError,
+    DockerfileError, ImageBuildError, NetworkMode, SERVICE_BINARIES, ServiceSource,
+    build_events::BuildEvents,
+    cleanup::{self, Cleanup},
+    client::Client,
+    container_configurator::{
+        ContainerConfigurator, ContainerNetwork, NetworkSettings, own_container,
+    },
+    container_starter::{ContainerStarter, ServiceAddress},
+    context_builder::{BUILD_CONTEXTS_DIR, ExtraPath},
+    dockerfile_manager::DockerfileManager,
+    elf, image_archive,
+    image_builder::{BuildOptions, ImageBuilder},
+    workspace_sync::Manifest,
 };
 
 pub mod codegen {
     tonic::include_proto!("shell");
 }
+
 pub use bollard::container::LogOutput;
 
 #[derive(Clone, Debug)]
 pub struct RunningDockerExecutor {
     pub container_id: String,
+    /// The image the container runs, with its tag
+    pub image: String,
     pub(crate) docker: Arc<Client>,
+    /// Where the service in the container is reached, an ip and port or a socket on the host if
+    /// the container has no network
+    pub service_address: ServiceAddress,
+    /// The port of the service. Empty if the container has no network.
+    #[deprecated(
+        note = "use `service_address`, which is a socket if the container has no network"
+    )]
     pub container_port: String,
+    /// The ip of the container. Unspecified if the container has no network.
+    #[deprecated(
+        note = "use `service_address`, which is a socket if the container has no network"
+    )]
     pub container_ip: IpAddr,
     dropped: bool,
     retain_on_drop: bool,
@@ -44,6 +83,16 @@ pub struct RunningDockerExecutor {
     pub(crate) env: HashMap<String, String>,
     pub(crate) default_timeout: Option<Duration>,
     pub(crate) workdir: PathBuf,
+    pub(crate) memory_limit: Option<i64>,
+
+    // Host side of the workspace, and the state of the container when it started
+    pub(crate) context_path: PathBuf,
+    pub(crate) sync_baseline: Option<Arc<Manifest>>,
+
+    // Kept until the last clone is dropped, it holds the socket of the service
+    _socket_dir: Option<Arc<TempDir>>,
+    // Network created for the executor, removed on shutdown
+    network: Option<Arc<ExecutorNetwork>>,
 
     /// Cancellation token to stop anything polling the docker api
     cancel_token: Arc<CancellationToken>,
@@ -96,73 +145,286 @@ impl RunningDockerExecutor {
         let context_path = &builder.context_path;
         let user = builder.user.as_deref();
         let container_uuid = builder.container_uuid;
+        let build_options = &builder.build_options;
+        let labels = cleanup::labels(&container_uuid);
+
+        // Retained containers and their networks are not orphans once this process exits
+        let mut container_labels = labels.clone();
+        if builder.retain_on_drop {
+            container_labels.insert(cleanup::RETAINED_LABEL.to_string(), "true".to_string());
+        }
+
+        if builder.orphan_sweep {
+            match Cleanup::default().run().await {
+                Ok(report) => tracing::info!(?report, "Removed orphaned containers"),
+                Err(err) => tracing::warn!(?err, "Failed to remove orphaned containers"),
+            }
+        }
 
         // Only build if a dockerfile is provided
         if let Some(dockerfile) = dockerfile {
+            let mut image_builder = ImageBuilder::new(docker.clone());
+            image_builder
+                .with_events(BuildEvents::new(builder.build_events.clone()))
+                .with_timeout(builder.build_timeout)
+                .with_cancel_token(builder.build_cancel_token.clone());
+
+            // Named contexts become stages, directories are copied from the build context
+            for context in builder.build_contexts.values() {
+                if let BuildContext::Path(path) = context
+                    && !path.is_dir()
+                {
+                    return Err(ContextError::NotADirectory(path.clone()).into());
+                }
+            }
+
+            // The service binaries are copied from an image, which is built from the host
+            // binaries if those are used. Published images have static binaries.
+            let (service_image, static_service) = match &builder.service_source {
+                ServiceSource::Image(image) => {
+                    // Installing the libraries is harmless for static binaries, so assume the
+                    // worst if the image cannot be inspected
+                    let static_service = has_static_service_binaries(&docker, image, &labels)
+                        .await
+                        .unwrap_or_else(|err| {
+                            tracing::warn!(
+                                error = ?err,
+                                "Failed to inspect the service binaries in {image}"
+                            );
+                            false
+                        });
+                    (image.clone(), static_service)
+                }
+                ServiceSource::Binaries(dir) => {
+                    let mut static_service = true;
+                    let mut files = Vec::new();
+                    for binary in SERVICE_BINARIES {
+                        let host_path = dir.join(binary);
+                        if !host_path.is_file() {
+                            return Err(ContextError::MissingServiceBinary(host_path).into());
+                        }
+                        static_service &=
+                            elf::is_static_binary(&host_path).map_err(ContextError::Io)?;
+                        files.push(ExtraPath {
+                            host_path,
+                            destination: Path::new("usr/bin").join(binary),
+                        });
+                    }
+
+                    let service_image_name = format!("{image_name}-service");
+                    let image =
+                        build_files_image(&image_builder, &service_image_name, &labels, &files)
+                            .await?;
+                    (image, static_service)
+                }
+            };
+
             // Prepare dockerfile
             let dockerfile_manager = DockerfileManager::new(context_path);
-            let tmp_dockerfile = dockerfile_manager.prepare_dockerfile(dockerfile).await?;
-
-            // Build context
-            tracing::warn!(
-                "Creating archive for context from {}",
-                context_path.display()
-            );
-            let context = ContextBuilder::from_path(context_path, tmp_dockerfile.path())?
-                .build_tar()
+            let (tmp_dockerfile, line_map) = dockerfile_manager
+                .prepare_dockerfile(
+                    dockerfile,
+                    build_options,
+                    &builder.build_contexts,
+                    &service_image,
+                    static_service,
+                )
                 .await?;
 
-            tracing::debug!("Context build with size: {} bytes", context.len());
+            // Images are tagged by the hash of their inputs, so unchanged contexts are not rebuilt
+            let mut context_builder = builder.context_builder(tmp_dockerfile.path())?;
+            let tag = build_options.image_tag(&context_builder.content_hash().await?);
+            let tmp_dockerfile_name_inner = format!(".dockerfile.{tag}");
+            context_builder.with_dockerfile_name(&tmp_dockerfile_name_inner);
+
+            let image_name_with_tag = format!("{image_name}:{tag}");
+
+            let force_rebuild = build_options.force_rebuild();
+            let cached_archive = builder
+                .image_cache_dir
+                .as_ref()
+                .map(|dir| dir.join(image_archive::archive_file_name(&image_name_with_tag)));
+
+            // The cache is best effort, an archive that cannot be loaded is built instead
+            let reuse = !force_rebuild && image_builder.image_exists(&image_name_with_tag).await?;
+            let loaded = match cached_archive
+                .as_deref()
+                .filter(|archive| !reuse && !force_rebuild && archive.is_file())
+            {
+                Some(archive) => match image_builder
+                    .load_image(&image_name_with_tag, archive)
+                    .await
+                {
+                    Ok(()) => true,
+                    Err(err) => {
+                        tracing::warn!(error = %err, "Failed to load cached image, building it");
+                        false
+                    }
+                },
+                None => false,
+            };
+            let built = !reuse && !loaded;
 
-            let tmp_dockerfile_name_inner = tmp_dockerfile
-                .path()
-                .file_name()
-                .ok_or_else(|| {
-                    ContextError::CustomDockerfile("Could not read custom dockerfile".to_string())
-                })
-                .map(|s| s.to_string_lossy().to_string())?;
+            if reuse {
+                image_builder.reuse_image(&image_name_with_tag);
+            }
+            if built {
+                tracing::warn!(
+                    "Creating archive for context from {}",
+                    context_path.display()
+                );
+                image_builder
+                    .build_image(
+                        context_builder.stream_tar(),
+                        tmp_dockerfile_name_inner.as_ref(),
+                        &image_name,
+                        &tag,
+                        &labels,
+                        build_options,
+                    )
+                    .await
+                    .map_err(|mut err| {
+                        // Point at the instruction in the Dockerfile of the user
+                        if let ImageBuildError::BuildError(failure) = &mut err {
+                            failure.line = failure
+                                .step
+                                .as_deref()
+                                .and_then(|step| line_map.find(step, failure.position.as_ref()));
+                        }
+                        err
+                    })?;
+            }
 
-            drop(tmp_dockerfile); // Make sure the temporary file is removed right away
+            // Images that were built, or that existed before the cache was used, are saved
+            if let Some(archive) = &cached_archive
+                && (built || !archive.is_file())
+                && let Err(err) = image_archive::save(&docker, &image_name_with_tag, archive).await
+            {
+                tracing::warn!(error = %err, "Failed to save image to the cache");
+            }
 
-            // Build image
-            let tag = container_uuid
-                .to_string()
-                .split_once('-')
-                .map(|(tag, _)| tag)
-                .unwrap_or("latest")
-                .to_string();
-
-            let image_builder = ImageBuilder::new(docker.clone());
-            let image_name_with_tag = image_builder
-                .build_image(
-                    context,
-                    tmp_dockerfile_name_inner.as_ref(),
-                    &image_name,
-                    &tag,
-                )
-                .await?;
+            drop(tmp_dockerfile); // Make sure the temporary file is removed right away
 
             image_name = image_name_with_tag;
             tmp_dockerfile_name = Some(tmp_dockerfile_name_inner);
         }
 
+        // Without a network the service listens on a socket in a directory shared with the host
+        let mut socket_dir = None;
+        let mut network = None;
+        let container_network = match &builder.network_mode {
+            NetworkMode::Default => ContainerNetwork::Default,
+            NetworkMode::None => {
+                // A directory on the host is only shared with the container if both run on it
+                if own_container(&docker).await.is_some() {
+                    return Err(DockerExecutorError::UnsupportedNetworkMode(
+                        "a container without a network cannot be reached from another container"
+                            .to_string(),
+                    ));
+                }
+                if is_docker_desktop(&docker).await {
+                    return Err(DockerExecutorError::UnsupportedNetworkMode(
+                        "sockets on the host cannot be shared with containers on Docker Desktop"
+                            .to_string(),
+                    ));
+                }
+
+                // The user of the image, if none is given
+                let user = match user {
+                    Some(user) => Some(user.to_string()),
+                    None => docker
+                        .inspect_image(&image_name)
+                        .await?
+                        .config
+                        .and_then(|config| config.user)
+                        .filter(|user| !user.is_empty()),
+                };
+                let dir = socket_dir_for(user.as_deref())?;
+                let container_network = ContainerNetwork::None {
+                    socket_dir: dir.path().to_path_buf(),
+                };
+                socket_dir = Some(Arc::new(dir));
+                container_network
+            }
+            NetworkMode::Internal => {
+                // Docker Desktop runs containers in a vm, their ips are not reachable from the
+                // host. An executor in a container joins the network instead.
+                if own_container(&docker).await.is_none() && is_docker_desktop(&docker).await {
+                    return Err(DockerExecutorError::UnsupportedNetworkMode(
+                        "containers on an internal network cannot be reached from the host on \
+                         Docker Desktop"
+                            .to_string(),
+                    ));
+                }
+                let created =
+                    ExecutorNetwork::create(docker.clone(), &container_uuid, &container_labels)
+                        .await?;
+                let container_network = ContainerNetwork::Named {
+                    name: created.name.clone(),
+                    internal: true,
+                };
+                network = Some(Arc::new(created));
+                container_network
+            }
+            NetworkMode::Named(name) => ContainerNetwork::Named {
+                name: name.clone(),
+                internal: false,
+            },
+        };
+        let network_settings = NetworkSettings {
+            network: container_network,
+            extra_hosts: builder.extra_hosts.clone(),
+            dns: builder.dns.clone(),
+        };
+
         // Configure container
-        let container_config = ContainerConfigurator::new(docker.socket_path.clone())
-            .create_container_config(&image_name, user, &docker)
+        let docker_socket = builder
+            .docker_socket
+            .then(|| docker.socket_path.clone())
+            .flatten();
+        let container_config = ContainerConfigurator::new(docker_socket)
+            .create_container_config(
+                &image_name,
+                user,
+                &container_labels,
+                &builder.resource_limits,
+                &network_settings,
+                &docker,
+            )
             .await;
 
         // Start container
         tracing::info!("Starting container with image: {image_name} and uuid: {container_uuid}");
         let container_starter = ContainerStarter::new(docker.clone());
-        let (container_id, container_ip, container_port) = container_starter
-            .start_container(&image_name, &container_uuid, container_config)
-            .await?;
-
+        let started = container_starter
+            .start_container(
+                &image_name,
+                &container_uuid,
+                container_config,
+                &network_settings.network,
+            )
+            .await;
+        let (container_id, address) = match started {
+            Ok(started) => started,
+            Err(err) => {
+                if let Some(network) = &network {
+                    network.remove().await;
+                }
+                return Err(err.into());
+            }
+        };
         // Remove the temporary dockerfile from the container
 
-        let executor = RunningDockerExecutor {
+        let (container_ip, container_port) = match &address {
+            ServiceAddress::Tcp { ip, port } => (*ip, port.clone()),
+            ServiceAddress::Unix(_) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), String::new()),
+        };
+        #[allow(deprecated)]
+        let mut executor = RunningDockerExecutor {
             container_id,
+            image: image_name,
             docker,
+            service_address: address,
             container_port,
             container_ip,
             env_clear: builder.env_clear,
@@ -173,49 +435,64 @@ impl RunningDockerExecutor {
             cancel_token: Arc::new(CancellationToken::new()),
             default_timeout: builder.default_timeout,
             workdir: builder.workdir.clone(),
+            memory_limit: builder.resource_limits.memory,
+            context_path: builder.context_path.clone(),
+            sync_baseline: None,
+            _socket_dir: socket_dir,
+            network,
         };
 
-        if let Some(tmp_dockerfile_name) = tmp_dockerfile_name {
-            let mut removal_targets = vec![tmp_dockerfile_name.clone()];
-
-            if executor.workdir.is_absolute() {
-                removal_targets.push(
-                    executor
-                        .workdir
-                        .join(&tmp_dockerfile_name)
-                        .display()
-                        .to_string(),
-                );
-            }
+        // The temporary dockerfile and copied build contexts are part of the build context, they
+        // should not linger in the workdir
+        let mut leftovers = Vec::new();
+        leftovers.extend(tmp_dockerfile_name);
+        if builder
+            .build_contexts
+            .values()
+            .any(|context| matches!(context, BuildContext::Path(_)))
+        {
+            leftovers.push(BUILD_CONTEXTS_DIR.to_string());
+        }
 
+        if !leftovers.is_empty() {
             let default_workdir = Path::new("/app");
-            if executor.workdir != default_workdir {
-                removal_targets.push(
-                    default_workdir
-                        .join(&tmp_dockerfile_name)
-                        .display()
-                        .to_string(),
-                );
+            let mut removal_targets = Vec::new();
+
+            for leftover in &leftovers {
+                removal_targets.push(leftover.clone());
+
+                if executor.workdir.is_absolute() {
+                    removal_targets.push(executor.workdir.join(leftover).display().to_string());
+                }
+
+                if executor.workdir != default_workdir {
+                    removal_targets.push(default_workdir.join(leftover).display().to_string());
+                }
             }
 
             removal_targets.sort();
             removal_targets.dedup();
 
-            let removal_args = removal_targets
-                .iter()
-                .map(|target| format!("{target:?}"))
-                .collect::<Vec<_>>()
-                .join(" ");
-
-            let removal_cmd = format!("rm -f -- {removal_args}");
+            let removal_args = ["rm", "-rf", "--"]
+                .into_iter()
+                .map(String::from)
+                .chain(removal_targets)
+                .collect::<Vec<_>>();
 
             executor
-                .exec_shell(&removal_cmd, Path::new("/"), executor.default_timeout)
+                .exec_args(&removal_args, Path::new("/"))
                 .await
-                .context("failed to remove temporary dockerfile")
+                .context("failed to remove temporary build files")
                 .map_err(DockerExecutorError::Start)?;
         }
 
+        // The baseline is what actually ended up in the container, files that were never copied
+        // in are not part of it
+        if builder.workspace_sync {
+            let baseline = executor.as_workspace_sync().container_manifest().await?;
+            executor.sync_baseline = Some(Arc::new(baseline));
+        }
+
         Ok(executor)
     }
 
@@ -333,18 +610,20 @@ impl RunningDockerExecutor {
         cmd.timeout_duration().copied().or(self.default_timeout)
     }
 
-    async fn exec_shell(
+    pub(crate) async fn exec_shell(
         &self,
         cmd: &str,
         workdir: &Path,
         timeout: Option<Duration>,
     ) -> Result<CommandOutput, CommandError> {
-        let mut client = ShellExecutorClient::connect(format!(
-            "http://{}:{}",
-            self.container_ip, self.container_port
-        ))
-        .await
-        .map_err(anyhow::Error::from)?;
+        let mut client = match self.service_address.connect().await {
+            Ok(channel) => ShellExecutorClient::new(channel),
+            Err(err) => {
+                return Err(self
+                    .out_of_memory_or(CommandError::ExecutorError(err.into()))
+                    .await);
+            }
+        };
 
         let timeout_ms = timeout.map(duration_to_millis);
         tracing::debug!(?timeout_ms, "sending shell request with timeout");
@@ -379,7 +658,10 @@ impl RunningDockerExecutor {
                     return Err(CommandError::ExecutorError(status.into()));
                 }
 
-                return Err(CommandError::ExecutorError(status.into()));
+                // The service is gone if the container was killed
+                return Err(self
+                    .out_of_memory_or(CommandError::ExecutorError(status.into()))
+                    .await);
             }
         };
 
@@ -395,11 +677,94 @@ impl RunningDockerExecutor {
 
         if exit_code == 0 {
             Ok(output)
+        } else {
+            Err(self
+                .out_of_memory_or(CommandError::NonZeroExit(output))
+                .await)
+        }
+    }
+
+    /// Runs a program in the container with docker exec instead of the service. There is no
+    /// shell, so the arguments are passed to the program as they are.
+    pub(crate) async fn exec_args(
+        &self,
+        args: &[String],
+        workdir: &Path,
+    ) -> Result<CommandOutput, CommandError> {
+        let exec = self
+            .docker
+            .create_exec(
+                &self.container_id,
+                ExecConfig {
+                    cmd: Some(args.to_vec()),
+                    working_dir: Some(workdir.display().to_string()),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|err| CommandError::ExecutorError(err.into()))?;
+
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let started = self
+            .docker
+            .start_exec(&exec.id, None)
+            .await
+            .map_err(|err| CommandError::ExecutorError(err.into()))?;
+        if let StartExecResults::Attached { mut output, .. } = started {
+            while let Some(log) = output.next().await {
+                match log.map_err(|err| CommandError::ExecutorError(err.into()))? {
+                    LogOutput::StdErr { message } => {
+                        stderr.push_str(&String::from_utf8_lossy(&message));
+                    }
+                    LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                        stdout.push_str(&String::from_utf8_lossy(&message));
+                    }
+                    LogOutput::StdIn { .. } => {}
+                }
+            }
+        }
+
+        let exit_code = self
+            .docker
+            .inspect_exec(&exec.id)
+            .await
+            .map_err(|err| CommandError::ExecutorError(err.into()))?
+            .exit_code;
+        let output =
+            CommandOutput::from_parts(stdout.trim().to_string(), stderr.trim().to_string());
+
+        if exit_code == Some(0) {
+            Ok(output)
         } else {
             Err(CommandError::NonZeroExit(output))
         }
     }
 
+    /// Reports that the container ran out of memory instead of the error it caused, if it has a
+    /// memory limit and docker killed a process for exceeding it
+    async fn out_of_memory_or(&self, err: CommandError) -> CommandError {
+        let Some(memory_limit) = self.memory_limit else {
+            return err;
+        };
+
+        match self.container_state().await {
+            Ok(state) if state.oom_killed == Some(true) => {
+                tracing::error!(memory_limit, "Container ran out of memory");
+                CommandError::ExecutorError(
+                    DockerExecutorError::OutOfMemory {
+                        container_id: self.container_id.clone(),
+                        memory_limit,
+                    }
+                    .into(),
+                )
+            }
+            _ => err,
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     async fn exec_read_file(
         &self,
@@ -471,21 +836,29 @@ impl RunningDockerExecutor {
             "Stopping container {container_id}",
             container_id = container_id
         );
-        docker
+        let killed = docker
             .kill_container(
                 &container_id,
                 Some(KillContainerOptions {
                     signal: "SIGTERM".to_string(),
                 }),
             )
-            .await?;
+            .await;
+        match killed {
+            // Containers that are kept when they stop may have stopped already
+            Ok(())
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 409, ..
+            }) => {}
+            Err(err) => return Err(err.into()),
+        }
 
         tracing::debug!(
             "Removing container {container_id}",
             container_id = container_id
         );
 
-        docker
+        let removed = docker
             .remove_container(
                 &container_id,
                 Some(RemoveContainerOptions {
@@ -494,12 +867,138 @@ impl RunningDockerExecutor {
                     ..Default::default()
                 }),
             )
-            .await?;
+            .await;
+
+        // The network can only be removed once nothing is connected to it
+        if let Some(network) = &self.network {
+            network.remove().await;
+        }
 
+        removed?;
         Ok(())
     }
 }
 
+/// A network created for a single executor
+#[derive(Debug)]
+struct ExecutorNetwork {
+    docker: Arc<Client>,
+    name: String,
+    /// The container the executor runs in, if it joined the network to reach the service
+    joined: Option<String>,
+}
+
+impl ExecutorNetwork {
+    /// Creates an internal network, and connects the container of the executor to it if there
+    /// is one
+    async fn create(
+        docker: Arc<Client>,
+        container_uuid: &Uuid,
+        labels: &HashMap<String, String>,
+    ) -> Result<Self, DockerExecutorError> {
+        let name = format!("swiftide-{container_uuid}");
+        tracing::info!(network = name, "Creating internal network");
+        docker
+            .create_network(NetworkCreateRequest {
+                name: name.clone(),
+                internal: Some(true),
+                labels: Some(labels.clone()),
+                ..Default::default()
+            })
+            .await?;
+
+        let mut network = Self {
+            docker,
+            name,
+            joined: None,
+        };
+
+        if let Some(id) = own_container(&network.docker).await.and_then(|me| me.id) {
+            tracing::info!(
+                network = network.name,
+                container = id,
+                "Joining internal network"
+            );
+            let connected = network
+                .docker
+                .connect_network(
+                    &network.name,
+                    NetworkConnectRequest {
+                        container: id.clone(),
+                        ..Default::default()
+                    },
+                )
+                .await;
+            if let Err(err) = connected {
+                network.remove().await;
+                return Err(err.into());
+            }
+            network.joined = Some(id);
+        }
+
+        Ok(network)
+    }
+
+    /// Disconnects the executor and removes the network, logging any errors
+    async fn remove(&self) {
+        if let Some(container) = &self.joined
+            && let Err(err) = self
+                .docker
+                .disconnect_network(
+                    &self.name,
+                    NetworkDisconnectRequest {
+                        container: container.clone(),
+                        force: Some(true),
+                    },
+                )
+                .await
+        {
+            tracing::warn!(?err, network = self.name, "Failed to leave network");
+        }
+
+        if let Err(err) = self.docker.remove_network(&self.name).await {
+            tracing::warn!(?err, network = self.name, "Failed to remove network");
+        }
+    }
+}
+
+/// Docker Desktop runs containers in a vm, so the host cannot reach their ips or share sockets
+/// with them
+async fn is_docker_desktop(docker: &Client) -> bool {
+    docker
+        .info()
+        .await
+        .ok()
+        .and_then(|info| info.operating_system)
+        .is_some_and(|os| os.contains("Docker Desktop"))
+}
+
+/// Creates the directory for the socket of a container without a network, that only the owner
+/// can use. The service in the container hands the socket over to the owner.
+///
+/// Root in the container can use the directory as is. Other users must be numeric, the directory
+/// is given to them if that is not the user of the executor already.
+fn socket_dir_for(user: Option<&str>) -> Result<TempDir, DockerExecutorError> {
+    let dir = tempfile::tempdir()?;
+    fs_err::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))?;
+
+    let Some(user) = user.filter(|user| !matches!(*user, "root" | "0" | "0:0")) else {
+        return Ok(dir);
+    };
+
+    let (uid, gid) = user.split_once(':').unwrap_or((user, ""));
+    let uid = uid
+        .parse::<u32>()
+        .map_err(|_| DockerExecutorError::NonNumericUser(user.to_string()))?;
+    let gid = gid.parse::<u32>().ok();
+
+    if uid != 0 && uid != fs_err::metadata(dir.path())?.uid() {
+        std::os::unix::fs::chown(dir.path(), Some(uid), gid)?;
+    }
+
+    Ok(dir)
+}
+
 fn duration_to_millis(duration: Duration) -> u64 {
     let millis = duration.as_millis();
     if millis > u64::MAX as u128 {
@@ -509,6 +1008,161 @@ fn duration_to_millis(duration: Duration) -> u64 {
     }
 }
 
+/// Service images that were inspected already, by image id, with whether their binaries are
+/// static
+static STATIC_SERVICE_IMAGES: LazyLock<Mutex<HashMap<String, bool>>> =
+    LazyLock::new(Mutex::default);
+
+/// How much of a binary is read to find its program headers, which follow the ELF header
+const ELF_PROBE_LEN: u64 = 64 * 1024;
+
+/// Returns true if the service binaries in an image are static. The image is pulled if it does
+/// not exist, and the binaries are read from a container that is never started.
+async fn has_static_service_binaries(
+    docker: &Client,
+    image: &str,
+    labels: &HashMap<String, String>,
+) -> anyhow::Result<bool> {
+    let inspect = match docker.inspect_image(image).await {
+        Ok(inspect) => inspect,
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {
+            tracing::info!("Pulling service image {image}");
+            let mut pull = docker.create_image(
+                Some(CreateImageOptions {
+                    from_image: Some(image.to_string()),
+                    ..Default::default()
+                }),
+                None,
+                None,
+            );
+            while let Some(info) = pull.next().await {
+                info?;
+            }
+            docker.inspect_image(image).await?
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let image_id = inspect.id.context("service image has no id")?;
+
+    if let Some(is_static) = STATIC_SERVICE_IMAGES.lock().unwrap().get(&image_id) {
+        return Ok(*is_static);
+    }
+
+    // The command is never run, but images without one cannot be created otherwise
+    let container_id = docker
+        .create_container(
+            None::<CreateContainerOptions>,
+            ContainerCreateBody {
+                image: Some(image_id.clone()),
+                cmd: Some(vec!["/usr/bin/swiftide-docker-service".to_string()]),
+                labels: Some(labels.clone()),
+                ..Default::default()
+            },
+        )
+        .await?
+        .id;
+
+    let result = async {
+        let mut is_static = true;
+        for binary in SERVICE_BINARIES {
+            let stream = docker
+                .download_from_container(
+                    &container_id,
+                    Some(DownloadFromContainerOptions {
+                        path: format!("/usr/bin/{binary}"),
+                    }),
+                )
+                .map(|chunk| chunk.map_err(std::io::Error::other));
+            let mut archive = Archive::new(StreamReader::new(Box::pin(stream)));
+            let mut entries = archive.entries()?;
+            let entry = entries
+                .next()
+                .await
+                .with_context(|| format!("{binary} is missing from {image}"))??;
+
+            let mut start = Vec::new();
+            entry.take(ELF_PROBE_LEN).read_to_end(&mut start).await?;
+            is_static &= elf::is_static_elf(&start);
+        }
+        anyhow::Ok(is_static)
+    }
+    .await;
+
+    if let Err(err) = docker
+        .remove_container(
+            &container_id,
+            Some(RemoveContainerOptions {
+                force: true,
+                v: true,
+                ..Default::default()
+            }),
+        )
+        .await
+    {
+        tracing::warn!(error = ?err, "Failed to remove container {container_id}");
+    }
+
+    let is_static = result?;
+    STATIC_SERVICE_IMAGES
+        .lock()
+        .unwrap()
+        .insert(image_id, is_static);
+    Ok(is_static)
+}
+
+/// Name of the directory that holds the files of an image built by `build_files_image`
+const FILES_IMAGE_DIR: &str = "files";
+
+/// Builds an image of just the given files, with their destinations relative to the root of the
+/// image, reusing it if the files did not change
+async fn build_files_image(
+    image_builder: &ImageBuilder,
+    image_name: &str,
+    labels: &HashMap<String, String>,
+    files: &[ExtraPath],
+) -> Result<String, DockerExecutorError> {
+    // The files go in a directory next to the Dockerfile, so the Dockerfile is not copied
+    let empty_context = tempfile::tempdir().map_err(DockerfileError::TempFileError)?;
+    let mut dockerfile = tempfile::NamedTempFile::new().map_err(DockerfileError::TempFileError)?;
+    writeln!(dockerfile, "FROM scratch\nCOPY {FILES_IMAGE_DIR}/ /")
+        .map_err(DockerfileError::TempFileError)?;
+
+    let dockerfile_name = ".dockerfile.files";
+    let mut context_builder = ContextBuilder::from_path(empty_context.path(), dockerfile.path())?;
+    context_builder.with_dockerfile_name(dockerfile_name);
+    for file in files {
+        context_builder.with_extra_path(
+            &file.host_path,
+            Path::new(FILES_IMAGE_DIR).join(&file.destination),
+        );
+    }
+
+    let options = BuildOptions::default();
+    let tag = options.image_tag(&context_builder.content_hash().await?);
+    let image_name_with_tag = format!("{image_name}:{tag}");
+
+    if image_builder.image_exists(&image_name_with_tag).await? {
+        image_builder.reuse_image(&image_name_with_tag);
+        return Ok(image_name_with_tag);
+    }
+
+    tracing::info!("Building image {image_name_with_tag}");
+    image_builder
+        .build_image(
+            context_builder.stream_tar(),
+            dockerfile_name,
+            image_name,
+            &tag,
+            labels,
+            &options,
+        )
+        .await?;
+
+    Ok(image_name_with_tag)
+}
+
 impl Drop for RunningDockerExecutor {
     fn drop(&mut self) {
         if self.dropped {
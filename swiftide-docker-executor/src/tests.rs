@@ -1,11 +1,21 @@
 use std::{path::Path, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use bollard::{models::ContainerStateStatusEnum, query_parameters::InspectContainerOptions};
+use bollard::{
+    models::{ContainerStateStatusEnum, NetworkCreateRequest},
+    query_parameters::{InspectContainerOptions, InspectNetworkOptions},
+};
 use swiftide_core::{Command, CommandError, Loader as _, ToolExecutor as _, indexing::TextNode};
 use tokio_stream::StreamExt as _;
 
-use crate::{DockerExecutor, DockerExecutorError};
+use crate::{
+    BuildContext, ContextError, DockerExecutor, DockerExecutorError, ImageBuildError, NetworkMode,
+    RunningDockerExecutor, SERVICE_IMAGE, ServiceAddress,
+    build_events::BuildEvent,
+    cleanup::{self, Cleanup},
+    file_loader::{CONTENT_HASH_METADATA_KEY, Chunking, DELETED_METADATA_KEY, LoadCursor},
+    image_archive,
+};
 
 // A much smaller busybox image for faster tests
 const TEST_DOCKERFILE: &str = "Dockerfile.tests";
@@ -630,6 +640,334 @@ async fn test_custom_dockerfile() {
     assert_eq!(output.stdout, "hello");
 }
 
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_reuses_image_for_unchanged_context() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::copy("Dockerfile.tests", context_path.path().join("Dockerfile")).unwrap();
+    std::fs::write(context_path.path().join("hello.txt"), "hello").unwrap();
+
+    let image_of = |executor: RunningDockerExecutor| async move {
+        executor
+            .docker
+            .inspect_container(&executor.container_id, None::<InspectContainerOptions>)
+            .await
+            .unwrap()
+            .config
+            .unwrap()
+            .image
+            .unwrap()
+    };
+    let start = || {
+        DockerExecutor::default()
+            .with_context_path(context_path.path())
+            .with_image_name("test-image-cache")
+            .to_owned()
+            .start()
+    };
+
+    let first = image_of(start().await.unwrap()).await;
+    let second = image_of(start().await.unwrap()).await;
+    assert_eq!(first, second);
+
+    std::fs::write(context_path.path().join("hello.txt"), "changed").unwrap();
+    let changed = image_of(start().await.unwrap()).await;
+    assert_ne!(first, changed);
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_labels_and_cleanup() {
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("test-labels")
+        .with_orphan_sweep(true)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let container = executor
+        .docker
+        .inspect_container(&executor.container_id, None::<InspectContainerOptions>)
+        .await
+        .unwrap();
+    let labels = container.config.unwrap().labels.unwrap();
+    assert_eq!(labels[cleanup::MANAGED_LABEL], "true");
+    assert_eq!(labels[cleanup::PID_LABEL], std::process::id().to_string());
+
+    assert!(!labels.contains_key(cleanup::RETAINED_LABEL));
+
+    // Containers of running processes are not orphans
+    let report = Cleanup::default().remove_images(false).run().await.unwrap();
+    assert!(!report.containers.contains(&executor.container_id));
+    assert!(executor.is_running().await);
+
+    // Retained containers are labeled, so they are never swept as orphans
+    let retained = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("test-labels")
+        .retain_on_drop(true)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+    let labels = retained
+        .docker
+        .inspect_container(&retained.container_id, None::<InspectContainerOptions>)
+        .await
+        .unwrap()
+        .config
+        .unwrap()
+        .labels
+        .unwrap();
+    assert_eq!(labels[cleanup::RETAINED_LABEL], "true");
+    retained.shutdown().await.unwrap();
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_build_args_and_target() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(
+        context_path.path().join("Dockerfile"),
+        indoc::indoc! {r#"
+            FROM debian:bookworm-slim AS dev
+            ARG GREETING=hello
+            RUN echo "$GREETING from dev" > /greeting
+
+            FROM debian:bookworm-slim AS release
+            RUN echo "release" > /greeting
+        "#},
+    )
+    .unwrap();
+
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-build-options")
+        .with_build_arg("GREETING", "hi")
+        .with_build_target("dev")
+        .with_image_label("com.example.purpose", "tests")
+        .with_build_shm_size(64 * 1024 * 1024)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let output = executor
+        .exec_cmd(&Command::shell("cat /greeting"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "hi from dev");
+
+    let container = executor
+        .docker
+        .inspect_container(&executor.container_id, None::<InspectContainerOptions>)
+        .await
+        .unwrap();
+    let image = executor
+        .docker
+        .inspect_image(&container.image.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        image.config.unwrap().labels.unwrap()["com.example.purpose"],
+        "tests"
+    );
+}
+
+#[cfg(feature = "buildkit")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_build_secrets() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(
+        context_path.path().join("Dockerfile"),
+        indoc::indoc! {r#"
+            FROM debian:bookworm-slim
+            RUN --mount=type=secret,id=token cat /run/secrets/token > /token
+            RUN --mount=type=secret,id=path,env=SECRET_PATH echo -n "$SECRET_PATH" > /path
+        "#},
+    )
+    .unwrap();
+
+    // Keep the secret out of the context
+    let secrets = tempfile::tempdir().unwrap();
+    std::fs::write(secrets.path().join("token"), "s3cr3t").unwrap();
+    // Setting a variable is not safe with other tests running, use one that is always there
+    let path = std::env::var("PATH").unwrap();
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-build-secrets")
+        .with_build_secret_file("token", secrets.path().join("token"))
+        .with_build_secret_env("path", "PATH")
+        .with_no_cache(true)
+        .with_build_events(sender)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let output = executor
+        .exec_cmd(&Command::shell("cat /token /path"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, format!("s3cr3t{path}"));
+
+    // Builds over a session report their steps like other builds
+    let mut steps = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        if let BuildEvent::StepStarted { name, .. } = event {
+            steps.push(name);
+        }
+    }
+    assert!(
+        steps
+            .iter()
+            .any(|name| name.contains("RUN --mount=type=secret,id=token")),
+        "{steps:?}"
+    );
+}
+
+#[cfg(feature = "buildkit")]
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_cancel_session_build() {
+    let context_path = tempfile::tempdir().unwrap();
+    let secrets = tempfile::tempdir().unwrap();
+    std::fs::write(secrets.path().join("token"), "s3cr3t").unwrap();
+    let run = uuid::Uuid::new_v4().to_string();
+
+    let cancel_token = tokio_util::sync::CancellationToken::new();
+    tokio::spawn({
+        let cancel_token = cancel_token.clone();
+        async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            cancel_token.cancel();
+        }
+    });
+    let err = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-cancel-session-build")
+        .with_dockerfile_contents(indoc::indoc! {r#"
+            FROM alpine:latest
+            RUN --mount=type=secret,id=token sleep 600
+        "#})
+        .with_build_secret_file("token", secrets.path().join("token"))
+        .with_image_label("swiftide-test-run", &run)
+        .with_no_cache(true)
+        .with_build_cancel_token(cancel_token)
+        .to_owned()
+        .start()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DockerExecutorError::ImageBuild(ImageBuildError::Cancelled)
+        ),
+        "{err:?}"
+    );
+
+    // The build stopped and was removed before the start failed
+    let docker = crate::client::Client::lazy_client().await.unwrap();
+    let socket_path = docker.socket_path.as_deref().unwrap();
+    let labels = std::collections::HashMap::from([("swiftide-test-run".to_string(), run)]);
+    let records = crate::buildkit_status::records(socket_path, &labels)
+        .await
+        .unwrap();
+    assert!(records.is_empty(), "{records:?}");
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_extra_context_sources() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(
+        context_path.path().join("Dockerfile"),
+        indoc::indoc! {r#"
+            FROM debian:bookworm-slim
+            COPY --from=fixtures /fixture.txt /fixtures/fixture.txt
+            COPY --from=base /etc/alpine-release /alpine-release
+            COPY shared/ /shared/
+            COPY generated.txt /generated.txt
+        "#},
+    )
+    .unwrap();
+
+    let fixtures = tempfile::tempdir().unwrap();
+    std::fs::write(fixtures.path().join("fixture.txt"), "fixture").unwrap();
+    let shared = tempfile::tempdir().unwrap();
+    std::fs::write(shared.path().join("shared.txt"), "shared").unwrap();
+
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-extra-context")
+        .with_extra_context_path(shared.path(), "shared")
+        .with_context_file("generated.txt", "generated", 0o644)
+        .with_build_context(
+            "fixtures",
+            BuildContext::Path(fixtures.path().to_path_buf()),
+        )
+        .with_build_context("base", BuildContext::Image("alpine:3.20".into()))
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let output = executor
+        .exec_cmd(&Command::shell(
+            "cat /fixtures/fixture.txt /shared/shared.txt /generated.txt",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "fixturesharedgenerated");
+
+    let output = executor
+        .exec_cmd(&Command::shell("cat /alpine-release"))
+        .await
+        .unwrap();
+    assert!(output.stdout.starts_with("3.20"));
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_executables_stay_executable() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(
+        context_path.path().join("Dockerfile"),
+        indoc::indoc! {r#"
+            FROM debian:bookworm-slim
+            COPY . /app
+            WORKDIR /app
+        "#},
+    )
+    .unwrap();
+    std::fs::create_dir(context_path.path().join("bin")).unwrap();
+    let script = context_path.path().join("bin/setup");
+    std::fs::write(&script, "#!/bin/sh\necho setup done").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-executables")
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let output = executor
+        .exec_cmd(&Command::shell("./bin/setup"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "setup done");
+
+    let output = executor
+        .exec_cmd(&Command::shell("stat -c '%a %u' bin/setup Dockerfile"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "755 0\n644 0");
+}
+
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
 async fn test_nullifies_cmd() {
     let context_path = tempfile::tempdir().unwrap();
@@ -707,6 +1045,122 @@ async fn test_container_state() {
     assert!(executor.is_running().await);
 }
 
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_runs_on_different_base_images() {
+    for (base, image_name) in [
+        ("alpine:3.20", "test-base-alpine"),
+        ("busybox:1.36", "test-base-busybox"),
+        ("debian:bookworm-slim", "test-base-debian"),
+        ("cgr.dev/chainguard/wolfi-base", "test-base-wolfi"),
+    ] {
+        let context_path = tempfile::tempdir().unwrap();
+        std::fs::write(
+            context_path.path().join("Dockerfile"),
+            format!("FROM {base}"),
+        )
+        .unwrap();
+
+        let executor = DockerExecutor::default()
+            .with_context_path(context_path.path())
+            .with_image_name(image_name)
+            .to_owned()
+            .start()
+            .await
+            .unwrap_or_else(|err| panic!("{base}: {err:#}"));
+
+        let output = executor
+            .exec_cmd(&Command::shell(
+                "echo hello && rg --version && fd --version",
+            ))
+            .await
+            .unwrap_or_else(|err| panic!("{base}: {err:#}"));
+        assert!(output.stdout.starts_with("hello\nripgrep"), "{base}");
+    }
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_dockerfile_contents() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(context_path.path().join("main.rs"), "fn main() {}").unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM debian:bookworm-slim
+        COPY . /app
+        WORKDIR /app
+        CMD ["this", "is", "removed"]
+    "#};
+
+    let mut builder = DockerExecutor::default();
+    builder
+        .with_context_path(context_path.path())
+        .with_image_name("test-dockerfile-contents")
+        .with_dockerfile_contents(contents);
+
+    let listed = builder
+        .dry_run_context()
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.path.display().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(listed, ["Dockerfile", "main.rs"]);
+
+    let executor = builder.start().await.unwrap();
+    let output = executor.exec_cmd(&Command::shell("ls -A")).await.unwrap();
+    assert_eq!(output.stdout, "main.rs");
+
+    // Nothing is written to the context path
+    let files = std::fs::read_dir(context_path.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(files, ["main.rs"]);
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_service_sources() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::write(
+        context_path.path().join("Dockerfile"),
+        "FROM debian:bookworm-slim",
+    )
+    .unwrap();
+
+    // An explicit image works like the default one
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-service-image")
+        .with_service_image(SERVICE_IMAGE)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+    let output = executor
+        .exec_cmd(&Command::shell("which rg fd"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "/usr/bin/rg\n/usr/bin/fd");
+
+    // Binaries on the host must all be there
+    let binaries = tempfile::tempdir().unwrap();
+    std::fs::write(binaries.path().join("swiftide-docker-service"), "").unwrap();
+    let err = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-service-binaries")
+        .with_service_binaries(binaries.path())
+        .to_owned()
+        .start()
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(
+            &err,
+            DockerExecutorError::Context(ContextError::MissingServiceBinary(path))
+                if path.ends_with("rg")
+        ),
+        "{err:#}"
+    );
+}
+
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
 async fn test_invalid_dockerfile() {
     let context_path = tempfile::tempdir().unwrap();
@@ -815,44 +1269,249 @@ async fn test_existing_image_no_context() {
         .await
         .unwrap();
 
-    assert!(executor.is_running().await);
+    assert!(executor.is_running().await);
+
+    let output = executor.exec_cmd(&Command::shell("ls")).await;
+    // assert_eq!(output.stdout, "hello");
+    println!("--- container logs ---");
+    for log in executor.logs().await.unwrap() {
+        println!("{log}");
+    }
+    output.unwrap();
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_loading_files() {
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("tests")
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    assert!(executor.is_running().await, "Container should be running");
+
+    let loader = executor.into_file_loader("src/".to_string(), vec!["rs".to_string()]);
+    let loader_stream = loader.into_stream();
+
+    // Collect the results from the stream and assert there's a bunch of rust files
+    let files = loader_stream
+        .collect::<Result<Vec<TextNode>>>()
+        .await
+        .unwrap();
+
+    assert!(!files.is_empty(), "No files loaded");
+    assert!(
+        files.iter().any(|node| node.path.ends_with("tests.rs")),
+        "Expected to find tests.rs in loaded files"
+    );
+
+    let node = files
+        .iter()
+        .find(|node| node.path.ends_with("lib.rs"))
+        .unwrap();
+    assert_eq!(node.original_size, node.chunk.len());
+    assert_eq!(
+        node.metadata.get("language").and_then(|v| v.as_str()),
+        Some("rust")
+    );
+    assert!(node.metadata.get("modified_at").is_some());
+    assert!(node.metadata.get("permissions").is_some());
+    assert!(node.metadata.get(CONTENT_HASH_METADATA_KEY).is_some());
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_loading_files_with_filters() {
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("tests")
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let loader = executor
+        .as_file_loader(".", Vec::<String>::new())
+        .with_include_globs(["src/**/*.rs", "Cargo.toml"])
+        .with_exclude_globs(["src/tests.rs"])
+        .with_max_file_size(512 * 1024)
+        .to_owned();
+
+    let files = loader
+        .into_stream()
+        .collect::<Result<Vec<TextNode>>>()
+        .await
+        .unwrap();
+
+    assert!(files.iter().any(|node| node.path.ends_with("lib.rs")));
+    assert!(files.iter().any(|node| node.path.ends_with("Cargo.toml")));
+    assert!(
+        !files.iter().any(|node| node.path.ends_with("tests.rs")),
+        "Excluded file was loaded"
+    );
+    assert!(
+        files
+            .iter()
+            .all(|node| node.path.extension().is_some_and(|ext| ext == "rs")
+                || node.path.ends_with("Cargo.toml")),
+        "Loaded files outside of the include globs"
+    );
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_loading_files_chunked() {
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("tests")
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let loader = executor
+        .as_file_loader(".", Vec::<String>::new())
+        .with_include_globs(["src/lib.rs"])
+        .with_chunking(Chunking::Lines {
+            max_lines: 10,
+            overlap_lines: 0,
+        })
+        .to_owned();
+
+    let chunks = loader
+        .into_stream()
+        .collect::<Result<Vec<TextNode>>>()
+        .await
+        .unwrap();
+
+    let contents = std::fs::read_to_string("src/lib.rs").unwrap();
+    assert!(chunks.len() > 1, "Expected lib.rs to be split into chunks");
+    assert_eq!(
+        chunks
+            .iter()
+            .map(|node| node.chunk.as_str())
+            .collect::<String>(),
+        contents
+    );
+
+    for node in &chunks {
+        assert!(node.chunk.lines().count() <= 10);
+        assert_eq!(
+            &contents[node.offset..node.offset + node.chunk.len()],
+            node.chunk
+        );
+    }
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_loading_files_incrementally() {
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE)
+        .with_context_path(".")
+        .with_image_name("tests")
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let loader = executor.as_file_loader("src/", vec!["rs"]);
+    let next_cursor = loader.next_cursor();
+    let files = loader
+        .into_stream()
+        .collect::<Result<Vec<TextNode>>>()
+        .await
+        .unwrap();
+    assert!(!files.is_empty(), "No files loaded");
+
+    let cursor = next_cursor.get().expect("cursor after a completed load");
+    assert_eq!(cursor.manifest.len(), files.len());
+
+    executor
+        .exec_cmd(&Command::shell(
+            "echo 'fn added() {}' > src/added.rs && rm src/client.rs",
+        ))
+        .await
+        .unwrap();
+
+    let changes = executor
+        .as_file_loader("src/", vec!["rs"])
+        .with_cursor(cursor)
+        .to_owned()
+        .into_stream()
+        .collect::<Result<Vec<TextNode>>>()
+        .await
+        .unwrap();
 
-    let output = executor.exec_cmd(&Command::shell("ls")).await;
-    // assert_eq!(output.stdout, "hello");
-    println!("--- container logs ---");
-    for log in executor.logs().await.unwrap() {
-        println!("{log}");
-    }
-    output.unwrap();
+    assert_eq!(changes.len(), 2, "Expected only changes, got {changes:?}");
+    assert!(
+        changes.iter().any(|node| node.path.ends_with("added.rs")
+            && node.metadata.get(DELETED_METADATA_KEY).is_none())
+    );
+    assert!(changes.iter().any(|node| node.path.ends_with("client.rs")
+        && node.metadata.get(DELETED_METADATA_KEY).is_some()));
 }
 
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
-async fn test_loading_files() {
+async fn test_loading_files_that_failed_again() {
+    // Root can read any file, so run as a user that is denied
     let executor = DockerExecutor::default()
         .with_dockerfile(TEST_DOCKERFILE)
         .with_context_path(".")
         .with_image_name("tests")
+        .with_user("1000")
         .to_owned()
         .start()
         .await
         .unwrap();
+    let load = |cursor: Option<LoadCursor>| {
+        let mut loader = executor.as_file_loader("/tmp/load", vec!["rs"]);
+        if let Some(cursor) = cursor {
+            loader.with_cursor(cursor);
+        }
+        let next_cursor = loader.next_cursor();
+        async move {
+            let nodes = loader.into_stream().collect::<Vec<_>>().await;
+            (
+                nodes,
+                next_cursor.get().expect("cursor after a completed load"),
+            )
+        }
+    };
 
-    assert!(executor.is_running().await, "Container should be running");
-
-    let loader = executor.into_file_loader("src/".to_string(), vec!["rs".to_string()]);
-    let loader_stream = loader.into_stream();
+    executor
+        .exec_cmd(&Command::shell(
+            "mkdir -p /tmp/load && echo 'fn a() {}' > /tmp/load/a.rs",
+        ))
+        .await
+        .unwrap();
+    let (nodes, cursor) = load(None).await;
+    assert_eq!(nodes.len(), 1);
 
-    // Collect the results from the stream and assert there's a bunch of rust files
-    let files = loader_stream
-        .collect::<Result<Vec<TextNode>>>()
+    executor
+        .exec_cmd(&Command::shell(
+            "echo 'fn b() {}' > /tmp/load/a.rs && chmod 000 /tmp/load/a.rs",
+        ))
         .await
         .unwrap();
+    let (nodes, cursor) = load(Some(cursor)).await;
+    assert_eq!(nodes.len(), 1);
+    assert!(nodes[0].is_err(), "{nodes:?}");
 
-    assert!(!files.is_empty(), "No files loaded");
-    assert!(
-        files.iter().any(|node| node.path.ends_with("tests.rs")),
-        "Expected to find tests.rs in loaded files"
-    );
+    // Making the file readable does not change it, it is read again because it failed
+    executor
+        .exec_cmd(&Command::shell("chmod 644 /tmp/load/a.rs"))
+        .await
+        .unwrap();
+    let (nodes, _) = load(Some(cursor)).await;
+    let nodes = nodes
+        .into_iter()
+        .collect::<Result<Vec<TextNode>>>()
+        .unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].chunk.trim(), "fn b() {}");
 }
 
 #[test_log::test(tokio::test(flavor = "multi_thread"))]
@@ -1166,3 +1825,572 @@ async fn test_background_shell_command_returns_immediately() {
         .unwrap();
     assert_eq!(echo.stdout, "done");
 }
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_workspace_sync_pull_and_push() {
+    let context_path = tempfile::tempdir().unwrap();
+    std::fs::copy(TEST_DOCKERFILE, context_path.path().join("Dockerfile")).unwrap();
+    std::fs::write(context_path.path().join("existing.txt"), "original").unwrap();
+    std::fs::write(context_path.path().join("conflict.txt"), "original").unwrap();
+
+    let executor = DockerExecutor::default()
+        .with_dockerfile("Dockerfile")
+        .with_context_path(context_path.path())
+        .with_image_name("test-workspace-sync")
+        .with_workspace_sync(true)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    executor
+        .exec_cmd(&Command::shell(
+            "mkdir -p nested && echo created > nested/created.txt && echo container > conflict.txt",
+        ))
+        .await
+        .unwrap();
+    std::fs::write(context_path.path().join("conflict.txt"), "host").unwrap();
+
+    let mut sync = executor.as_workspace_sync();
+    let report = sync.pull().await.unwrap();
+
+    assert_eq!(report.written, vec![Path::new("nested/created.txt")]);
+    assert_eq!(report.conflicts, vec![Path::new("conflict.txt")]);
+    assert_eq!(
+        std::fs::read_to_string(context_path.path().join("nested/created.txt")).unwrap(),
+        "created\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(context_path.path().join("conflict.txt")).unwrap(),
+        "host"
+    );
+
+    std::fs::write(context_path.path().join("existing.txt"), "from host").unwrap();
+    std::fs::remove_file(context_path.path().join("nested/created.txt")).unwrap();
+
+    let report = sync.push().await.unwrap();
+    assert_eq!(report.written, vec![Path::new("existing.txt")]);
+    assert_eq!(report.deleted, vec![Path::new("nested/created.txt")]);
+
+    let output = executor
+        .exec_cmd(&Command::shell("cat existing.txt"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "from host");
+
+    let output = executor
+        .exec_cmd(&Command::shell("ls nested"))
+        .await
+        .unwrap();
+    assert!(!output.stdout.contains("created.txt"));
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_workspace_sync_pull_keeps_files_not_in_container() {
+    let context_path = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+        WORKDIR /app
+        COPY copied.txt ./
+    "#};
+    std::fs::write(context_path.path().join(".dockerignore"), "ignored.txt\n").unwrap();
+    std::fs::write(context_path.path().join("ignored.txt"), "ignored").unwrap();
+    std::fs::write(context_path.path().join("copied.txt"), "copied").unwrap();
+    std::fs::write(context_path.path().join("not_copied.txt"), "not copied").unwrap();
+
+    let executor = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-workspace-sync-ignored")
+        .with_dockerfile_contents(contents)
+        .with_workspace_sync(true)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    executor
+        .exec_cmd(&Command::shell("echo changed > copied.txt"))
+        .await
+        .unwrap();
+
+    let report = executor.as_workspace_sync().pull().await.unwrap();
+    assert_eq!(report.written, vec![Path::new("copied.txt")]);
+    assert!(report.deleted.is_empty());
+
+    for (file, contents) in [("ignored.txt", "ignored"), ("not_copied.txt", "not copied")] {
+        assert_eq!(
+            std::fs::read_to_string(context_path.path().join(file)).unwrap(),
+            contents
+        );
+    }
+
+    // Without a baseline, files that only exist on the host are left alone as well
+    let report = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-workspace-sync-ignored")
+        .with_dockerfile_contents(contents)
+        .to_owned()
+        .start()
+        .await
+        .unwrap()
+        .as_workspace_sync()
+        .pull()
+        .await
+        .unwrap();
+    assert!(report.deleted.is_empty());
+    assert!(context_path.path().join("not_copied.txt").is_file());
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_build_events() {
+    let context_path = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+        RUN echo "hello from the build"
+        WORKDIR /app
+    "#};
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut builder = DockerExecutor::default();
+    builder
+        .with_context_path(context_path.path())
+        .with_image_name("test-build-events")
+        .with_dockerfile_contents(contents)
+        .with_no_cache(true)
+        .with_build_events(sender);
+    let executor = builder.clone().start().await.unwrap();
+    drop(executor);
+
+    let mut events = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        events.push(event);
+    }
+    assert!(matches!(
+        events.first(),
+        Some(BuildEvent::Started { image }) if image.starts_with("test-build-events:")
+    ));
+    assert!(matches!(events.last(), Some(BuildEvent::Finished { .. })));
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, BuildEvent::StepStarted { .. }))
+    );
+    assert!(events.iter().any(
+        |event| matches!(event, BuildEvent::Log { line, .. } if line.contains("hello from the build"))
+    ));
+
+    // Starting again reuses the image
+    builder
+        .with_no_cache(false)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+    assert!(matches!(
+        receiver.try_recv(),
+        Ok(BuildEvent::Reused { image }) if image.starts_with("test-build-events:")
+    ));
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_build_failure_has_logs_and_line() {
+    let context_path = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+
+        RUN echo "building the app"
+        RUN echo "the reason it failed" && \
+            exit 3
+    "#};
+
+    let err = DockerExecutor::default()
+        .with_context_path(context_path.path())
+        .with_image_name("test-build-failure")
+        .with_dockerfile_contents(contents)
+        .to_owned()
+        .start()
+        .await
+        .unwrap_err();
+
+    let DockerExecutorError::ImageBuild(ImageBuildError::BuildError(failure)) = err else {
+        panic!("expected a build error, got {err:?}");
+    };
+    // Docker keeps the whitespace of continuations in the step
+    let step = failure.step.as_deref().unwrap();
+    assert!(step.starts_with(r#"RUN echo "the reason it failed""#));
+    assert!(step.ends_with("exit 3"));
+    assert_eq!(failure.line, Some(4));
+    assert!(
+        failure
+            .log_tail
+            .iter()
+            .any(|line| line.contains("the reason it failed"))
+    );
+    assert!(failure.to_string().contains("at line 4 of the Dockerfile"));
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_image_cache_dir() {
+    let context_path = tempfile::tempdir().unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+        RUN echo "cached" > /cached.txt
+    "#};
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut builder = DockerExecutor::default();
+    builder
+        .with_context_path(context_path.path())
+        .with_image_name("test-image-cache-dir")
+        .with_dockerfile_contents(contents)
+        .with_image_cache_dir(cache_dir.path().join("images"))
+        .with_build_events(sender);
+
+    // The built image is saved to the cache directory
+    let executor = builder.clone().start().await.unwrap();
+    let archive = cache_dir
+        .path()
+        .join("images")
+        .join(image_archive::archive_file_name(&executor.image));
+    assert!(archive.is_file());
+
+    // Without the image, it is loaded from the archive instead of built
+    let image = executor.image.clone();
+    drop(executor);
+    let docker = crate::client::Client::lazy_client().await.unwrap();
+    docker
+        .remove_image(
+            &image,
+            Some(bollard::query_parameters::RemoveImageOptions {
+                force: true,
+                ..Default::default()
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+    while receiver.try_recv().is_ok() {}
+
+    let executor = builder.to_owned().start().await.unwrap();
+    assert_eq!(executor.image, image);
+    assert!(matches!(
+        receiver.try_recv(),
+        Ok(BuildEvent::Loaded { image: loaded, path }) if loaded == image && path == archive
+    ));
+    let output = executor
+        .exec_cmd(&Command::shell("cat /cached.txt"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "cached");
+
+    // Archives can be exported and imported by hand as well
+    let exported = image_archive::export_image(&image, &cache_dir.path().join("exported.tar"))
+        .await
+        .unwrap();
+    assert_eq!(exported.hash, image.rsplit_once(':').unwrap().1);
+    let imported = image_archive::import_image(&exported.path).await.unwrap();
+    assert_eq!(imported, [image]);
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_image_cache_dir_is_best_effort() {
+    let context_path = tempfile::tempdir().unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+        RUN echo "corrupt" > /cached.txt
+    "#};
+
+    let mut builder = DockerExecutor::default();
+    builder
+        .with_context_path(context_path.path())
+        .with_image_name("test-image-cache-best-effort")
+        .with_dockerfile_contents(contents)
+        .with_image_cache_dir(cache_dir.path());
+
+    let executor = builder.clone().start().await.unwrap();
+    let image = executor.image.clone();
+    let archive = cache_dir
+        .path()
+        .join(image_archive::archive_file_name(&image));
+    drop(executor);
+
+    // A broken archive is built instead, and replaced by a good one
+    std::fs::write(&archive, "not an archive").unwrap();
+    let docker = crate::client::Client::lazy_client().await.unwrap();
+    docker
+        .remove_image(
+            &image,
+            Some(bollard::query_parameters::RemoveImageOptions {
+                force: true,
+                ..Default::default()
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let executor = builder.to_owned().start().await.unwrap();
+    assert_eq!(executor.image, image);
+    assert!(std::fs::metadata(&archive).unwrap().len() > 100);
+
+    // Failing to save the archive does not fail the start
+    let not_a_dir = cache_dir.path().join("file");
+    std::fs::write(&not_a_dir, "").unwrap();
+    let executor = builder
+        .with_image_cache_dir(not_a_dir.join("images"))
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+    assert_eq!(executor.image, image);
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_build_timeout_and_cancellation() {
+    let context_path = tempfile::tempdir().unwrap();
+    let contents = indoc::indoc! {r#"
+        FROM alpine:latest
+        RUN sleep 600
+    "#};
+
+    let mut builder = DockerExecutor::default();
+    builder
+        .with_context_path(context_path.path())
+        .with_image_name("test-build-timeout")
+        .with_dockerfile_contents(contents)
+        .with_no_cache(true);
+
+    let started = std::time::Instant::now();
+    let err = builder
+        .clone()
+        .with_build_timeout(Duration::from_secs(5))
+        .to_owned()
+        .start()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DockerExecutorError::ImageBuild(ImageBuildError::Timeout(timeout))
+                if timeout == Duration::from_secs(5)
+        ),
+        "{err:?}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(60));
+
+    let cancel_token = tokio_util::sync::CancellationToken::new();
+    tokio::spawn({
+        let cancel_token = cancel_token.clone();
+        async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            cancel_token.cancel();
+        }
+    });
+    let err = builder
+        .with_build_cancel_token(cancel_token)
+        .to_owned()
+        .start()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            DockerExecutorError::ImageBuild(ImageBuildError::Cancelled)
+        ),
+        "{err:?}"
+    );
+
+    // Nothing was tagged
+    let docker = crate::client::Client::lazy_client().await.unwrap();
+    let images = docker
+        .list_images(Some(bollard::query_parameters::ListImagesOptions {
+            filters: Some(std::collections::HashMap::from([(
+                "reference".to_string(),
+                vec!["test-build-timeout".to_string()],
+            )])),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    assert!(images.is_empty());
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_resource_limits() {
+    let memory_limit = 64 * 1024 * 1024;
+    let executor = DockerExecutor::default()
+        .with_dockerfile(TEST_DOCKERFILE_ALPINE)
+        .with_context_path(".")
+        .with_image_name("test-resource-limits")
+        .with_memory_limit(memory_limit)
+        .with_memory_swap_limit(memory_limit)
+        .with_cpus(0.5)
+        .with_cpu_shares(512)
+        .with_cpuset("0")
+        .with_pids_limit(64)
+        .with_ulimit("nofile", 512, 1024)
+        .with_shm_size(16 * 1024 * 1024)
+        .to_owned()
+        .start()
+        .await
+        .unwrap();
+
+    let host_config = executor
+        .docker
+        .inspect_container(&executor.container_id, None::<InspectContainerOptions>)
+        .await
+        .unwrap()
+        .host_config
+        .unwrap();
+    assert_eq!(host_config.memory, Some(memory_limit));
+    assert_eq!(host_config.nano_cpus, Some(500_000_000));
+    assert_eq!(host_config.cpu_shares, Some(512));
+    assert_eq!(host_config.cpuset_cpus.as_deref(), Some("0"));
+    assert_eq!(host_config.pids_limit, Some(64));
+    assert_eq!(host_config.shm_size, Some(16 * 1024 * 1024));
+
+    let output = executor
+        .exec_cmd(&Command::shell("ulimit -n"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "512");
+
+    // `tail` keeps the whole line of zeroes in memory
+    let err = executor
+        .exec_cmd(&Command::shell("head -c 512m /dev/zero | tail"))
+        .await
+        .unwrap_err();
+    let CommandError::ExecutorError(err) = err else {
+        panic!("expected an executor error, got {err:?}");
+    };
+    assert!(matches!(
+        err.downcast_ref::<DockerExecutorError>(),
+        Some(DockerExecutorError::OutOfMemory { memory_limit: limit, .. }) if *limit == memory_limit
+    ));
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn test_network_modes() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let start = |mode: NetworkMode| {
+        DockerExecutor::default()
+            .with_dockerfile(TEST_DOCKERFILE)
+            .with_context_path(".")
+            .with_image_name("test-network-modes")
+            .with_network_mode(mode)
+            .with_extra_host("db", "10.0.0.2")
+            .with_dns_server("10.0.0.53")
+            .with_docker_socket(true)
+            .to_owned()
+            .start()
+    };
+    let no_internet = "wget -q -T 2 -O /dev/null http://1.1.1.1";
+    let docker_socket = "test -e /var/run/docker.sock";
+
+    // Without a network the service is reached over a socket on the host
+    let executor = start(NetworkMode::None).await.unwrap();
+    let ServiceAddress::Unix(socket) = executor.service_address.clone() else {
+        panic!("expected a socket, got {}", executor.service_address);
+    };
+    let metadata = std::fs::metadata(&socket).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        std::fs::metadata(socket.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777,
+        0o700
+    );
+    let output = executor
+        .exec_cmd(&Command::shell("cat /etc/hosts"))
+        .await
+        .unwrap();
+    assert!(output.stdout.contains("10.0.0.2"));
+    assert!(output.stdout.contains("db"));
+    executor
+        .exec_cmd(&Command::shell(no_internet))
+        .await
+        .unwrap_err();
+    // Even when asked for, the docker socket of the host is not mounted
+    executor
+        .exec_cmd(&Command::shell(docker_socket))
+        .await
+        .unwrap_err();
+    let files = executor
+        .as_file_loader(".", vec!["rs".to_string()])
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+    assert!(!files.is_empty());
+    assert!(files.iter().all(Result::is_ok));
+    drop(executor);
+
+    // The internal network is created for the executor and removed when it shuts down
+    let executor = start(NetworkMode::Internal).await.unwrap();
+    let container = executor
+        .docker
+        .inspect_container(&executor.container_id, None::<InspectContainerOptions>)
+        .await
+        .unwrap();
+    let executor_id = &container.config.unwrap().labels.unwrap()[cleanup::EXECUTOR_ID_LABEL];
+    let network = format!("swiftide-{executor_id}");
+    let networks = container
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .unwrap();
+    assert!(networks.contains_key(&network));
+    let output = executor
+        .exec_cmd(&Command::shell("cat /etc/resolv.conf"))
+        .await
+        .unwrap();
+    assert!(output.stdout.contains("10.0.0.53"));
+    executor
+        .exec_cmd(&Command::shell(no_internet))
+        .await
+        .unwrap_err();
+    executor
+        .exec_cmd(&Command::shell(docker_socket))
+        .await
+        .unwrap_err();
+    let docker = executor.docker.clone();
+    executor.shutdown().await.unwrap();
+    docker
+        .inspect_network(&network, None::<InspectNetworkOptions>)
+        .await
+        .unwrap_err();
+
+    // A named network is used as is
+    let network = format!("swiftide-test-{}", uuid::Uuid::new_v4());
+    docker
+        .create_network(NetworkCreateRequest {
+            name: network.clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let executor = start(NetworkMode::Named(network.clone())).await.unwrap();
+    let output = executor
+        .exec_cmd(&Command::shell("echo hello"))
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, "hello");
+    executor
+        .exec_cmd(&Command::shell(docker_socket))
+        .await
+        .unwrap();
+    // The deprecated fields still describe a service reached over tcp
+    let ServiceAddress::Tcp { ip, port } = executor.service_address.clone() else {
+        panic!("expected tcp, got {}", executor.service_address);
+    };
+    #[allow(deprecated)]
+    {
+        assert_eq!(executor.container_ip, ip);
+        assert_eq!(executor.container_port, port);
+    }
+    executor.shutdown().await.unwrap();
+    docker.remove_network(&network).await.unwrap();
+}
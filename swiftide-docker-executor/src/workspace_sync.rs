@@ -0,0 +1,644 @@
+//! Two-way synchronisation between the host context path and the workdir of a running container.
+//!
+//! The context sent to docker is a one-way copy. A [`WorkspaceSync`] keeps a baseline manifest
+//! (a content hash per file) and compares both sides against it, so it can tell which side
+//! changed a file since the last sync. Files changed on both sides are reported as conflicts
+//! unless a [`ConflictStrategy`] says otherwise.
+//!
+//! Ignore rules from `.gitignore`, `.dockerignore` and the global gitignore are respected on both
+//! sides, and the `.git` directory is never synced.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use bollard::query_parameters::{DownloadFromContainerOptions, UploadToContainerOptions};
+use http_body_util::{Either, Full};
+use sha2::{Digest as _, Sha256};
+use swiftide_core::prelude::StreamExt as _;
+use tokio::io::AsyncReadExt as _;
+use tokio_tar::{Archive, Builder, EntryType, Header};
+use tokio_util::io::StreamReader;
+use walkdir::WalkDir;
+
+use crate::{RunningDockerExecutor, SyncError, context_builder::IgnoreRules};
+
+/// Content hashes of the files in a workspace, keyed by their path relative to the workspace root
+pub type Manifest = BTreeMap<PathBuf, String>;
+
+/// What to do with a file that changed both on the host and in the container since the last sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Leave both sides untouched and report the conflict (default)
+    #[default]
+    Skip,
+    /// The version in the container wins
+    PreferContainer,
+    /// The version on the host wins
+    PreferHost,
+}
+
+/// The outcome of a single pull or push
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Files created or updated on the receiving side
+    pub written: Vec<PathBuf>,
+    /// Files removed on the receiving side
+    pub deleted: Vec<PathBuf>,
+    /// Files changed on both sides that were left untouched
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl SyncReport {
+    /// Returns true if nothing was written, deleted or in conflict
+    pub fn is_empty(&self) -> bool {
+        self.written.is_empty() && self.deleted.is_empty() && self.conflicts.is_empty()
+    }
+}
+
+/// Syncs files between the host context path and the running container
+#[derive(Debug, Clone)]
+pub struct WorkspaceSync<'a> {
+    host_path: PathBuf,
+    container_path: PathBuf,
+    baseline: Option<Manifest>,
+    conflict_strategy: ConflictStrategy,
+    executor: Cow<'a, RunningDockerExecutor>,
+}
+
+impl RunningDockerExecutor {
+    /// Creates an owned workspace sync between the context path and the container workdir.
+    ///
+    /// If the executor was started with `DockerExecutor::with_workspace_sync`, the state of the
+    /// container right after it started is used as the baseline. Otherwise the files on both
+    /// sides are snapshotted on the first sync, and every difference with the container counts as
+    /// a change in the container. Files that only exist on the host are never in the baseline, so
+    /// a pull never removes them.
+    pub fn into_workspace_sync(self) -> WorkspaceSync<'static> {
+        WorkspaceSync {
+            host_path: self.context_path.clone(),
+            container_path: self.workdir.clone(),
+            baseline: self.sync_baseline.as_deref().cloned(),
+            conflict_strategy: ConflictStrategy::default(),
+            executor: Cow::Owned(self),
+        }
+    }
+
+    /// Creates a borrowed workspace sync between the context path and the container workdir.
+    pub fn as_workspace_sync(&self) -> WorkspaceSync<'_> {
+        WorkspaceSync {
+            host_path: self.context_path.clone(),
+            container_path: self.workdir.clone(),
+            baseline: self.sync_baseline.as_deref().cloned(),
+            conflict_strategy: ConflictStrategy::default(),
+            executor: Cow::Borrowed(self),
+        }
+    }
+}
+
+impl WorkspaceSync<'_> {
+    /// Set the directory on the host to sync (default: the context path of the executor)
+    pub fn with_host_path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.host_path = path.into();
+
+        self
+    }
+
+    /// Set the directory in the container to sync (default: the workdir of the executor)
+    pub fn with_container_path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.container_path = path.into();
+
+        self
+    }
+
+    /// Set how files changed on both sides are resolved (default: skip and report)
+    pub fn with_conflict_strategy(&mut self, strategy: ConflictStrategy) -> &mut Self {
+        self.conflict_strategy = strategy;
+
+        self
+    }
+
+    /// Returns the manifest both sides are compared against, if one has been taken
+    pub fn baseline(&self) -> Option<&Manifest> {
+        self.baseline.as_ref()
+    }
+
+    /// Copies files changed in the container since the last sync back to the host
+    #[tracing::instrument(skip(self), fields(host_path = %self.host_path.display()))]
+    pub async fn pull(&mut self) -> Result<SyncReport, SyncError> {
+        let ignore = IgnoreRules::from_path(&self.host_path)?;
+        let host = host_manifest(&self.host_path, &ignore).await?;
+        // Without a baseline yet, everything that differs from the host may be pulled
+        let keep_changed_from = self.baseline.as_ref().unwrap_or(&host);
+        let mut container = self.download(&ignore, Some(keep_changed_from)).await?;
+        let container_manifest = container
+            .iter()
+            .map(|(path, file)| (path.clone(), file.digest.clone()))
+            .collect::<Manifest>();
+        let mut baseline = self
+            .baseline
+            .clone()
+            .unwrap_or_else(|| initial_baseline(&host, &container_manifest));
+
+        let plan = plan(
+            Direction::Pull,
+            self.conflict_strategy,
+            &baseline,
+            &host,
+            &container_manifest,
+        );
+
+        for path in &plan.write {
+            let Some(file) = container.remove(path) else {
+                continue;
+            };
+            let target = self.host_path.join(path);
+            tracing::debug!(path = ?path, "Writing file from container");
+
+            if let Some(parent) = target.parent() {
+                fs_err::tokio::create_dir_all(parent).await?;
+            }
+            fs_err::tokio::write(&target, file.contents.unwrap_or_default()).await?;
+            fs_err::tokio::set_permissions(
+                &target,
+                std::fs::Permissions::from_mode(file.mode & 0o777),
+            )
+            .await?;
+        }
+
+        for path in &plan.delete {
+            tracing::debug!(path = ?path, "Removing file deleted in container");
+            match fs_err::tokio::remove_file(self.host_path.join(path)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        let report = plan.apply(&mut baseline, &container_manifest);
+        self.baseline = Some(baseline);
+
+        Ok(report)
+    }
+
+    /// Copies files changed on the host since the last sync into the container
+    #[tracing::instrument(skip(self), fields(host_path = %self.host_path.display()))]
+    pub async fn push(&mut self) -> Result<SyncReport, SyncError> {
+        let ignore = IgnoreRules::from_path(&self.host_path)?;
+        let host = host_manifest(&self.host_path, &ignore).await?;
+        let container = self
+            .download(&ignore, None)
+            .await?
+            .into_iter()
+            .map(|(path, file)| (path, file.digest))
+            .collect::<Manifest>();
+        let mut baseline = self
+            .baseline
+            .clone()
+            .unwrap_or_else(|| initial_baseline(&host, &container));
+
+        let plan = plan(
+            Direction::Push,
+            self.conflict_strategy,
+            &baseline,
+            &host,
+            &container,
+        );
+
+        if !plan.write.is_empty() {
+            let mut tar = Builder::new(Vec::new());
+
+            for path in &plan.write {
+                tracing::debug!(path = ?path, "Adding file to push");
+                let source = self.host_path.join(path);
+                let contents = fs_err::tokio::read(&source).await?;
+                let metadata = fs_err::tokio::metadata(&source).await?;
+
+                let mut header = Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(metadata.permissions().mode() & 0o777);
+                header.set_cksum();
+
+                tar.append_data(&mut header, path, &*contents).await?;
+            }
+
+            let archive = tar.into_inner().await?;
+
+            self.executor
+                .docker
+                .upload_to_container(
+                    &self.executor.container_id,
+                    Some(UploadToContainerOptions {
+                        path: self.container_path.display().to_string(),
+                        ..Default::default()
+                    }),
+                    Either::Left(Full::new(archive.into())),
+                )
+                .await
+                .map_err(SyncError::Upload)?;
+        }
+
+        if !plan.delete.is_empty() {
+            // Paths are passed as arguments, never through a shell
+            let removal_args = ["rm", "-f", "--"]
+                .into_iter()
+                .map(String::from)
+                .chain(plan.delete.iter().map(|path| path.display().to_string()))
+                .collect::<Vec<_>>();
+
+            self.executor
+                .exec_args(&removal_args, &self.container_path)
+                .await
+                .map_err(|err| SyncError::Remove(err.into()))?;
+        }
+
+        let report = plan.apply(&mut baseline, &host);
+        self.baseline = Some(baseline);
+
+        Ok(report)
+    }
+
+    /// Hashes every regular file in the container path that is not ignored
+    pub(crate) async fn container_manifest(&self) -> Result<Manifest, SyncError> {
+        let ignore = IgnoreRules::from_path(&self.host_path)?;
+
+        Ok(self
+            .download(&ignore, None)
+            .await?
+            .into_iter()
+            .map(|(path, file)| (path, file.digest))
+            .collect())
+    }
+
+    /// Downloads the container path as a tar archive and hashes every regular file in it.
+    ///
+    /// Contents are only kept for files that differ from the given baseline.
+    async fn download(
+        &self,
+        ignore: &IgnoreRules,
+        keep_changed_from: Option<&Manifest>,
+    ) -> Result<HashMap<PathBuf, ContainerFile>, SyncError> {
+        let stream = self
+            .executor
+            .docker
+            .download_from_container(
+                &self.executor.container_id,
+                Some(DownloadFromContainerOptions {
+                    path: self.container_path.display().to_string(),
+                }),
+            )
+            .map(|chunk| chunk.map_err(std::io::Error::other));
+
+        let mut archive = Archive::new(StreamReader::new(Box::pin(stream)));
+        let mut entries = archive.entries()?;
+        let mut files = HashMap::new();
+
+        while let Some(entry) = entries.next().await {
+            let mut entry =
+                entry.map_err(|err| match err.downcast::<bollard::errors::Error>() {
+                    Ok(err) => SyncError::Download(err),
+                    Err(err) => SyncError::Io(err),
+                })?;
+
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+
+            // Docker prefixes every entry with the name of the requested directory
+            let entry_path = entry.path()?.into_owned();
+            let relative_path = entry_path.components().skip(1).collect::<PathBuf>();
+
+            if is_git_path(&relative_path) || ignore.is_ignored(self.host_path.join(&relative_path))
+            {
+                continue;
+            }
+
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).await?;
+
+            let digest = digest(&contents);
+            let changed = keep_changed_from
+                .is_some_and(|baseline| baseline.get(&relative_path) != Some(&digest));
+
+            files.insert(
+                relative_path,
+                ContainerFile {
+                    digest,
+                    mode: entry.header().mode()?,
+                    contents: changed.then_some(contents),
+                },
+            );
+        }
+
+        Ok(files)
+    }
+}
+
+struct ContainerFile {
+    digest: String,
+    mode: u32,
+    contents: Option<Vec<u8>>,
+}
+
+/// Hashes every regular file in the host path that is not ignored
+async fn host_manifest(host_path: &Path, ignore: &IgnoreRules) -> Result<Manifest, SyncError> {
+    let mut manifest = Manifest::new();
+
+    let walker = WalkDir::new(host_path).into_iter().filter_entry(|entry| {
+        let is_skipped_dir = entry.file_type().is_dir() && ignore.can_skip_dir(entry.path());
+
+        !is_git_path(entry.path().strip_prefix(host_path).unwrap_or(entry.path()))
+            && !is_skipped_dir
+    });
+
+    for entry in walker {
+        let Ok(entry) = entry else {
+            tracing::warn!(?entry, "Failed to read entry");
+            continue;
+        };
+
+        if !entry.file_type().is_file() || ignore.is_ignored(entry.path()) {
+            continue;
+        }
+
+        let contents = fs_err::tokio::read(entry.path()).await?;
+        let relative_path = entry.path().strip_prefix(host_path)?.to_path_buf();
+
+        manifest.insert(relative_path, digest(&contents));
+    }
+
+    Ok(manifest)
+}
+
+/// The baseline of a first sync without one: the host files that also exist in the container.
+///
+/// Files only on the host were never copied into the container, so they must not look deleted
+/// there.
+fn initial_baseline(host: &Manifest, container: &Manifest) -> Manifest {
+    host.iter()
+        .filter(|(path, _)| container.contains_key(*path))
+        .map(|(path, digest)| (path.clone(), digest.clone()))
+        .collect()
+}
+
+fn digest(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn is_git_path(relative_path: &Path) -> bool {
+    relative_path.starts_with(".git")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Pull,
+    Push,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Plan {
+    write: Vec<PathBuf>,
+    delete: Vec<PathBuf>,
+    conflicts: Vec<PathBuf>,
+    /// Paths where both sides already agree, but differ from the baseline
+    settled: Vec<PathBuf>,
+}
+
+impl Plan {
+    /// Moves the baseline forward for every path that is in sync after applying the plan
+    fn apply(self, baseline: &mut Manifest, source: &Manifest) -> SyncReport {
+        for path in self.write.iter().chain(&self.delete).chain(&self.settled) {
+            match source.get(path) {
+                Some(digest) => baseline.insert(path.clone(), digest.clone()),
+                None => baseline.remove(path),
+            };
+        }
+
+        SyncReport {
+            written: self.write,
+            deleted: self.delete,
+            conflicts: self.conflicts,
+        }
+    }
+}
+
+/// Three-way comparison of the host and container against the baseline
+fn plan(
+    direction: Direction,
+    strategy: ConflictStrategy,
+    baseline: &Manifest,
+    host: &Manifest,
+    container: &Manifest,
+) -> Plan {
+    let mut plan = Plan::default();
+    let paths = baseline
+        .keys()
+        .chain(host.keys())
+        .chain(container.keys())
+        .collect::<BTreeSet<_>>();
+
+    for path in paths {
+        let base = baseline.get(path);
+        let (source, target) = match direction {
+            Direction::Pull => (container.get(path), host.get(path)),
+            Direction::Push => (host.get(path), container.get(path)),
+        };
+
+        if source == target {
+            if source != base {
+                plan.settled.push(path.clone());
+            }
+            continue;
+        }
+
+        // Only the receiving side changed; that is up to a sync in the other direction
+        if source == base {
+            continue;
+        }
+
+        if target != base {
+            match (strategy, direction) {
+                (ConflictStrategy::PreferContainer, Direction::Pull)
+                | (ConflictStrategy::PreferHost, Direction::Push) => {}
+                (ConflictStrategy::Skip, _) => {
+                    plan.conflicts.push(path.clone());
+                    continue;
+                }
+                _ => continue,
+            }
+        }
+
+        if source.is_some() {
+            plan.write.push(path.clone());
+        } else {
+            plan.delete.push(path.clone());
+        }
+    }
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, &str)]) -> Manifest {
+        files
+            .iter()
+            .map(|(path, digest)| (PathBuf::from(path), (*digest).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_pull_applies_container_changes() {
+        let baseline = manifest(&[("changed", "a"), ("deleted", "a"), ("same", "a")]);
+        let host = baseline.clone();
+        let container = manifest(&[("changed", "b"), ("created", "a"), ("same", "a")]);
+
+        let plan = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+
+        assert_eq!(
+            plan.write,
+            vec![PathBuf::from("changed"), PathBuf::from("created")]
+        );
+        assert_eq!(plan.delete, vec![PathBuf::from("deleted")]);
+        assert!(plan.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_pull_leaves_host_only_changes() {
+        let baseline = manifest(&[("file", "a")]);
+        let host = manifest(&[("file", "b"), ("new", "a")]);
+        let container = baseline.clone();
+
+        let plan = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn test_push_applies_host_changes() {
+        let baseline = manifest(&[("file", "a"), ("deleted", "a")]);
+        let host = manifest(&[("file", "b")]);
+        let container = baseline.clone();
+
+        let plan = plan(
+            Direction::Push,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+
+        assert_eq!(plan.write, vec![PathBuf::from("file")]);
+        assert_eq!(plan.delete, vec![PathBuf::from("deleted")]);
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let baseline = manifest(&[("file", "a")]);
+        let host = manifest(&[("file", "b")]);
+        let container = manifest(&[("file", "c")]);
+
+        let skipped = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+        assert_eq!(skipped.conflicts, vec![PathBuf::from("file")]);
+        assert!(skipped.write.is_empty());
+
+        let preferred = plan(
+            Direction::Pull,
+            ConflictStrategy::PreferContainer,
+            &baseline,
+            &host,
+            &container,
+        );
+        assert_eq!(preferred.write, vec![PathBuf::from("file")]);
+
+        let other_side = plan(
+            Direction::Pull,
+            ConflictStrategy::PreferHost,
+            &baseline,
+            &host,
+            &container,
+        );
+        assert_eq!(other_side, Plan::default());
+    }
+
+    #[test]
+    fn test_pull_keeps_host_files_missing_from_baseline() {
+        // The baseline is taken from the container, which never got the ignored file
+        let baseline = manifest(&[("file", "a")]);
+        let host = manifest(&[("file", "a"), ("ignored", "a")]);
+        let container = baseline.clone();
+
+        let plan = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn test_initial_baseline_skips_host_only_files() {
+        let host = manifest(&[("file", "a"), ("host_only", "a")]);
+        let container = manifest(&[("file", "b"), ("container_only", "a")]);
+
+        let baseline = initial_baseline(&host, &container);
+        assert_eq!(baseline, manifest(&[("file", "a")]));
+
+        let plan = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+        assert_eq!(
+            plan.write,
+            vec![PathBuf::from("container_only"), PathBuf::from("file")]
+        );
+        assert!(plan.delete.is_empty());
+    }
+
+    #[test]
+    fn test_same_change_on_both_sides_settles() {
+        let mut baseline = manifest(&[("file", "a")]);
+        let host = manifest(&[("file", "b")]);
+        let container = host.clone();
+
+        let plan = plan(
+            Direction::Pull,
+            ConflictStrategy::Skip,
+            &baseline,
+            &host,
+            &container,
+        );
+        assert_eq!(plan.settled, vec![PathBuf::from("file")]);
+
+        let report = plan.apply(&mut baseline, &container);
+        assert!(report.is_empty());
+        assert_eq!(baseline, host);
+    }
+}
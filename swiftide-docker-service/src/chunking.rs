@@ -0,0 +1,624 @@
+//! Splitting files into chunks before they are streamed
+use std::ops::Range;
+use std::path::Path;
+
+use tonic::Status;
+
+use crate::file_metadata;
+use crate::loader::codegen::{ChunkingStrategy, chunking_strategy::Strategy};
+
+/// A validated chunking strategy from a load request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunker {
+    FixedSize {
+        max_bytes: usize,
+        overlap_bytes: usize,
+    },
+    Lines {
+        max_lines: usize,
+        overlap_lines: usize,
+    },
+    Markdown {
+        max_bytes: usize,
+    },
+    Code {
+        max_bytes: usize,
+        language: Option<String>,
+    },
+}
+
+impl TryFrom<ChunkingStrategy> for Chunker {
+    type Error = Status;
+
+    fn try_from(strategy: ChunkingStrategy) -> Result<Self, Self::Error> {
+        let invalid =
+            |message: &str| Status::invalid_argument(format!("invalid chunking: {message}"));
+
+        match strategy.strategy {
+            None => Err(invalid("no strategy given")),
+            Some(Strategy::FixedSize(fixed)) => {
+                if fixed.max_bytes == 0 {
+                    return Err(invalid("max_bytes must be greater than 0"));
+                }
+                if fixed.overlap_bytes >= fixed.max_bytes {
+                    return Err(invalid("overlap_bytes must be less than max_bytes"));
+                }
+
+                Ok(Self::FixedSize {
+                    max_bytes: to_usize(fixed.max_bytes),
+                    overlap_bytes: to_usize(fixed.overlap_bytes),
+                })
+            }
+            Some(Strategy::Lines(lines)) => {
+                if lines.max_lines == 0 {
+                    return Err(invalid("max_lines must be greater than 0"));
+                }
+                if lines.overlap_lines >= lines.max_lines {
+                    return Err(invalid("overlap_lines must be less than max_lines"));
+                }
+
+                Ok(Self::Lines {
+                    max_lines: lines.max_lines as usize,
+                    overlap_lines: lines.overlap_lines as usize,
+                })
+            }
+            Some(Strategy::Markdown(markdown)) => Ok(Self::Markdown {
+                max_bytes: to_usize(markdown.max_bytes),
+            }),
+            Some(Strategy::Code(code)) => Ok(Self::Code {
+                max_bytes: to_usize(code.max_bytes),
+                language: code.language,
+            }),
+        }
+    }
+}
+
+impl Chunker {
+    /// Returns the byte ranges of the chunks of a file, in order. Empty files have a single empty
+    /// chunk, so they are still streamed.
+    pub fn chunk(&self, path: &Path, text: &str) -> Vec<Range<usize>> {
+        if text.is_empty() {
+            return std::iter::once(0..0).collect();
+        }
+
+        match self {
+            Self::FixedSize {
+                max_bytes,
+                overlap_bytes,
+            } => fixed_size(text, 0..text.len(), *max_bytes, *overlap_bytes),
+            Self::Lines {
+                max_lines,
+                overlap_lines,
+            } => lines(text, *max_lines, *overlap_lines),
+            Self::Markdown { max_bytes } => {
+                pack(text, sections(text, markdown_headings(text)), *max_bytes)
+            }
+            Self::Code {
+                max_bytes,
+                language,
+            } => {
+                let language = language
+                    .as_deref()
+                    .or_else(|| file_metadata::language(path));
+
+                pack(
+                    text,
+                    sections(text, declarations(text, language)),
+                    *max_bytes,
+                )
+            }
+        }
+    }
+}
+
+fn to_usize(value: u64) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+/// Windows of at most `max_bytes`, never splitting a character
+fn fixed_size(
+    text: &str,
+    span: Range<usize>,
+    max_bytes: usize,
+    overlap_bytes: usize,
+) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = span.start;
+
+    loop {
+        let mut end = floor_char_boundary(text, start.saturating_add(max_bytes).min(span.end));
+        // A single character can be larger than the window
+        if end <= start {
+            end = ceil_char_boundary(text, start + 1);
+        }
+        chunks.push(start..end);
+
+        if end >= span.end {
+            return chunks;
+        }
+
+        // The end may have been moved back further than the overlap, always move forward
+        let next = floor_char_boundary(text, end.saturating_sub(overlap_bytes).max(start + 1));
+        start = if next > start {
+            next
+        } else {
+            ceil_char_boundary(text, start + 1)
+        };
+    }
+}
+
+/// Windows of at most `max_lines`, each repeating the last `overlap_lines` of the previous
+fn lines(text: &str, max_lines: usize, overlap_lines: usize) -> Vec<Range<usize>> {
+    let lines = line_ranges(text, 0..text.len());
+    let step = max_lines - overlap_lines;
+
+    let mut chunks = Vec::new();
+    let mut first = 0;
+
+    loop {
+        let last = (first + max_lines).min(lines.len()) - 1;
+        chunks.push(lines[first].start..lines[last].end);
+
+        if last == lines.len() - 1 {
+            return chunks;
+        }
+        first += step;
+    }
+}
+
+/// Merges consecutive segments into chunks of at most `max_bytes`, breaking up larger segments on
+/// lines first. Every segment is a chunk if `max_bytes` is 0.
+fn pack(text: &str, segments: Vec<Range<usize>>, max_bytes: usize) -> Vec<Range<usize>> {
+    if max_bytes == 0 {
+        return segments;
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = None;
+
+    for segment in segments {
+        pack_segment(text, segment, max_bytes, &mut chunks, &mut current);
+    }
+    chunks.extend(current);
+
+    chunks
+}
+
+fn pack_segment(
+    text: &str,
+    segment: Range<usize>,
+    max_bytes: usize,
+    chunks: &mut Vec<Range<usize>>,
+    current: &mut Option<Range<usize>>,
+) {
+    if segment.len() > max_bytes {
+        let lines = line_ranges(text, segment.clone());
+
+        if lines.len() > 1 {
+            for line in lines {
+                pack_segment(text, line, max_bytes, chunks, current);
+            }
+        } else {
+            chunks.extend(current.take());
+            chunks.extend(fixed_size(text, segment, max_bytes, 0));
+        }
+        return;
+    }
+
+    match current.take() {
+        Some(chunk) if segment.end - chunk.start <= max_bytes => {
+            *current = Some(chunk.start..segment.end);
+        }
+        chunk => {
+            chunks.extend(chunk);
+            *current = Some(segment);
+        }
+    }
+}
+
+/// Splits the text into consecutive sections starting at the given offsets
+fn sections(text: &str, mut starts: Vec<usize>) -> Vec<Range<usize>> {
+    starts.retain(|start| *start > 0);
+    starts.dedup();
+    starts.push(text.len());
+
+    let mut previous = 0;
+    starts
+        .into_iter()
+        .map(|start| {
+            let section = previous..start;
+            previous = start;
+            section
+        })
+        .collect()
+}
+
+/// Offsets of the lines in the span, including their line endings
+fn line_ranges(text: &str, span: Range<usize>) -> Vec<Range<usize>> {
+    let mut start = span.start;
+
+    text[span]
+        .split_inclusive('\n')
+        .map(|line| {
+            let range = start..start + line.len();
+            start = range.end;
+            range
+        })
+        .collect()
+}
+
+/// Offsets of the headings that are not inside fenced code blocks
+fn markdown_headings(text: &str) -> Vec<usize> {
+    let mut headings = Vec::new();
+    let mut fence: Option<&str> = None;
+
+    for range in line_ranges(text, 0..text.len()) {
+        let line = text[range.clone()].trim_end();
+        let unindented = line.trim_start_matches(' ');
+        // More than 3 spaces of indentation is an indented code block
+        if line.len() - unindented.len() > 3 {
+            continue;
+        }
+
+        match fence {
+            Some(marker) if unindented.starts_with(marker) => fence = None,
+            Some(_) => {}
+            None if unindented.starts_with("```") => fence = Some("```"),
+            None if unindented.starts_with("~~~") => fence = Some("~~~"),
+            None => {
+                let level = unindented.bytes().take_while(|b| *b == b'#').count();
+                let rest = &unindented[level..];
+
+                if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with([' ', '\t'])) {
+                    headings.push(range.start);
+                }
+            }
+        }
+    }
+
+    headings
+}
+
+/// Offsets of the top level declarations, including the comments, attributes and decorators
+/// directly above them
+fn declarations(text: &str, language: Option<&str>) -> Vec<usize> {
+    let keywords = language.and_then(declaration_keywords);
+    let lines = line_ranges(text, 0..text.len());
+    let mut starts = Vec::new();
+
+    for (index, range) in lines.iter().enumerate() {
+        let line = &text[range.clone()];
+        if line.starts_with(char::is_whitespace) || line.trim_end().is_empty() {
+            continue;
+        }
+
+        let is_declaration = match keywords {
+            Some(keywords) => keywords.iter().any(|keyword| line.starts_with(keyword)),
+            // Without known keywords, any unindented line after a blank line starts a section
+            None => {
+                !line.starts_with(['}', ')', ']'])
+                    && index > 0
+                    && text[lines[index - 1].clone()].trim().is_empty()
+            }
+        };
+        if !is_declaration {
+            continue;
+        }
+
+        let leading = lines[..index]
+            .iter()
+            .rev()
+            .take_while(|range| is_leading_trivia(&text[(*range).clone()]))
+            .count();
+        starts.push(lines[index - leading].start);
+    }
+
+    starts
+}
+
+fn is_leading_trivia(line: &str) -> bool {
+    ["//", "/*", " *", "*/", "#", "@", "--", ";"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+fn declaration_keywords(language: &str) -> Option<&'static [&'static str]> {
+    let keywords: &[&str] = match language {
+        "rust" => &[
+            "fn ",
+            "pub ",
+            "pub(",
+            "async ",
+            "const ",
+            "unsafe ",
+            "extern ",
+            "impl",
+            "struct ",
+            "enum ",
+            "union ",
+            "trait ",
+            "type ",
+            "mod ",
+            "static ",
+            "macro_rules!",
+        ],
+        "python" => &["def ", "async def ", "class "],
+        "go" => &["func ", "type ", "var ", "const "],
+        "typescript" | "javascript" => &[
+            "function ",
+            "async function ",
+            "class ",
+            "abstract class ",
+            "export ",
+            "interface ",
+            "type ",
+            "enum ",
+            "const ",
+            "let ",
+            "var ",
+            "declare ",
+        ],
+        "java" | "kotlin" | "scala" | "csharp" | "swift" | "dart" => &[
+            "public ",
+            "private ",
+            "protected ",
+            "internal ",
+            "abstract ",
+            "final ",
+            "sealed ",
+            "open ",
+            "data ",
+            "static ",
+            "class ",
+            "interface ",
+            "enum ",
+            "record ",
+            "struct ",
+            "object ",
+            "trait ",
+            "protocol ",
+            "extension ",
+            "namespace ",
+            "fun ",
+            "func ",
+            "def ",
+            "val ",
+            "var ",
+        ],
+        "ruby" => &["def ", "class ", "module "],
+        "php" => &[
+            "function ",
+            "class ",
+            "interface ",
+            "trait ",
+            "enum ",
+            "abstract ",
+            "final ",
+        ],
+        "elixir" => &["defmodule ", "def ", "defp ", "defmacro "],
+        "lua" => &["function ", "local function "],
+        _ => return None,
+    };
+
+    Some(keywords)
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    fn chunks<'a>(chunker: &Chunker, path: &str, text: &'a str) -> Vec<&'a str> {
+        chunker
+            .chunk(Path::new(path), text)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn test_fixed_size_with_overlap() {
+        let chunker = Chunker::FixedSize {
+            max_bytes: 4,
+            overlap_bytes: 1,
+        };
+
+        assert_eq!(
+            chunks(&chunker, "a.txt", "abcdefghij"),
+            vec!["abcd", "defg", "ghij"]
+        );
+    }
+
+    #[test]
+    fn test_fixed_size_keeps_characters_whole() {
+        let chunker = Chunker::FixedSize {
+            max_bytes: 3,
+            overlap_bytes: 0,
+        };
+
+        assert_eq!(chunks(&chunker, "a.txt", "aéé"), vec!["aé", "é"]);
+        assert_eq!(chunks(&chunker, "a.txt", "🦀🦀"), vec!["🦀", "🦀"]);
+    }
+
+    #[test]
+    fn test_fixed_size_overlap_with_multibyte_characters() {
+        let chunker = Chunker::FixedSize {
+            max_bytes: 5,
+            overlap_bytes: 4,
+        };
+
+        assert_eq!(
+            chunks(&chunker, "a.txt", "é🦀…é🦀…"),
+            vec!["é", "🦀", "…é", "é", "🦀", "…"]
+        );
+    }
+
+    #[test]
+    fn test_lines_with_overlap() {
+        let chunker = Chunker::Lines {
+            max_lines: 2,
+            overlap_lines: 1,
+        };
+
+        assert_eq!(
+            chunks(&chunker, "a.txt", "one\ntwo\nthree"),
+            vec!["one\ntwo\n", "two\nthree"]
+        );
+    }
+
+    #[test]
+    fn test_markdown_sections() {
+        let text = indoc! {"
+            intro
+            # Title
+            text
+            ```sh
+            # not a heading
+            ```
+            ## Section
+            more
+        "};
+        let chunker = Chunker::Markdown { max_bytes: 0 };
+
+        assert_eq!(
+            chunks(&chunker, "README.md", text),
+            vec![
+                "intro\n",
+                "# Title\ntext\n```sh\n# not a heading\n```\n",
+                "## Section\nmore\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_markdown_merges_small_sections() {
+        let text = "# A\na\n# B\nb\n# C\nccccccccccc\n";
+        let chunker = Chunker::Markdown { max_bytes: 12 };
+
+        assert_eq!(
+            chunks(&chunker, "README.md", text),
+            vec!["# A\na\n# B\nb\n", "# C\n", "ccccccccccc\n"]
+        );
+    }
+
+    #[test]
+    fn test_code_declarations() {
+        let text = indoc! {"
+            use std::fmt;
+
+            /// Says hello
+            #[inline]
+            pub fn hello() {
+                println!(\"hello\");
+            }
+
+            struct Foo;
+
+            impl Foo {
+                fn bar() {}
+            }
+        "};
+        let chunker = Chunker::Code {
+            max_bytes: 0,
+            language: None,
+        };
+
+        assert_eq!(
+            chunks(&chunker, "src/lib.rs", text),
+            vec![
+                "use std::fmt;\n\n",
+                "/// Says hello\n#[inline]\npub fn hello() {\n    println!(\"hello\");\n}\n\n",
+                "struct Foo;\n\n",
+                "impl Foo {\n    fn bar() {}\n}\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_language_override_and_packing() {
+        let text = "@cache\ndef a():\n    pass\n\ndef b():\n    pass\n";
+        let chunker = Chunker::Code {
+            max_bytes: 50,
+            language: Some("python".into()),
+        };
+
+        assert_eq!(
+            chunks(&chunker, "script", text),
+            vec!["@cache\ndef a():\n    pass\n\ndef b():\n    pass\n"]
+        );
+
+        let chunker = Chunker::Code {
+            max_bytes: 30,
+            language: Some("python".into()),
+        };
+        assert_eq!(
+            chunks(&chunker, "script", text),
+            vec!["@cache\ndef a():\n    pass\n\n", "def b():\n    pass\n"]
+        );
+    }
+
+    #[test]
+    fn test_code_unknown_language() {
+        let text = "int a() {\n}\n\nint b() {\n}\n";
+        let chunker = Chunker::Code {
+            max_bytes: 0,
+            language: None,
+        };
+
+        assert_eq!(
+            chunks(&chunker, "main.c", text),
+            vec!["int a() {\n}\n\n", "int b() {\n}\n"]
+        );
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let chunker = Chunker::Lines {
+            max_lines: 10,
+            overlap_lines: 0,
+        };
+
+        assert_eq!(chunks(&chunker, "a.txt", ""), vec![""]);
+    }
+
+    #[test]
+    fn test_invalid_strategies() {
+        use crate::loader::codegen::{FixedSize, Lines};
+
+        let invalid = [
+            ChunkingStrategy { strategy: None },
+            ChunkingStrategy {
+                strategy: Some(Strategy::FixedSize(FixedSize {
+                    max_bytes: 0,
+                    overlap_bytes: 0,
+                })),
+            },
+            ChunkingStrategy {
+                strategy: Some(Strategy::Lines(Lines {
+                    max_lines: 2,
+                    overlap_lines: 2,
+                })),
+            },
+        ];
+
+        for strategy in invalid {
+            let err = Chunker::try_from(strategy).unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        }
+    }
+}
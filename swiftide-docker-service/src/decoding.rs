@@ -0,0 +1,104 @@
+//! Telling text from binary files and transcoding text to UTF-8
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+
+/// How many bytes are inspected for NUL bytes, like git does
+const SNIFF_LEN: usize = 8000;
+
+/// The contents of a file
+#[derive(Debug, PartialEq, Eq)]
+pub enum Contents {
+    /// Text transcoded to UTF-8, with the name of the original encoding
+    Text {
+        text: String,
+        encoding: &'static str,
+    },
+    Binary,
+}
+
+/// Decodes the contents of a file
+///
+/// Files with a byte order mark are decoded accordingly. Otherwise files with NUL bytes near the
+/// start are binary, valid UTF-8 is kept as is and anything else is transcoded from the most
+/// likely legacy encoding.
+pub fn decode(bytes: Vec<u8>) -> Contents {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(&bytes) {
+        return transcode(encoding, &bytes[bom_len..]);
+    }
+
+    if bytes[..bytes.len().min(SNIFF_LEN)].contains(&0) {
+        return Contents::Binary;
+    }
+
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => {
+            return Contents::Text {
+                text,
+                encoding: encoding_rs::UTF_8.name(),
+            };
+        }
+        Err(err) => err.into_bytes(),
+    };
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(&bytes, true);
+
+    transcode(detector.guess(None, false), &bytes)
+}
+
+fn transcode(encoding: &'static Encoding, bytes: &[u8]) -> Contents {
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+
+    if had_errors {
+        return Contents::Binary;
+    }
+
+    Contents::Text {
+        text: text.into_owned(),
+        encoding: encoding.name(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, encoding: &'static str) -> Contents {
+        Contents::Text {
+            text: text.to_string(),
+            encoding,
+        }
+    }
+
+    #[test]
+    fn test_utf8() {
+        assert_eq!(decode("héllo".into()), text("héllo", "UTF-8"));
+        assert_eq!(decode(Vec::new()), text("", "UTF-8"));
+    }
+
+    #[test]
+    fn test_byte_order_marks() {
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFhello".to_vec()),
+            text("hello", "UTF-8")
+        );
+        assert_eq!(decode(b"\xFF\xFEh\0i\0".to_vec()), text("hi", "UTF-16LE"));
+    }
+
+    #[test]
+    fn test_legacy_encoding() {
+        // "café crème" in windows-1252
+        assert_eq!(
+            decode(b"caf\xE9 cr\xE8me".to_vec()),
+            text("café crème", "windows-1252")
+        );
+    }
+
+    #[test]
+    fn test_binary() {
+        assert_eq!(
+            decode(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec()),
+            Contents::Binary
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Metadata about files on disk, attached to the nodes streamed by the loader
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+/// Last modification time in milliseconds since the unix epoch
+pub const MODIFIED_AT: &str = "modified_at";
+/// Permission bits in octal, i.e. `755`
+pub const PERMISSIONS: &str = "permissions";
+/// Language derived from the file extension, i.e. `rust`
+pub const LANGUAGE: &str = "language";
+/// Git status of the file, i.e. `modified` or `untracked`
+pub const GIT_STATUS: &str = "git_status";
+/// Encoding of a text file before it was transcoded to UTF-8, i.e. `UTF-16LE`
+pub const ENCODING: &str = "encoding";
+
+/// Collects the metadata of a single file
+pub fn file_metadata(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    git: Option<&GitStatuses>,
+) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+
+    if let Some(modified) = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    {
+        map.insert(MODIFIED_AT.to_string(), modified.as_millis().to_string());
+    }
+
+    map.insert(
+        PERMISSIONS.to_string(),
+        format!("{:o}", metadata.permissions().mode() & 0o7777),
+    );
+
+    if let Some(language) = language(path) {
+        map.insert(LANGUAGE.to_string(), language.to_string());
+    }
+
+    if let Some(status) = git.and_then(|git| git.status(path)) {
+        map.insert(GIT_STATUS.to_string(), status.to_string());
+    }
+
+    map
+}
+
+/// Guesses the language of a file from its extension
+pub fn language(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+
+    let language = match extension.as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "ts" | "tsx" | "mts" | "cts" => "typescript",
+        "js" | "jsx" | "mjs" | "cjs" => "javascript",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "rb" => "ruby",
+        "php" => "php",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "swift" => "swift",
+        "ex" | "exs" => "elixir",
+        "erl" | "hrl" => "erlang",
+        "hs" => "haskell",
+        "lua" => "lua",
+        "dart" => "dart",
+        "zig" => "zig",
+        "sh" | "bash" | "zsh" => "shell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" | "scss" | "sass" => "css",
+        "md" | "markdown" => "markdown",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        "proto" => "protobuf",
+        _ => return None,
+    };
+
+    Some(language)
+}
+
+/// Git statuses of all files in the repository containing the loaded path
+#[derive(Debug)]
+pub struct GitStatuses {
+    toplevel: PathBuf,
+    changed: HashMap<PathBuf, &'static str>,
+}
+
+impl GitStatuses {
+    /// Returns `None` if git is not available or the path is not inside a repository
+    pub fn discover(root_path: &Path) -> Option<Self> {
+        let toplevel = git(root_path, &["rev-parse", "--show-toplevel"])?;
+        let toplevel = PathBuf::from(toplevel.trim_end());
+
+        let status = git(
+            &toplevel,
+            &["status", "--porcelain=v1", "-z", "--untracked-files=all"],
+        )?;
+
+        let mut changed = HashMap::new();
+        let mut entries = status.split('\0');
+
+        while let Some(entry) = entries.next() {
+            if entry.len() < 4 {
+                continue;
+            }
+            let (code, path) = entry.split_at(3);
+            let code = code.trim_end();
+
+            // Renames and copies are followed by the original path, which we skip
+            if code.contains(['R', 'C']) {
+                entries.next();
+            }
+
+            changed.insert(PathBuf::from(path), describe_status(code));
+        }
+
+        Some(Self {
+            toplevel: toplevel.canonicalize().unwrap_or(toplevel),
+            changed,
+        })
+    }
+
+    /// Returns the status of a file, or `None` if it is outside of the repository
+    pub fn status(&self, path: &Path) -> Option<&'static str> {
+        let path = path.canonicalize().ok()?;
+        let relative = path.strip_prefix(&self.toplevel).ok()?;
+
+        Some(self.changed.get(relative).copied().unwrap_or("unmodified"))
+    }
+}
+
+/// Describes a status code of `git status --porcelain`. Ignored files are never loaded, so they
+/// are not asked for.
+fn describe_status(code: &str) -> &'static str {
+    match code {
+        "??" => "untracked",
+        "DD" | "AA" => "conflicted",
+        code if code.contains('U') => "conflicted",
+        code if code.contains('R') => "renamed",
+        code if code.contains('C') => "copied",
+        code if code.contains('A') => "added",
+        code if code.contains('D') => "deleted",
+        _ => "modified",
+    }
+}
+
+fn git(current_dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(current_dir)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn test_language() {
+        assert_eq!(language(Path::new("src/main.rs")), Some("rust"));
+        assert_eq!(language(Path::new("App.TSX")), Some("typescript"));
+        assert_eq!(language(Path::new("Makefile")), None);
+    }
+
+    #[test]
+    fn test_file_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.sh");
+        std::fs::write(&path, "echo hello").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let map = file_metadata(&path, &std::fs::metadata(&path).unwrap(), None);
+
+        assert_eq!(map[PERMISSIONS], "755");
+        assert_eq!(map[LANGUAGE], "shell");
+        assert!(map[MODIFIED_AT].parse::<u128>().unwrap() > 0);
+        assert!(!map.contains_key(GIT_STATUS));
+    }
+
+    #[test]
+    fn test_git_statuses() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        run_git(root, &["init", "-q"]);
+        run_git(root, &["config", "user.email", "test@example.com"]);
+        run_git(root, &["config", "user.name", "test"]);
+        std::fs::write(root.join("committed.txt"), "a").unwrap();
+        std::fs::write(root.join("changed.txt"), "a").unwrap();
+        run_git(root, &["add", "."]);
+        run_git(root, &["commit", "-q", "-m", "initial"]);
+        std::fs::write(root.join("changed.txt"), "b").unwrap();
+        std::fs::write(root.join("new.txt"), "a").unwrap();
+
+        let git = GitStatuses::discover(root).unwrap();
+
+        assert_eq!(git.status(&root.join("committed.txt")), Some("unmodified"));
+        assert_eq!(git.status(&root.join("changed.txt")), Some("modified"));
+        assert_eq!(git.status(&root.join("new.txt")), Some("untracked"));
+    }
+
+    #[test]
+    fn test_describe_status() {
+        assert_eq!(describe_status("??"), "untracked");
+        assert_eq!(describe_status(" M"), "modified");
+        assert_eq!(describe_status("MM"), "modified");
+        assert_eq!(describe_status("A"), "added");
+        assert_eq!(describe_status(" D"), "deleted");
+        assert_eq!(describe_status("R"), "renamed");
+        assert_eq!(describe_status("UU"), "conflicted");
+        assert_eq!(describe_status("AA"), "conflicted");
+    }
+
+    #[test]
+    fn test_no_git_repository() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(GitStatuses::discover(dir.path()).is_none());
+    }
+}
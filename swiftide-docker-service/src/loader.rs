@@ -1,9 +1,14 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::UNIX_EPOCH;
 
-use futures_util::{Stream, StreamExt as _, TryStreamExt};
-use swiftide_core::Loader as _;
-use swiftide_core::indexing::TextNode;
-use swiftide_indexing::loaders::FileLoader;
+use futures_util::{Stream, StreamExt as _};
+use ignore::WalkBuilder;
+use ignore::overrides::{Override, OverrideBuilder};
+use sha2::{Digest as _, Sha256};
+use tokio::sync::mpsc::Sender;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::Status;
 
 // The module `shell` is created by Tonic automatically because your
@@ -14,7 +19,11 @@ pub mod codegen {
 }
 
 use codegen::loader_server::Loader;
-use codegen::{LoadFilesRequest, NodeResponse};
+use codegen::{BinaryFiles, LoadCursor, LoadFilesRequest, NodeResponse};
+
+use crate::chunking::Chunker;
+use crate::decoding::{Contents, decode};
+use crate::file_metadata::{ENCODING, GitStatuses, file_metadata};
 
 #[derive(Debug, Default)]
 pub struct MyLoaderExecutor;
@@ -25,29 +34,617 @@ impl Loader for MyLoaderExecutor {
     type LoadFilesStream = Pin<Box<dyn Stream<Item = Result<NodeResponse, Status>> + Send>>;
 
     #[doc = " Runs a shell command and returns exit code, stdout, and stderr."]
+    #[tracing::instrument(skip_all)]
     async fn load_files(
         &self,
         request: tonic::Request<LoadFilesRequest>,
     ) -> Result<tonic::Response<Self::LoadFilesStream>, tonic::Status> {
-        let args = request.into_inner();
-        let loader = FileLoader::new(args.root_path).with_extensions(&args.file_extensions);
+        let mut args = request.into_inner();
+        tracing::info!(
+            root_path = args.root_path,
+            incremental = args.cursor.is_some(),
+            "Loading files"
+        );
+
+        let walk = FileWalk::from_request(&mut args)?;
+        let (tx, rx) = tokio::sync::mpsc::channel(1000);
 
-        Ok(tonic::Response::new(
-            loader
-                .into_stream()
-                .map_ok(Into::into)
-                .map_err(|err| tonic::Status::from_error(err.into()))
-                .boxed(),
-        ))
+        // Walking and reading is blocking, so keep it off the async runtime
+        tokio::task::spawn_blocking(move || walk.send_to(&tx));
+
+        Ok(tonic::Response::new(ReceiverStream::new(rx).boxed()))
     }
 }
 
-impl From<TextNode> for NodeResponse {
-    fn from(val: TextNode) -> Self {
-        NodeResponse {
-            path: val.path.to_string_lossy().to_string(),
-            chunk: val.chunk,
-            original_size: val.original_size as i32,
+/// A configured walk over the files of a load request
+struct FileWalk {
+    root_path: PathBuf,
+    walker: WalkBuilder,
+    include: Override,
+    extensions: Vec<String>,
+    cursor: LoadCursor,
+    chunker: Option<Chunker>,
+    binary_files: BinaryFiles,
+}
+
+impl FileWalk {
+    fn from_request(args: &mut LoadFilesRequest) -> Result<Self, Status> {
+        Ok(Self {
+            root_path: PathBuf::from(&args.root_path),
+            walker: build_walker(args)?,
+            include: build_include(args)?,
+            extensions: args
+                .file_extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_string())
+                .collect(),
+            cursor: args.cursor.take().unwrap_or_default(),
+            chunker: args.chunking.take().map(Chunker::try_from).transpose()?,
+            binary_files: args.binary_files(),
+        })
+    }
+
+    /// Streams every file that changed since the cursor, followed by tombstones for files in the
+    /// cursor manifest that no longer exist. Without a cursor every file is streamed.
+    fn send_to(&self, tx: &Sender<Result<NodeResponse, Status>>) {
+        let cursor = &self.cursor;
+        let mut deleted = cursor.manifest.keys().cloned().collect::<HashSet<_>>();
+        let git = GitStatuses::discover(&self.root_path);
+
+        for entry in self.walker.build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    tracing::warn!(?err, "Failed to read entry");
+                    continue;
+                }
+            };
+
+            if !entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+                || !has_extension(entry.path(), &self.extensions)
+                || self.include.matched(entry.path(), false).is_ignore()
+            {
+                continue;
+            }
+
+            let path = entry.path().to_string_lossy().to_string();
+            let known = deleted.remove(&path);
+
+            // Cheap check first; with a manifest, only files it knows about can be unchanged
+            if let Some(since) = cursor.modified_since_ms
+                && (known || cursor.manifest.is_empty())
+                && modified_before(entry.path(), since)
+            {
+                continue;
+            }
+
+            // Failing to load a single file should not abort the whole load
+            let node = match read_node(entry.path(), git.as_ref()) {
+                Ok(node) if node.binary && self.binary_files == BinaryFiles::Skip => {
+                    tracing::debug!(path = ?entry.path(), "Skipping binary file");
+                    // A file that was text before is gone for the caller
+                    if known {
+                        deleted.insert(path);
+                    }
+                    continue;
+                }
+                Ok(node) if cursor.manifest.get(&path) == Some(&node.content_hash) => continue,
+                Ok(node) => node,
+                Err(err) => {
+                    tracing::warn!(path = ?entry.path(), ?err, "Failed to read file");
+                    NodeResponse {
+                        path,
+                        error: format!("failed to read {}: {err}", entry.path().display()),
+                        ..Default::default()
+                    }
+                }
+            };
+            let nodes = self.chunk(node);
+
+            tracing::debug!(path = ?entry.path(), chunks = nodes.len(), "Loading file");
+            for node in nodes {
+                if tx.blocking_send(Ok(node)).is_err() {
+                    tracing::warn!("Client went away; stopped loading files");
+                    return;
+                }
+            }
+        }
+
+        for path in deleted {
+            tracing::debug!(path, "Sending tombstone for deleted file");
+            let tombstone = NodeResponse {
+                path,
+                deleted: true,
+                ..Default::default()
+            };
+
+            if tx.blocking_send(Ok(tombstone)).is_err() {
+                tracing::warn!("Client went away; stopped sending tombstones");
+                return;
+            }
         }
     }
+
+    /// Splits a node with a whole file into a node per chunk
+    fn chunk(&self, node: NodeResponse) -> Vec<NodeResponse> {
+        let Some(chunker) = &self.chunker else {
+            return vec![node];
+        };
+
+        chunker
+            .chunk(Path::new(&node.path), &node.chunk)
+            .into_iter()
+            .map(|range| NodeResponse {
+                chunk: node.chunk[range.clone()].to_string(),
+                chunk_start: range.start as u64,
+                chunk_end: range.end as u64,
+                ..node.clone()
+            })
+            .collect()
+    }
+}
+
+/// Configures a directory walker with the filters from the request
+///
+/// Include globs are not part of it, as overrides that match take precedence over ignore files
+/// and hidden files. The `.git` directory is never walked.
+fn build_walker(args: &LoadFilesRequest) -> Result<WalkBuilder, Status> {
+    let mut overrides = OverrideBuilder::new(&args.root_path);
+
+    for glob in &args.exclude_globs {
+        overrides
+            .add(&format!("!{glob}"))
+            .map_err(|err| Status::invalid_argument(format!("invalid exclude glob: {err}")))?;
+    }
+
+    let overrides = overrides
+        .build()
+        .map_err(|err| Status::invalid_argument(format!("invalid glob: {err}")))?;
+
+    let mut walker = WalkBuilder::new(&args.root_path);
+    walker
+        .overrides(overrides)
+        .max_filesize(args.max_file_size)
+        .hidden(!args.include_hidden);
+
+    for ignore_file in &args.ignore_files {
+        walker.add_custom_ignore_filename(ignore_file);
+    }
+
+    walker.filter_entry(|entry| entry.file_name() != ".git");
+
+    Ok(walker)
+}
+
+/// Matches the files that pass the ignore rules against the include globs. Without include
+/// globs every file is included.
+fn build_include(args: &LoadFilesRequest) -> Result<Override, Status> {
+    let mut include = OverrideBuilder::new(&args.root_path);
+
+    for glob in &args.include_globs {
+        include
+            .add(glob)
+            .map_err(|err| Status::invalid_argument(format!("invalid include glob: {err}")))?;
+    }
+
+    include
+        .build()
+        .map_err(|err| Status::invalid_argument(format!("invalid glob: {err}")))
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    if extensions.is_empty() {
+        return true;
+    }
+
+    path.extension()
+        .is_some_and(|ext| extensions.iter().any(|wanted| ext == wanted.as_str()))
+}
+
+fn modified_before(path: &Path, since_ms: u64) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|modified| modified.as_millis() < u128::from(since_ms))
+}
+
+/// Reads a file into a single node. Binary files have an empty chunk and `binary` set.
+fn read_node(path: &Path, git: Option<&GitStatuses>) -> std::io::Result<NodeResponse> {
+    let metadata = std::fs::metadata(path)?;
+    let contents = std::fs::read(path)?;
+    let content_hash = format!("{:x}", Sha256::digest(&contents));
+
+    let mut node = NodeResponse {
+        path: path.to_string_lossy().to_string(),
+        original_size: i32::try_from(metadata.len()).unwrap_or(i32::MAX),
+        size: metadata.len(),
+        metadata: file_metadata(path, &metadata, git),
+        content_hash,
+        ..Default::default()
+    };
+
+    match decode(contents) {
+        Contents::Text { text, encoding } => {
+            node.chunk_end = text.len() as u64;
+            node.chunk = text;
+            node.metadata
+                .insert(ENCODING.to_string(), encoding.to_string());
+        }
+        Contents::Binary => node.binary = true,
+    }
+
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::loader::codegen::{ChunkingStrategy, FixedSize, chunking_strategy::Strategy};
+
+    async fn load(args: LoadFilesRequest) -> Vec<NodeResponse> {
+        let root = args.root_path.clone();
+        let mut nodes = MyLoaderExecutor
+            .load_files(tonic::Request::new(args))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+
+        for node in &mut nodes {
+            node.path = node.path.strip_prefix(&root).unwrap()[1..].to_string();
+        }
+        nodes.sort_by(|a, b| a.path.cmp(&b.path));
+        nodes
+    }
+
+    async fn load_paths(args: LoadFilesRequest) -> Vec<String> {
+        load(args).await.into_iter().map(|node| node.path).collect()
+    }
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("vendor")).unwrap();
+        std::fs::create_dir_all(root.join(".hidden")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(root.join("src/big.rs"), "x".repeat(1024)).unwrap();
+        std::fs::write(root.join("vendor/dep.rs"), "fn dep() {}").unwrap();
+        std::fs::write(root.join(".hidden/secret.rs"), "fn secret() {}").unwrap();
+        std::fs::write(root.join("Cargo.lock"), "lock").unwrap();
+        std::fs::write(root.join(".indexignore"), "Cargo.lock").unwrap();
+
+        dir
+    }
+
+    fn request(root: &Path) -> LoadFilesRequest {
+        LoadFilesRequest {
+            root_path: root.display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_defaults_skip_hidden() {
+        let dir = fixture();
+
+        assert_eq!(
+            load_paths(request(dir.path())).await,
+            vec!["Cargo.lock", "src/big.rs", "src/main.rs", "vendor/dep.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_include_and_exclude_globs() {
+        let dir = fixture();
+
+        let files = load_paths(LoadFilesRequest {
+            include_globs: vec!["*.rs".into()],
+            exclude_globs: vec!["vendor/**".into()],
+            ..request(dir.path())
+        })
+        .await;
+
+        assert_eq!(files, vec!["src/big.rs", "src/main.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_max_file_size_and_extensions() {
+        let dir = fixture();
+
+        let files = load_paths(LoadFilesRequest {
+            file_extensions: vec!["rs".into()],
+            max_file_size: Some(512),
+            ..request(dir.path())
+        })
+        .await;
+
+        assert_eq!(files, vec!["src/main.rs", "vendor/dep.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_hidden_and_custom_ignore_files() {
+        let dir = fixture();
+
+        let files = load_paths(LoadFilesRequest {
+            include_hidden: true,
+            ignore_files: vec![".indexignore".into()],
+            ..request(dir.path())
+        })
+        .await;
+
+        assert_eq!(
+            files,
+            vec![
+                ".hidden/secret.rs",
+                ".indexignore",
+                "src/big.rs",
+                "src/main.rs",
+                "vendor/dep.rs"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_include_globs_keep_ignored_files_out() {
+        let dir = fixture();
+        let root = dir.path();
+
+        let status = std::process::Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("target/build.rs"), "fn build() {}").unwrap();
+        std::fs::write(root.join(".gitignore"), "target/").unwrap();
+
+        let files = load_paths(LoadFilesRequest {
+            include_globs: vec!["**/*.rs".into(), "**/config".into()],
+            include_hidden: true,
+            ..request(root)
+        })
+        .await;
+
+        assert_eq!(
+            files,
+            vec![
+                ".hidden/secret.rs",
+                "src/big.rs",
+                "src/main.rs",
+                "vendor/dep.rs"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_glob() {
+        let dir = fixture();
+
+        let Err(err) = MyLoaderExecutor
+            .load_files(tonic::Request::new(LoadFilesRequest {
+                include_globs: vec!["{".into()],
+                ..request(dir.path())
+            }))
+            .await
+        else {
+            panic!("expected an invalid glob to be rejected");
+        };
+
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_nodes_carry_metadata() {
+        let dir = fixture();
+
+        let nodes = load(LoadFilesRequest {
+            include_globs: vec!["src/big.rs".into()],
+            ..request(dir.path())
+        })
+        .await;
+
+        let [node] = nodes.as_slice() else {
+            panic!("expected a single node, got {nodes:?}");
+        };
+        assert_eq!(node.size, 1024);
+        assert_eq!(node.original_size, 1024);
+        assert_eq!(node.metadata["language"], "rust");
+        assert!(node.metadata.contains_key("modified_at"));
+        assert!(node.metadata.contains_key("permissions"));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_nodes_carry_offsets() {
+        let dir = fixture();
+
+        let nodes = load(LoadFilesRequest {
+            include_globs: vec!["src/big.rs".into()],
+            chunking: Some(ChunkingStrategy {
+                strategy: Some(Strategy::FixedSize(FixedSize {
+                    max_bytes: 400,
+                    overlap_bytes: 100,
+                })),
+            }),
+            ..request(dir.path())
+        })
+        .await;
+
+        let offsets = nodes
+            .iter()
+            .map(|node| (node.chunk_start, node.chunk_end, node.chunk.len()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            offsets,
+            vec![
+                (0, 400, 400),
+                (300, 700, 400),
+                (600, 1000, 400),
+                (900, 1024, 124)
+            ]
+        );
+        assert!(nodes.iter().all(|node| node.size == 1024));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_chunking() {
+        let dir = fixture();
+
+        let Err(err) = MyLoaderExecutor
+            .load_files(tonic::Request::new(LoadFilesRequest {
+                chunking: Some(ChunkingStrategy::default()),
+                ..request(dir.path())
+            }))
+            .await
+        else {
+            panic!("expected chunking without a strategy to be rejected");
+        };
+
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_binary_files_and_encodings() {
+        let dir = fixture();
+        std::fs::write(
+            dir.path().join("logo.png"),
+            b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("latin1.txt"), b"caf\xE9").unwrap();
+        let args = LoadFilesRequest {
+            include_globs: vec!["*.png".into(), "*.txt".into()],
+            ..request(dir.path())
+        };
+
+        let nodes = load(args.clone()).await;
+        let [node] = nodes.as_slice() else {
+            panic!("expected only the text file, got {nodes:?}");
+        };
+        assert_eq!(node.chunk, "café");
+        assert_eq!(node.metadata["encoding"], "windows-1252");
+
+        let nodes = load(LoadFilesRequest {
+            binary_files: BinaryFiles::Report.into(),
+            ..args
+        })
+        .await;
+        let binary = nodes
+            .iter()
+            .map(|node| (node.path.as_str(), node.binary, node.chunk.is_empty()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            binary,
+            vec![("latin1.txt", false, false), ("logo.png", true, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manifest_cursor_streams_changes_and_tombstones() {
+        let dir = fixture();
+        let nodes = load(request(dir.path())).await;
+
+        let manifest = nodes
+            .iter()
+            .map(|node| {
+                (
+                    dir.path().join(&node.path).display().to_string(),
+                    node.content_hash.clone(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() { changed() }").unwrap();
+        std::fs::write(dir.path().join("src/new.rs"), "fn new() {}").unwrap();
+        std::fs::remove_file(dir.path().join("vendor/dep.rs")).unwrap();
+
+        let nodes = load(LoadFilesRequest {
+            cursor: Some(LoadCursor {
+                modified_since_ms: None,
+                manifest,
+            }),
+            ..request(dir.path())
+        })
+        .await;
+
+        let changes = nodes
+            .iter()
+            .map(|node| (node.path.as_str(), node.deleted))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            vec![
+                ("src/main.rs", false),
+                ("src/new.rs", false),
+                ("vendor/dep.rs", true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_file_that_became_binary_is_deleted() {
+        let dir = fixture();
+        let nodes = load(request(dir.path())).await;
+        let manifest = nodes
+            .iter()
+            .map(|node| {
+                (
+                    dir.path().join(&node.path).display().to_string(),
+                    node.content_hash.clone(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        std::fs::write(dir.path().join("src/main.rs"), b"\0\x01\x02binary").unwrap();
+
+        let nodes = load(LoadFilesRequest {
+            cursor: Some(LoadCursor {
+                modified_since_ms: None,
+                manifest,
+            }),
+            ..request(dir.path())
+        })
+        .await;
+        let changes = nodes
+            .iter()
+            .map(|node| (node.path.as_str(), node.deleted))
+            .collect::<Vec<_>>();
+
+        assert_eq!(changes, vec![("src/main.rs", true)]);
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_cursor_skips_old_files() {
+        let dir = fixture();
+        let since = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            + 1;
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        std::fs::write(dir.path().join("src/new.rs"), "fn new() {}").unwrap();
+
+        let files = load_paths(LoadFilesRequest {
+            cursor: Some(LoadCursor {
+                modified_since_ms: Some(since),
+                manifest: HashMap::new(),
+            }),
+            ..request(dir.path())
+        })
+        .await;
+
+        assert_eq!(files, vec!["src/new.rs"]);
+    }
 }
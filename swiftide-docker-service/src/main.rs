@@ -1,11 +1,30 @@
+use std::{
+    fs::Permissions,
+    os::unix::fs::{MetadataExt as _, PermissionsExt as _},
+    path::Path,
+};
+
 use executor::{MyShellExecutor, codegen::shell_executor_server::ShellExecutorServer};
-use tokio::signal::unix::{SignalKind, signal};
+use tokio::{
+    net::UnixListener,
+    signal::unix::{SignalKind, signal},
+};
+use tokio_stream::wrappers::UnixListenerStream;
 use tonic::transport::Server;
 
+#[cfg(feature = "file-loader")]
+mod chunking;
+#[cfg(feature = "file-loader")]
+mod decoding;
 mod executor;
 #[cfg(feature = "file-loader")]
+mod file_metadata;
+#[cfg(feature = "file-loader")]
 mod loader;
 
+/// Listen on a unix socket at this path instead of tcp, for containers without a network
+const SOCKET_ENV: &str = "SWIFTIDE_DOCKER_SERVICE_SOCKET";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt()
@@ -13,7 +32,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_ansi(false)
         .with_target(false)
         .init();
-    let addr = "0.0.0.0:50051".parse()?;
+    let socket = std::env::var(SOCKET_ENV).ok();
+    let addr = match &socket {
+        Some(socket) => socket.clone(),
+        None => "0.0.0.0:50051".to_string(),
+    };
 
     let version = env!("CARGO_PKG_VERSION");
     tracing::warn!("ShellExecutor {version} gRPC server listening on {}", addr);
@@ -29,11 +52,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         builder = builder.add_service(LoaderServer::new(MyLoaderExecutor));
     }
 
-    builder.serve_with_shutdown(addr, sigterm()).await?;
+    if let Some(socket) = socket {
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket)?;
+        restrict_socket(Path::new(&socket))?;
+
+        builder
+            .serve_with_incoming_shutdown(UnixListenerStream::new(listener), sigterm())
+            .await?;
+    } else {
+        builder
+            .serve_with_shutdown(addr.parse()?, sigterm())
+            .await?;
+    }
 
     Ok(())
 }
 
+/// Hands the socket to the owner of its directory, the executor on the host, and makes sure no
+/// one else can connect to it
+fn restrict_socket(socket: &Path) -> std::io::Result<()> {
+    let dir = std::fs::metadata(socket.parent().unwrap_or(Path::new("/")))?;
+    if let Err(err) = std::os::unix::fs::chown(socket, Some(dir.uid()), Some(dir.gid())) {
+        // Only root can give the socket away, otherwise the service must run as the owner
+        tracing::warn!(?err, "Failed to hand over socket to the owner of its directory");
+    }
+
+    std::fs::set_permissions(socket, Permissions::from_mode(0o600))
+}
+
 async fn sigterm() {
     let _ = signal(SignalKind::terminate())
         .expect("failed to create a new SIGINT signal handler for gRPC")